@@ -33,7 +33,7 @@ cell grip_get_response_state(const void *amx);
 
 cell grip_get_response_status_code(const void *amx);
 
-void grip_init(void (*error_logger)(const void*, const char*), const char *config_file_path);
+bool grip_init(void (*error_logger)(const void*, const char*), const char *config_file_path);
 
 bool grip_is_initialized();
 
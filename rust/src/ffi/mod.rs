@@ -77,11 +77,12 @@ struct ModuleStorage {
 
 static mut MODULE: Option<ModuleStorage> = None;
 
+// Returns false, when the queue can't be started. Module stays uninitialized then, so init may be retried.
 #[no_mangle]
 pub unsafe extern "C" fn grip_init(
     error_logger: extern "C" fn(*const c_void, *const c_char),
     config_file_path: *const c_char,
-) {
+) -> bool {
     let ini = Ini::load_from_file(str_from_ptr(config_file_path).unwrap())
         .map_err(|e| {
             println!(
@@ -100,8 +101,16 @@ pub unsafe extern "C" fn grip_init(
         })
         .unwrap();
 
+    let global_queue = match Queue::new() {
+        Ok(queue) => queue,
+        Err(e) => {
+            println!("Error: Can't start the request queue\n{}", e);
+            return false;
+        }
+    };
+
     MODULE = Some(ModuleStorage {
-        global_queue: Queue::new(),
+        global_queue,
         cancellations_handles: CellMap::new(),
         current_response: None,
         bodies_handles: CellMap::new(),
@@ -132,6 +141,8 @@ pub unsafe extern "C" fn grip_init(
                 .unwrap()
         },
     });
+
+    true
 }
 
 unsafe fn get_module() -> &'static ModuleStorage {
@@ -245,29 +256,34 @@ pub unsafe extern "C" fn grip_request(
     );
 
     let next_cancellation_id = get_module().cancellations_handles.peek_id();
-    let cancellation = get_module_mut().global_queue.send_request(
-        RequestBuilder::default()
-            .http_type(request_type)
-            .body(body.clone())
-            .uri(try_and_log_ffi!(
-                amx,
-                uri.parse()
-                    .chain_err(|| ffi_error(format!("URI parsing error: {}", uri)))
-            ))
-            .options(options.clone())
-            .build()
-            .unwrap(),
-        move |response| {
-            get_module_mut().current_response = Some(response);
-
-            handler.unwrap()(forward_id, user_data);
-
-            get_module_mut()
-                .cancellations_handles
-                .remove_with_id(next_cancellation_id);
-
-            get_module_mut().current_response = None;
-        },
+    let cancellation = try_and_log_ffi!(
+        amx,
+        get_module_mut().global_queue.send_request(
+            RequestBuilder::default()
+                .http_type(request_type)
+                .body(body.clone())
+                .uri(try_and_log_ffi!(
+                    amx,
+                    get_module()
+                        .global_queue
+                        .resolve_uri(uri)
+                        .chain_err(|| ffi_error(format!("URI parsing error: {}", uri)))
+                ))
+                .options(options.clone())
+                .build()
+                .unwrap(),
+            move |response| {
+                get_module_mut().current_response = Some(response);
+
+                handler.unwrap()(forward_id, user_data);
+
+                get_module_mut()
+                    .cancellations_handles
+                    .remove_with_id(next_cancellation_id);
+
+                get_module_mut().current_response = None;
+            },
+        )
     );
 
     get_module_mut()
@@ -420,9 +436,8 @@ pub unsafe extern "C" fn grip_create_default_options(amx: *const c_void, timeout
 
     get_module_mut()
         .options_handles
-        .insert_with_unique_id(RequestOptions::new(
-            reqwest::header::HeaderMap::default(),
-            try_and_log_ffi!(
+        .insert_with_unique_id(RequestOptions {
+            timeout: try_and_log_ffi!(
                 amx,
                 if timeout.approx_eq(&-1.0, std::f64::EPSILON, 2) {
                     Ok(None)
@@ -434,7 +449,8 @@ pub unsafe extern "C" fn grip_create_default_options(amx: *const c_void, timeout
                     Err(ffi_error(format!("Invalid timeout: {}", timeout)))
                 }
             ),
-        ))
+            ..Default::default()
+        })
 }
 
 #[no_mangle]
@@ -480,7 +496,7 @@ pub unsafe extern "C" fn grip_options_add_header(
 
     let header_value = try_and_log_ffi!(
         amx,
-        reqwest::header::HeaderValue::from_str(header_value)
+        hyper::header::HeaderValue::from_str(header_value)
         .chain_err(|| ffi_error(format!("Header value contains invalid byte sequences or was rejected by Hyper HTTP implementation: {}", header_value)))
     );
 
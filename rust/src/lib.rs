@@ -63,18 +63,173 @@ mod errors {
             RequestTimeout {
                 display("Request timeout")
             }
+            AcquireTimeout {
+                display("Timed out waiting for a free request slot")
+            }
+            RetriesExhausted(attempts: u32) {
+                display("Request failed after {} attempts", attempts)
+            }
+            UnsupportedScheme(t: String) {
+                display("Unsupported scheme: {}", t)
+            }
+            TooManyRedirects(limit: usize) {
+                display("More than {} redirects", limit)
+            }
+            TransferDecoding(t: String) {
+                display("Transfer decoding failed: {}", t)
+            }
+            InvalidSchema(t: String) {
+                display("Invalid JSON schema: {}", t)
+            }
+            SchemaValidation(errors: Vec<String>) {
+                display("Response doesn't match the schema: {}", errors.join("; "))
+            }
+            MalformedResponse(t: String) {
+                display("Malformed response: {}", t)
+            }
+            RequestFailed(response: Box<crate::networking_queue::Response>) {
+                display("Request failed with status {}", response.status_code)
+            }
+            HttpStatus(status: ::hyper::StatusCode, body: Vec<u8>) {
+                display("Server responded with status {}", status)
+            }
+            NoReachableAddress(host: String) {
+                display("No address of the allowed family for {}", host)
+            }
+            ConnectionRefused {
+                display("Connection refused")
+            }
+            ProxyError(reason: crate::networking_queue::SocksError) {
+                display("Proxy failed: {}", reason)
+            }
+            LocalAddressUnavailable(address: ::std::net::IpAddr) {
+                display("Can't bind the connection to the local address {}", address)
+            }
+            DnsResolution(host: String) {
+                display("Failed to resolve {}", host)
+            }
+            DnsTimeout(host: String) {
+                display("Timed out resolving {}", host)
+            }
+            QueueStopped {
+                display("Queue was stopped")
+            }
+            QueueFull {
+                display("Queue is full")
+            }
+            RuntimeInit {
+                display("Failed to start the runtime of the queue")
+            }
+            InvalidOptions(t: String) {
+                display("Invalid options of the queue: {}", t)
+            }
+            RelativeUriWithoutBase(uri: String) {
+                display("Relative URI '{}' requires the base URL of the queue", uri)
+            }
+            UriTooLong(length: usize, limit: usize) {
+                display("URI is {} bytes long, which exceeds the limit of {} bytes", length, limit)
+            }
+            BodyStreamConsumed {
+                display("Request body stream was already sent")
+            }
+            BodyLengthMismatch(declared: u64, produced: u64) {
+                display("Request body stream produced {} bytes instead of declared {}", produced, declared)
+            }
+            RequestBuildError(t: String) {
+                display("Failed to build request: {}", t)
+            }
+            DecodeError(charset: String) {
+                display("Body is not valid {}", charset)
+            }
+            WebSocketHandshake(t: String) {
+                display("WebSocket handshake failed: {}", t)
+            }
+            WebSocketProtocol(t: String) {
+                display("WebSocket protocol error: {}", t)
+            }
+            WebSocketClosed {
+                display("WebSocket connection is closed")
+            }
+            EventStream(t: String) {
+                display("Event stream failed: {}", t)
+            }
+            HyperError(t: String) {
+                display("{}", t)
+            }
         }
 
         foreign_links {
             CrossBeamError(::crossbeam_channel::TryRecvError);
-            HTTPError(::reqwest::Error);
             JSONError(::serde_json::Error);
+            IOError(::std::io::Error);
+            InvalidUri(::hyper::http::uri::InvalidUri);
         }
     }
 
     pub fn ffi_error<T: Into<String>>(t: T) -> Error {
         ErrorKind::FFIError(t.into()).into()
     }
+
+    impl From<::hyper::Error> for Error {
+        fn from(e: ::hyper::Error) -> Self {
+            let description = e.to_string();
+            Error::with_chain(e, ErrorKind::HyperError(description))
+        }
+    }
+
+    /// Category of the `hyper` error.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum TransportErrorKind {
+        /// Response couldn't be parsed.
+        Parse,
+        /// Connection couldn't be established.
+        Connect,
+        /// Request was canceled before completion.
+        Canceled,
+        /// Connection was closed before the response was received.
+        Closed,
+        /// Request couldn't be sent, e.g. because its body failed.
+        User,
+        /// Reading or writing the connection failed.
+        Io,
+        Other,
+    }
+
+    impl Error {
+        /// `hyper` error, which caused this error. Errors wrapping it, e.g. `ErrorKind::DnsResolution`,
+        /// keep it in the chain, which is printed by `display_chain`.
+        pub fn hyper_error(&self) -> Option<&::hyper::Error> {
+            let next = self.1.next_error.as_ref()?;
+            match next.downcast_ref::<::hyper::Error>() {
+                Some(e) => Some(e),
+                None => next.downcast_ref::<Error>()?.hyper_error(),
+            }
+        }
+
+        /// Category of the `hyper` error, which caused this error, without matching its message.
+        pub fn transport_error_kind(&self) -> Option<TransportErrorKind> {
+            let e = self.hyper_error()?;
+
+            Some(if e.is_parse() {
+                TransportErrorKind::Parse
+            } else if e.is_connect() {
+                TransportErrorKind::Connect
+            } else if e.is_canceled() {
+                TransportErrorKind::Canceled
+            } else if e.is_closed() {
+                TransportErrorKind::Closed
+            } else if e.is_user() {
+                TransportErrorKind::User
+            } else if e
+                .cause2()
+                .map_or(false, |cause| cause.is::<::std::io::Error>())
+            {
+                TransportErrorKind::Io
+            } else {
+                TransportErrorKind::Other
+            })
+        }
+    }
 }
 
 #[macro_use]
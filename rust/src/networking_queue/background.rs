@@ -0,0 +1,132 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Background requests, which yield to the foreground ones.
+
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use futures::task::{self, Task};
+
+#[derive(Default)]
+struct GateInner {
+    /// Number of foreground requests, which are pending or in flight.
+    foreground: usize,
+    /// Background requests waiting for the foreground ones to complete.
+    parked: Vec<Task>,
+}
+
+/// Lets background requests run only while there are no foreground requests.
+#[derive(Clone, Default)]
+pub(crate) struct ForegroundGate(Arc<Mutex<GateInner>>);
+
+impl ForegroundGate {
+    /// Marks foreground request as started. It is completed, when guard is dropped.
+    pub fn enter(&self) -> ForegroundGuard {
+        self.0.lock().unwrap().foreground += 1;
+        ForegroundGuard(self.clone())
+    }
+
+    /// Polls `inner` only while there are no foreground requests.
+    /// Otherwise `inner` is paused: it isn't polled, but it isn't dropped either.
+    pub fn yielding<F: Future>(&self, inner: F) -> Yielding<F> {
+        Yielding {
+            gate: self.clone(),
+            inner,
+        }
+    }
+}
+
+pub(crate) struct ForegroundGuard(ForegroundGate);
+
+impl Drop for ForegroundGuard {
+    fn drop(&mut self) {
+        let parked = {
+            let mut inner = (self.0).0.lock().unwrap();
+            inner.foreground -= 1;
+            if inner.foreground > 0 {
+                return;
+            }
+            std::mem::replace(&mut inner.parked, vec![])
+        };
+
+        for task in parked {
+            task.notify();
+        }
+    }
+}
+
+pub(crate) struct Yielding<F> {
+    gate: ForegroundGate,
+    inner: F,
+}
+
+impl<F: Future> Future for Yielding<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let mut gate = self.gate.0.lock().unwrap();
+            if gate.foreground > 0 {
+                gate.parked.push(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn test_yielding() {
+        let gate = ForegroundGate::default();
+        let foreground = gate.enter();
+        let mut background = gate.yielding(future::ok::<_, ()>("done"));
+
+        future::lazy(move || {
+            assert_eq!(background.poll(), Ok(Async::NotReady));
+            assert_eq!(gate.0.lock().unwrap().parked.len(), 1);
+
+            drop(foreground);
+            assert!(gate.0.lock().unwrap().parked.is_empty());
+            assert_eq!(background.poll(), Ok(Async::Ready("done")));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use flate2::write::{GzDecoder, GzEncoder, ZlibEncoder};
+use futures::prelude::*;
+use hyper::body::Payload;
+use tempfile::NamedTempFile;
+
+use super::multipart::{MultipartParser, PartHandler};
+use crate::errors::*;
+
+/// Describes how the response body is consumed.
+#[derive(Clone, Debug)]
+pub enum BodyStrategy {
+    /// Whole body is kept in memory.
+    Buffer,
+    /// Body is kept in memory until it grows past `threshold` bytes,
+    /// after that it is moved to the temporary file.
+    SpillToFile { threshold: usize },
+    /// Body is split into parts of the `multipart/*` response, which are passed to the handler
+    /// as soon as each of them is received. Response body is left empty.
+    Multipart(PartHandler),
+}
+
+impl Default for BodyStrategy {
+    fn default() -> Self {
+        BodyStrategy::Buffer
+    }
+}
+
+/// Picks `BodyStrategy` once status and headers of the response are known.
+#[derive(Clone)]
+pub struct BodyStrategySelector(Arc<SelectorFn>);
+
+type SelectorFn =
+    dyn Fn(&hyper::header::HeaderMap, hyper::StatusCode) -> BodyStrategy + Send + Sync;
+
+impl BodyStrategySelector {
+    pub fn new<F>(selector: F) -> Self
+    where
+        F: 'static + Fn(&hyper::header::HeaderMap, hyper::StatusCode) -> BodyStrategy + Send + Sync,
+    {
+        BodyStrategySelector(Arc::new(selector))
+    }
+
+    pub fn select(
+        &self,
+        headers: &hyper::header::HeaderMap,
+        status_code: hyper::StatusCode,
+    ) -> BodyStrategy {
+        (self.0)(headers, status_code)
+    }
+}
+
+impl std::fmt::Debug for BodyStrategySelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("BodyStrategySelector")
+    }
+}
+
+/// Response body which was moved to the temporary file. File is removed on drop.
+#[derive(Debug)]
+pub struct SpilledBody {
+    file: NamedTempFile,
+    len: usize,
+}
+
+impl SpilledBody {
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Opens new independent reader positioned at the start of the body.
+    pub fn reader(&self) -> Result<File> {
+        Ok(self.file.reopen()?)
+    }
+
+    /// Copies the body into the new temporary file.
+    pub(crate) fn duplicate(&self) -> Result<SpilledBody> {
+        let mut file = NamedTempFile::new()?;
+        io::copy(&mut self.reader()?, &mut file)?;
+
+        Ok(SpilledBody {
+            file,
+            len: self.len,
+        })
+    }
+}
+
+/// Custom transfer-decoding applied to the body, for servers which encode it in a non-compliant way.
+/// `hyper` still removes the outermost transfer coding, the decoder receives the rest.
+/// When decoder is set, body is always kept in memory.
+#[derive(Clone)]
+pub struct TransferDecoder(Arc<DecoderFn>);
+
+type DecoderFn = dyn Fn(&hyper::header::HeaderMap, Vec<u8>) -> Result<Vec<u8>> + Send + Sync;
+
+impl TransferDecoder {
+    pub fn new<F>(decoder: F) -> Self
+    where
+        F: 'static + Fn(&hyper::header::HeaderMap, Vec<u8>) -> Result<Vec<u8>> + Send + Sync,
+    {
+        TransferDecoder(Arc::new(decoder))
+    }
+
+    /// Decodes one more layer of `chunked` coding, for servers which apply it twice.
+    pub fn chunked() -> Self {
+        TransferDecoder::new(|_, body| decode_chunked(&body[..]))
+    }
+
+    pub fn decode(&self, headers: &hyper::header::HeaderMap, body: Vec<u8>) -> Result<Vec<u8>> {
+        (self.0)(headers, body)
+    }
+}
+
+impl std::fmt::Debug for TransferDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("TransferDecoder")
+    }
+}
+
+/// Decodes body in the `chunked` transfer coding. Trailers are ignored.
+pub fn decode_chunked(mut encoded: &[u8]) -> Result<Vec<u8>> {
+    fn split_line(data: &[u8]) -> Result<(&[u8], &[u8])> {
+        let position = data
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| ErrorKind::TransferDecoding("missing CRLF".to_owned()))?;
+        Ok((&data[..position], &data[position + 2..]))
+    }
+
+    let mut decoded = vec![];
+    loop {
+        let (line, rest) = split_line(encoded)?;
+        let size = String::from_utf8_lossy(line);
+        let size = size.split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| ErrorKind::TransferDecoding(format!("invalid chunk size {:?}", size)))?;
+
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        if rest.len() < size + 2 || &rest[size..size + 2] != b"\r\n" {
+            bail!(ErrorKind::TransferDecoding("truncated chunk".to_owned()));
+        }
+
+        decoded.extend_from_slice(&rest[..size]);
+        encoded = &rest[size + 2..];
+    }
+}
+
+/// Request bodies up to this size aren't compressed, as the overhead outweighs the savings.
+const MIN_COMPRESSED_BODY: usize = 1024;
+
+/// Content coding applied to the request body. See `RequestOptions::compress_request`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    /// `zlib` format, as `deflate` coding is defined by HTTP.
+    Deflate,
+}
+
+impl Compression {
+    /// Value of the `Content-Encoding` header.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// `None`, when the body is too small to be worth compressing.
+    pub(crate) fn compress(self, body: &[u8]) -> Option<Bytes> {
+        if body.len() <= MIN_COMPRESSED_BODY {
+            return None;
+        }
+
+        let level = flate2::Compression::default();
+        let compressed = match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], level);
+                encoder.write_all(body).ok()?;
+                encoder.finish().ok()?
+            }
+            Compression::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], level);
+                encoder.write_all(body).ok()?;
+                encoder.finish().ok()?
+            }
+        };
+
+        Some(compressed.into())
+    }
+}
+
+/// Decompresses `gzip` encoded body on the fly.
+pub(crate) struct GzipBody<S> {
+    inner: S,
+    decoder: Option<GzDecoder<Vec<u8>>>,
+}
+
+impl<S> GzipBody<S> {
+    pub fn new(inner: S) -> Self {
+        GzipBody {
+            inner,
+            decoder: Some(GzDecoder::new(vec![])),
+        }
+    }
+}
+
+impl<S: Stream<Item = hyper::Chunk, Error = Error>> Stream for GzipBody<S> {
+    type Item = hyper::Chunk;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, Error> {
+        loop {
+            let decoder = match &mut self.decoder {
+                Some(decoder) => decoder,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let finished = match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => {
+                    decoder.write_all(&chunk)?;
+                    false
+                }
+                Async::Ready(None) => {
+                    decoder.try_finish()?;
+                    true
+                }
+            };
+
+            let decoded = mem::take(decoder.get_mut());
+            if finished {
+                self.decoder = None;
+            }
+
+            if !decoded.is_empty() {
+                return Ok(Async::Ready(Some(decoded.into())));
+            }
+        }
+    }
+}
+
+/// Trailers of the response, set once its body is over.
+pub(crate) type Trailers = Arc<Mutex<Option<hyper::header::HeaderMap>>>;
+
+/// Stores trailers, which follow the body, into `trailers`.
+pub(crate) struct TrailersBody {
+    inner: hyper::Body,
+    trailers: Trailers,
+    data_finished: bool,
+}
+
+impl TrailersBody {
+    pub fn new(inner: hyper::Body, trailers: Trailers) -> Self {
+        TrailersBody {
+            inner,
+            trailers,
+            data_finished: false,
+        }
+    }
+}
+
+impl Stream for TrailersBody {
+    type Item = hyper::Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, hyper::Error> {
+        if !self.data_finished {
+            match futures::try_ready!(self.inner.poll_data()) {
+                Some(chunk) => return Ok(Async::Ready(Some(chunk))),
+                None => self.data_finished = true,
+            }
+        }
+
+        if let Some(trailers) = futures::try_ready!(self.inner.poll_trailers()) {
+            *self.trailers.lock().unwrap() = Some(trailers);
+        }
+        Ok(Async::Ready(None))
+    }
+}
+
+/// Collects response chunks according to the `BodyStrategy`.
+pub(crate) struct BodyAccumulator {
+    strategy: BodyStrategy,
+    memory: Vec<u8>,
+    spilled: Option<SpilledBody>,
+    multipart: Option<MultipartParser>,
+}
+
+impl BodyAccumulator {
+    pub fn new(strategy: BodyStrategy) -> Self {
+        BodyAccumulator {
+            strategy,
+            memory: vec![],
+            spilled: None,
+            multipart: None,
+        }
+    }
+
+    /// Same as `new`, but also reads multipart boundary from the response `headers`, when it is needed.
+    pub fn for_response(
+        strategy: BodyStrategy,
+        headers: &hyper::header::HeaderMap,
+    ) -> Result<Self> {
+        let multipart = match strategy {
+            BodyStrategy::Multipart(_) => Some(MultipartParser::from_headers(headers)?),
+            _ => None,
+        };
+
+        Ok(BodyAccumulator {
+            multipart,
+            ..BodyAccumulator::new(strategy)
+        })
+    }
+
+    pub fn push(mut self, chunk: &[u8]) -> Result<Self> {
+        if let (Some(parser), BodyStrategy::Multipart(handler)) =
+            (&mut self.multipart, &self.strategy)
+        {
+            for part in parser.push(chunk)? {
+                handler.handle(part);
+            }
+            return Ok(self);
+        }
+
+        if let Some(spilled) = &mut self.spilled {
+            spilled.file.write_all(chunk)?;
+            spilled.len += chunk.len();
+            return Ok(self);
+        }
+
+        self.memory.extend_from_slice(chunk);
+
+        if let BodyStrategy::SpillToFile { threshold } = self.strategy {
+            if self.memory.len() > threshold {
+                let mut file = NamedTempFile::new()?;
+                file.write_all(&self.memory[..])?;
+
+                self.spilled = Some(SpilledBody {
+                    file,
+                    len: self.memory.len(),
+                });
+                self.memory = vec![];
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn finish(mut self) -> Result<(Vec<u8>, Option<SpilledBody>)> {
+        if let Some(parser) = self.multipart.take() {
+            parser.finish()?;
+        }
+
+        if let Some(spilled) = &mut self.spilled {
+            spilled.file.flush()?;
+        }
+
+        Ok((self.memory, self.spilled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_spill_to_file() {
+        let (memory, spilled) = BodyAccumulator::new(BodyStrategy::SpillToFile { threshold: 16 })
+            .push(b"0123456789")
+            .unwrap()
+            .push(b"abcdefghij")
+            .unwrap()
+            .push(b"ABCDEFGHIJ")
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        assert!(memory.is_empty());
+
+        let spilled = spilled.unwrap();
+        assert_eq!(spilled.len(), 30);
+
+        let mut content = String::new();
+        spilled
+            .reader()
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "0123456789abcdefghijABCDEFGHIJ");
+
+        let path = spilled.path().to_owned();
+        assert!(path.exists());
+        drop(spilled);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_below_threshold_stays_in_memory() {
+        let (memory, spilled) = BodyAccumulator::new(BodyStrategy::SpillToFile { threshold: 16 })
+            .push(b"0123456789")
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        assert_eq!(&memory[..], b"0123456789");
+        assert!(spilled.is_none());
+    }
+
+    #[test]
+    fn test_gzip_body() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"compressed body").unwrap();
+        let encoded = encoder.finish().unwrap();
+
+        let chunks = encoded
+            .chunks(4)
+            .map(|chunk| Ok(hyper::Chunk::from(chunk.to_vec())))
+            .collect::<Vec<Result<_>>>();
+
+        let decoded = GzipBody::new(futures::stream::iter_result(chunks))
+            .concat2()
+            .wait()
+            .unwrap();
+        assert_eq!(&decoded[..], b"compressed body");
+    }
+
+    #[test]
+    fn test_compress() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+
+        assert_eq!(Compression::Gzip.compress(b"tiny"), None);
+
+        let body = "compressible ".repeat(200).into_bytes();
+
+        let compressed = Compression::Gzip.compress(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decompressed = vec![];
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let compressed = Compression::Deflate.compress(&body).unwrap();
+        let mut decompressed = vec![];
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        assert_eq!(
+            decode_chunked(b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n").unwrap(),
+            b"hello, world"
+        );
+        assert!(decode_chunked(b"5\r\nhel").is_err());
+        assert!(decode_chunked(b"zz\r\n").is_err());
+    }
+}
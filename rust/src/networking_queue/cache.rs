@@ -0,0 +1,372 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Cache of the `GET` responses, revalidated with `ETag` and `Last-Modified`.
+
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+use super::{Request, RequestType};
+
+struct Entry {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    body: Bytes,
+    /// Values of the request headers named by `Vary` of the response.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    last_used: u64,
+}
+
+impl Entry {
+    /// Whether the entry was stored for the request with the same values of the headers named by `Vary`.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+struct Entries {
+    map: FnvHashMap<String, Entry>,
+    clock: u64,
+}
+
+/// Keeps at most `capacity` bodies, evicting the least recently used one.
+pub(crate) struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+            entries: Mutex::new(Entries {
+                map: FnvHashMap::default(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Conditional headers for the cached response of the `request`.
+    /// Empty, when nothing is cached or request already is conditional.
+    pub fn validators(&self, request: &Request) -> HeaderMap {
+        let mut validators = HeaderMap::new();
+        let headers = &request.options.headers;
+        if key(request).is_none()
+            || headers.contains_key(header::IF_NONE_MATCH)
+            || headers.contains_key(header::IF_MODIFIED_SINCE)
+        {
+            return validators;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.map.get(request.uri.as_str());
+        if let Some(entry) = entry.filter(|entry| entry.matches(headers)) {
+            if let Some(etag) = &entry.etag {
+                validators.insert(header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                validators.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+
+        validators
+    }
+
+    /// Cached body for the `key`, which was revalidated by the server for the request with `request_headers`.
+    pub fn revalidated(&self, key: &str, request_headers: &HeaderMap) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let entry = entries.map.get_mut(key)?;
+        if !entry.matches(request_headers) {
+            return None;
+        }
+        entry.last_used = clock;
+        Some(entry.body.clone())
+    }
+
+    /// Stores successful response for the `key`, when it has validators and may be stored.
+    /// Responses to the requests with credentials are stored only with `Cache-Control: public`.
+    pub fn store(&self, key: &str, request_headers: &HeaderMap, headers: &HeaderMap, body: &Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let credentialed = request_headers.contains_key(header::AUTHORIZATION)
+            || request_headers.contains_key(header::COOKIE);
+        if credentialed && !has_directive(headers, "public") {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let etag = headers.get(header::ETAG).cloned();
+        let last_modified = headers.get(header::LAST_MODIFIED).cloned();
+        let vary = match vary(headers, request_headers) {
+            Some(vary) => vary,
+            None => {
+                entries.map.remove(key);
+                return;
+            }
+        };
+        if has_directive(headers, "no-store") || (etag.is_none() && last_modified.is_none()) {
+            entries.map.remove(key);
+            return;
+        }
+
+        if !entries.map.contains_key(key) && entries.map.len() >= self.capacity {
+            let least_recent = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                entries.map.remove(&least_recent);
+            }
+        }
+
+        entries.clock += 1;
+        let entry = Entry {
+            etag,
+            last_modified,
+            body: body.clone(),
+            vary,
+            last_used: entries.clock,
+        };
+        entries.map.insert(key.to_owned(), entry);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().map.clear();
+    }
+}
+
+/// Key of the cached response of the `request`. Only `GET` responses are cached.
+pub(crate) fn key(request: &Request) -> Option<&str> {
+    match request.http_type {
+        RequestType::Get => Some(request.uri.as_str()),
+        _ => None,
+    }
+}
+
+fn has_directive(headers: &HeaderMap, directive: &str) -> bool {
+    list_items(headers, header::CACHE_CONTROL).any(|item| item.eq_ignore_ascii_case(directive))
+}
+
+/// Values of the request headers named by `Vary` of the response.
+/// `None`, when the response varies on something else than the headers, i.e. `Vary: *`.
+fn vary(
+    headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    list_items(headers, header::VARY)
+        .map(|item| {
+            if item == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(item.as_bytes()).ok()?;
+            let value = request_headers.get(&name).cloned();
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Items of the comma separated lists in all values of the header `name`.
+fn list_items(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RequestBuilder;
+    use super::*;
+
+    fn get(uri: &str) -> Request {
+        RequestBuilder::default()
+            .http_type(RequestType::Get)
+            .uri(uri.parse().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn etag(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ResponseCache::new(2);
+        let (a, c) = (get("http://a/"), get("http://c/"));
+
+        cache.store(
+            "http://a/",
+            &HeaderMap::new(),
+            &etag("\"a\""),
+            &Bytes::from_static(b"a"),
+        );
+        cache.store(
+            "http://b/",
+            &HeaderMap::new(),
+            &etag("\"b\""),
+            &Bytes::from_static(b"b"),
+        );
+        assert_eq!(
+            cache.revalidated("http://a/", &HeaderMap::new()),
+            Some(Bytes::from_static(b"a"))
+        );
+
+        cache.store(
+            "http://c/",
+            &HeaderMap::new(),
+            &etag("\"c\""),
+            &Bytes::from_static(b"c"),
+        );
+        assert_eq!(cache.revalidated("http://b/", &HeaderMap::new()), None);
+        assert_eq!(
+            cache.validators(&a).get(header::IF_NONE_MATCH).unwrap(),
+            "\"a\""
+        );
+        assert!(cache.validators(&c).contains_key(header::IF_NONE_MATCH));
+    }
+
+    #[test]
+    fn test_no_store() {
+        let cache = ResponseCache::new(2);
+        let a = get("http://a/");
+
+        cache.store(
+            "http://a/",
+            &HeaderMap::new(),
+            &etag("\"a\""),
+            &Bytes::from_static(b"a"),
+        );
+        let mut headers = etag("\"a\"");
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, no-store"),
+        );
+        cache.store(
+            "http://a/",
+            &HeaderMap::new(),
+            &headers,
+            &Bytes::from_static(b"a"),
+        );
+
+        assert!(cache.validators(&a).is_empty());
+    }
+
+    #[test]
+    fn test_vary() {
+        let cache = ResponseCache::new(2);
+        let with_language = |language: &'static str| {
+            let mut request = get("http://a/");
+            request
+                .options
+                .headers
+                .insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(language));
+            request
+        };
+        let (en, de) = (with_language("en"), with_language("de"));
+
+        let mut headers = etag("\"en\"");
+        headers.insert(header::VARY, HeaderValue::from_static("Accept-Language"));
+        cache.store(
+            "http://a/",
+            &en.options.headers,
+            &headers,
+            &Bytes::from_static(b"en"),
+        );
+
+        assert_eq!(
+            cache.validators(&en).get(header::IF_NONE_MATCH).unwrap(),
+            "\"en\""
+        );
+        assert!(cache.validators(&de).is_empty());
+        assert!(cache.validators(&get("http://a/")).is_empty());
+        assert_eq!(cache.revalidated("http://a/", &de.options.headers), None);
+
+        headers.insert(header::VARY, HeaderValue::from_static("*"));
+        cache.store(
+            "http://a/",
+            &en.options.headers,
+            &headers,
+            &Bytes::from_static(b"en"),
+        );
+        assert!(cache.validators(&en).is_empty());
+    }
+
+    #[test]
+    fn test_credentialed() {
+        let cache = ResponseCache::new(2);
+        let mut request = get("http://a/");
+        request.options.headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+
+        cache.store(
+            "http://a/",
+            &request.options.headers,
+            &etag("\"a\""),
+            &Bytes::from_static(b"a"),
+        );
+        assert!(cache.validators(&request).is_empty());
+
+        let mut headers = etag("\"a\"");
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60"),
+        );
+        cache.store(
+            "http://a/",
+            &request.options.headers,
+            &headers,
+            &Bytes::from_static(b"a"),
+        );
+        assert!(cache
+            .validators(&request)
+            .contains_key(header::IF_NONE_MATCH));
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Sharing of the single execution between identical requests, which are in flight simultaneously.
+
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use fnv::FnvHashMap;
+
+use super::{Request, RequestId, RequestType, ResponseCallBack};
+use crate::errors::*;
+
+/// Identifies requests, which may share the response: URI, body and headers are equal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CoalesceKey {
+    uri: String,
+    hash: u64,
+}
+
+impl CoalesceKey {
+    /// Returns `None` for the requests, which are never coalesced. Only `GET` requests are.
+    pub fn new(request: &Request) -> Option<CoalesceKey> {
+        match request.http_type {
+            RequestType::Get => {}
+            _ => return None,
+        }
+
+        let mut hasher = DefaultHasher::new();
+        request.body.hash(&mut hasher);
+        for (name, value) in &request.options.headers {
+            name.as_str().hash(&mut hasher);
+            value.as_bytes().hash(&mut hasher);
+        }
+
+        Some(CoalesceKey {
+            uri: request.uri.as_str().to_owned(),
+            hash: hasher.finish(),
+        })
+    }
+}
+
+/// Request, which waits for the response of the matching in-flight request.
+pub(crate) struct Waiter {
+    pub id: RequestId,
+    pub request: Request,
+    pub callback: Box<ResponseCallBack>,
+}
+
+/// Requests in flight, along with the requests attached to them.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight(Arc<Mutex<FnvHashMap<CoalesceKey, Vec<Waiter>>>>);
+
+impl InFlight {
+    /// Attaches `waiter` to the matching in-flight request.
+    /// When there is none, `waiter` is returned back and has to be executed, becoming in flight.
+    pub fn attach(&self, key: &CoalesceKey, waiter: Waiter) -> Option<Waiter> {
+        match self.0.lock().unwrap().entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().push(waiter);
+                None
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(vec![]);
+                Some(waiter)
+            }
+        }
+    }
+
+    /// Detaches the waiter with `id`, e.g. because it was cancelled. `None`, when it was already completed.
+    pub fn detach(&self, key: &CoalesceKey, id: RequestId) -> Option<Waiter> {
+        let mut groups = self.0.lock().unwrap();
+        let waiters = groups.get_mut(key)?;
+        let index = waiters.iter().position(|waiter| waiter.id == id)?;
+        Some(waiters.remove(index))
+    }
+
+    /// Marks request as completed, returning attached waiters.
+    pub fn complete(&self, key: &CoalesceKey) -> Vec<Waiter> {
+        self.0.lock().unwrap().remove(key).unwrap_or_default()
+    }
+}
+
+/// Copies the error for the waiter. Errors of the foreign crates are kept only as the message.
+pub(crate) fn duplicate_error(error: &Error) -> Error {
+    let kind = match error.kind() {
+        ErrorKind::FFIError(t) => ErrorKind::FFIError(t.clone()),
+        ErrorKind::RequestCancelled => ErrorKind::RequestCancelled,
+        ErrorKind::RequestTimeout => ErrorKind::RequestTimeout,
+        ErrorKind::AcquireTimeout => ErrorKind::AcquireTimeout,
+        ErrorKind::RetriesExhausted(attempts) => ErrorKind::RetriesExhausted(*attempts),
+        ErrorKind::UnsupportedScheme(t) => ErrorKind::UnsupportedScheme(t.clone()),
+        ErrorKind::TooManyRedirects(limit) => ErrorKind::TooManyRedirects(*limit),
+        ErrorKind::TransferDecoding(t) => ErrorKind::TransferDecoding(t.clone()),
+        ErrorKind::InvalidSchema(t) => ErrorKind::InvalidSchema(t.clone()),
+        ErrorKind::SchemaValidation(errors) => ErrorKind::SchemaValidation(errors.clone()),
+        ErrorKind::MalformedResponse(t) => ErrorKind::MalformedResponse(t.clone()),
+        ErrorKind::NoReachableAddress(host) => ErrorKind::NoReachableAddress(host.clone()),
+        ErrorKind::BodyStreamConsumed => ErrorKind::BodyStreamConsumed,
+        ErrorKind::BodyLengthMismatch(declared, produced) => {
+            ErrorKind::BodyLengthMismatch(*declared, *produced)
+        }
+        ErrorKind::UriTooLong(length, limit) => ErrorKind::UriTooLong(*length, *limit),
+        ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+        ErrorKind::DnsResolution(host) => ErrorKind::DnsResolution(host.clone()),
+        ErrorKind::DnsTimeout(host) => ErrorKind::DnsTimeout(host.clone()),
+        ErrorKind::QueueStopped => ErrorKind::QueueStopped,
+        ErrorKind::QueueFull => ErrorKind::QueueFull,
+        ErrorKind::RuntimeInit => ErrorKind::RuntimeInit,
+        ErrorKind::RequestBuildError(t) => ErrorKind::RequestBuildError(t.clone()),
+        ErrorKind::DecodeError(charset) => ErrorKind::DecodeError(charset.clone()),
+        _ => ErrorKind::Msg(error.to_string()),
+    };
+
+    kind.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RequestBuilder;
+    use super::*;
+
+    #[test]
+    fn test_coalesce_key() {
+        let request = |http_type, body: &[u8]| {
+            RequestBuilder::default()
+                .http_type(http_type)
+                .uri("http://example.com/".parse().unwrap())
+                .body(body.to_vec())
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(
+            CoalesceKey::new(&request(RequestType::Get, b"a")),
+            CoalesceKey::new(&request(RequestType::Get, b"a"))
+        );
+        assert_ne!(
+            CoalesceKey::new(&request(RequestType::Get, b"a")),
+            CoalesceKey::new(&request(RequestType::Get, b"b"))
+        );
+        assert_eq!(CoalesceKey::new(&request(RequestType::Post, b"a")), None);
+    }
+}
@@ -0,0 +1,82 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Connectors replacing the transport of the queue for the single request.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Connection established by the `Connector`.
+pub trait Connection: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Connection for T {}
+
+pub type ConnectFuture = Box<dyn Future<Item = Box<dyn Connection>, Error = io::Error> + Send>;
+
+/// Establishes connections for the requests with `RequestOptions::connector_override`.
+/// Connection is used as is: TLS isn't applied on top of it, even for `https` URLs.
+pub trait Connector: Send + Sync + fmt::Debug {
+    /// Connects to the host of `url`.
+    fn connect(&self, url: &url::Url) -> ConnectFuture;
+}
+
+/// Adapts `Connector` to `hyper` for the requests to `url`.
+#[derive(Clone)]
+pub(crate) struct OverrideConnector {
+    pub connector: Arc<dyn Connector>,
+    pub url: url::Url,
+}
+
+impl OverrideConnector {
+    /// Client used for the single request. Connections aren't reused, as the client isn't shared.
+    pub fn client(self) -> hyper::Client<OverrideConnector> {
+        hyper::Client::builder().keep_alive(false).build(self)
+    }
+}
+
+impl Connect for OverrideConnector {
+    type Transport = Box<dyn Connection>;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (Self::Transport, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, _dst: Destination) -> Self::Future {
+        Box::new(
+            self.connector
+                .connect(&self.url)
+                .map(|connection| (connection, Connected::new())),
+        )
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use hyper::header::{HeaderName, HeaderValue};
+
+use super::{Request, RequestType};
+
+/// Bodies larger than this are referenced as the file instead of being inlined.
+const MAX_INLINE_BODY: usize = 4096;
+
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+impl Request {
+    /// Renders equivalent `curl` command to reproduce the request.
+    /// Values of sensitive headers are redacted, large or binary bodies are referenced as `@body.bin`.
+    pub fn to_curl(&self) -> String {
+        let method = match self.http_type {
+            RequestType::Get => "GET",
+            RequestType::Post => "POST",
+            RequestType::Put => "PUT",
+            RequestType::Delete => "DELETE",
+        };
+
+        let mut command = format!("curl -X {} {}", method, shell_quote(self.uri.as_str()));
+
+        for (name, value) in &self.options.headers {
+            let value = redacted_value(name, value);
+
+            command.push_str(" -H ");
+            command.push_str(&shell_quote(&format!("{}: {}", name, value)));
+        }
+
+        // Unlike `--data-binary`, `--data-raw` doesn't read the file, when the body starts with `@`.
+        if !self.body.is_empty() {
+            match std::str::from_utf8(&self.body) {
+                Ok(body) if body.len() <= MAX_INLINE_BODY => {
+                    command.push_str(" --data-raw ");
+                    command.push_str(&shell_quote(body));
+                }
+                _ => command.push_str(" --data-binary @body.bin"),
+            }
+        }
+
+        command
+    }
+}
+
+/// Value of the header, unless it is sensitive.
+pub(crate) fn redacted_value(name: &HeaderName, value: &HeaderValue) -> String {
+    if SENSITIVE_HEADERS.contains(&name.as_str()) {
+        "<redacted>".to_owned()
+    } else {
+        String::from_utf8_lossy(value.as_bytes()).into_owned()
+    }
+}
+
+/// Quotes string for POSIX shells.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{RequestBuilder, RequestOptionsBuilder};
+    use super::*;
+
+    #[test]
+    fn test_to_curl() {
+        let mut headers = hyper::header::HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Post)
+            .uri("http://localhost:8080/api?x=1&y=2".parse().unwrap())
+            .body(br#"{"name": "it's"}"#.to_vec())
+            .options(
+                RequestOptionsBuilder::default()
+                    .headers(headers)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.to_curl(),
+            r#"curl -X POST 'http://localhost:8080/api?x=1&y=2' -H 'content-type: application/json' -H 'authorization: <redacted>' --data-raw '{"name": "it'\''s"}'"#
+        );
+    }
+
+    #[test]
+    fn test_to_curl_body_starting_with_at() {
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Post)
+            .uri("http://localhost/".parse().unwrap())
+            .body(b"@mention".to_vec())
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.to_curl(),
+            "curl -X POST 'http://localhost/' --data-raw '@mention'"
+        );
+    }
+
+    #[test]
+    fn test_to_curl_large_body() {
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Put)
+            .uri("http://localhost/".parse().unwrap())
+            .body(vec![0; MAX_INLINE_BODY + 1])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.to_curl(),
+            "curl -X PUT 'http://localhost/' --data-binary @body.bin"
+        );
+    }
+}
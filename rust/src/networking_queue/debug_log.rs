@@ -0,0 +1,107 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Logging of the requests with `RequestOptions::debug_log` set.
+
+use std::fmt;
+
+use hyper::header::HeaderMap;
+
+use super::curl::redacted_value;
+use super::Request;
+use crate::errors::*;
+
+/// Target of the records, which may be used for filtering.
+pub(crate) const TARGET: &str = "grip::debug_log";
+
+/// Longer bodies are truncated.
+const BODY_PREVIEW_LENGTH: usize = 1024;
+
+pub(crate) fn log_request(request: &Request) {
+    log(format_args!(
+        "Request {:?} '{}'\n{}{}",
+        request.http_type,
+        request.uri,
+        render_headers(&request.options.headers),
+        preview(&request.body)
+    ));
+}
+
+pub(crate) fn log_response(
+    request: &Request,
+    status_code: hyper::StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) {
+    log(format_args!(
+        "Response {} for '{}'\n{}{}",
+        status_code,
+        request.uri,
+        render_headers(headers),
+        preview(body)
+    ));
+}
+
+pub(crate) fn log_error(request: &Request, error: &Error) {
+    log(format_args!("Request '{}' failed: {}", request.uri, error));
+}
+
+/// Passes the record to the logger directly, bypassing the global maximal level.
+fn log(args: fmt::Arguments) {
+    log::logger().log(
+        &log::Record::builder()
+            .level(log::Level::Debug)
+            .target(TARGET)
+            .module_path(Some(module_path!()))
+            .file(Some(file!()))
+            .args(args)
+            .build(),
+    );
+}
+
+fn render_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\n", name, redacted_value(name, value)))
+        .collect()
+}
+
+fn preview(body: &[u8]) -> String {
+    if body.len() <= BODY_PREVIEW_LENGTH {
+        String::from_utf8_lossy(body).into_owned()
+    } else {
+        format!(
+            "{}... ({} bytes)",
+            String::from_utf8_lossy(&body[..BODY_PREVIEW_LENGTH]),
+            body.len()
+        )
+    }
+}
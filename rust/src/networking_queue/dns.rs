@@ -0,0 +1,381 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use fnv::FnvHashMap;
+use futures::future;
+use futures::prelude::*;
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use tokio::prelude::FutureExt;
+
+pub type ResolveFuture = Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send>;
+
+/// Resolves host names into the addresses.
+pub trait Resolver: Send + Sync + fmt::Debug {
+    fn resolve(&self, host: &str) -> ResolveFuture;
+}
+
+/// Resolver using blocking `getaddrinfo` calls on the dedicated thread pool.
+#[derive(Clone)]
+pub struct SystemResolver {
+    resolver: GaiResolver,
+}
+
+impl SystemResolver {
+    pub fn new(threads: usize) -> Self {
+        SystemResolver {
+            resolver: GaiResolver::new(threads),
+        }
+    }
+}
+
+impl fmt::Debug for SystemResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SystemResolver")
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> ResolveFuture {
+        match host.parse::<Name>() {
+            Ok(name) => Box::new(self.resolver.resolve(name).map(Iterator::collect)),
+            Err(e) => Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        }
+    }
+}
+
+/// Addresses, which may be used to connect to the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    Any,
+    V4Only,
+    V6Only,
+}
+
+impl Default for AddressFamily {
+    fn default() -> Self {
+        AddressFamily::Any
+    }
+}
+
+impl AddressFamily {
+    pub fn allows(self, address: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4Only => address.is_ipv4(),
+            AddressFamily::V6Only => address.is_ipv6(),
+        }
+    }
+}
+
+/// Error of the `Resolver`, as it is seen by the connector.
+#[derive(Debug)]
+pub(crate) struct ResolveFailure(io::Error);
+
+impl fmt::Display for ResolveFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DNS resolution failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for ResolveFailure {}
+
+impl ResolveFailure {
+    /// Whether connection failed, because the host name couldn't be resolved.
+    pub fn caused(error: &hyper::Error) -> bool {
+        error.is_connect()
+            && error
+                .cause2()
+                .and_then(|cause| cause.downcast_ref::<io::Error>())
+                .and_then(|io_error| io_error.get_ref())
+                .map_or(false, |inner| inner.is::<ResolveFailure>())
+    }
+}
+
+/// Host was resolved, but none of its addresses is allowed by the `AddressFamily`.
+#[derive(Debug)]
+pub(crate) struct NoAllowedAddress;
+
+impl fmt::Display for NoAllowedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no address of the allowed family")
+    }
+}
+
+impl std::error::Error for NoAllowedAddress {}
+
+impl NoAllowedAddress {
+    /// Whether connection failed because of the `AddressFamily`.
+    pub fn caused(error: &hyper::Error) -> bool {
+        error.is_connect()
+            && error
+                .cause2()
+                .and_then(|cause| cause.downcast_ref::<io::Error>())
+                .and_then(|io_error| io_error.get_ref())
+                .map_or(false, |inner| inner.is::<NoAllowedAddress>())
+    }
+}
+
+/// Host wasn't resolved within the timeout of the `CachingResolver`.
+#[derive(Debug)]
+pub(crate) struct ResolveTimeout;
+
+impl fmt::Display for ResolveTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DNS resolution timed out")
+    }
+}
+
+impl std::error::Error for ResolveTimeout {}
+
+impl ResolveTimeout {
+    /// Whether connection failed, because the host name wasn't resolved in time.
+    pub fn caused(error: &hyper::Error) -> bool {
+        error.is_connect()
+            && error
+                .cause2()
+                .and_then(|cause| cause.downcast_ref::<io::Error>())
+                .and_then(|io_error| io_error.get_ref())
+                .map_or(false, |inner| inner.is::<ResolveTimeout>())
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolver used by the connector. Keeps successful answers for `ttl`, when it is set.
+/// Connector gets only the addresses allowed by `family`, resolved within `timeout`, when it is set.
+#[derive(Clone, Debug)]
+pub(crate) struct CachingResolver {
+    resolver: Arc<RwLock<Arc<dyn Resolver>>>,
+    ttl: Option<Duration>,
+    cache: Arc<Mutex<FnvHashMap<String, CacheEntry>>>,
+    family: AddressFamily,
+    timeout: Option<Duration>,
+}
+
+impl CachingResolver {
+    pub fn new(resolver: Arc<dyn Resolver>, ttl: Option<Duration>) -> Self {
+        CachingResolver {
+            resolver: Arc::new(RwLock::new(resolver)),
+            ttl,
+            cache: Default::default(),
+            family: AddressFamily::Any,
+            timeout: None,
+        }
+    }
+
+    /// Resolver sharing the cache with this one, which filters addresses by `family`.
+    pub fn with_family(&self, family: AddressFamily) -> Self {
+        CachingResolver {
+            family,
+            ..self.clone()
+        }
+    }
+
+    /// Resolver sharing the cache with this one, which fails lookups not completed within `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        CachingResolver {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the underlying resolver. Lookups, which already started, use the old one.
+    pub fn set_resolver(&self, resolver: Arc<dyn Resolver>) {
+        *self.resolver.write().unwrap() = resolver;
+    }
+
+    pub fn lookup(&self, host: &str) -> ResolveFuture {
+        let resolver = Arc::clone(&self.resolver.read().unwrap());
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return resolver.resolve(host),
+        };
+
+        if let Some(entry) = self.cache.lock().unwrap().get(host) {
+            if entry.expires > Instant::now() {
+                return Box::new(future::ok(entry.addresses.clone()));
+            }
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let host = host.to_owned();
+        Box::new(resolver.resolve(&host).map(move |addresses| {
+            cache.lock().unwrap().insert(
+                host,
+                CacheEntry {
+                    addresses: addresses.clone(),
+                    expires: Instant::now() + ttl,
+                },
+            );
+            addresses
+        }))
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl Resolve for CachingResolver {
+    type Addrs = vec::IntoIter<IpAddr>;
+    type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let family = self.family;
+
+        let lookup = self
+            .lookup(name.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, ResolveFailure(e)));
+        let lookup: ResolveFuture = match self.timeout {
+            Some(timeout) => Box::new(lookup.timeout(timeout).map_err(|e| {
+                e.into_inner()
+                    .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, ResolveTimeout))
+            })),
+            None => Box::new(lookup),
+        };
+
+        Box::new(lookup.and_then(move |addresses| {
+            let addresses: Vec<_> = addresses
+                .into_iter()
+                .filter(|address| family.allows(address))
+                .collect();
+
+            if addresses.is_empty() {
+                Err(io::Error::new(io::ErrorKind::Other, NoAllowedAddress))
+            } else {
+                Ok(addresses.into_iter())
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Resolves every host into the loopback address, counting lookups.
+    #[derive(Debug, Default)]
+    pub struct StubResolver {
+        pub lookups: AtomicUsize,
+    }
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, _host: &str) -> ResolveFuture {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::ok(vec!["127.0.0.1".parse().unwrap()]))
+        }
+    }
+
+    #[test]
+    fn test_cache_reused_within_ttl() {
+        let stub = Arc::new(StubResolver::default());
+        let resolver = CachingResolver::new(stub.clone(), Some(Duration::from_millis(200)));
+
+        for _ in 0..3 {
+            assert_eq!(
+                resolver.lookup("example.com").wait().unwrap(),
+                vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+            );
+        }
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+
+        resolver.lookup("example.org").wait().unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 2);
+
+        std::thread::sleep(Duration::from_millis(250));
+        resolver.lookup("example.com").wait().unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 3);
+
+        resolver.flush();
+        resolver.lookup("example.com").wait().unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_no_cache_without_ttl() {
+        let stub = Arc::new(StubResolver::default());
+        let resolver = CachingResolver::new(stub.clone(), None);
+
+        resolver.lookup("example.com").wait().unwrap();
+        resolver.lookup("example.com").wait().unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_address_family() {
+        let stub = Arc::new(StubResolver::default());
+        let resolver = CachingResolver::new(stub, None);
+        let resolve = |family| {
+            Resolve::resolve(
+                &resolver.with_family(family),
+                "example.com".parse().unwrap(),
+            )
+            .wait()
+            .map(Iterator::collect::<Vec<_>>)
+        };
+
+        assert_eq!(
+            resolve(AddressFamily::V4Only).unwrap(),
+            vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        assert!(resolve(AddressFamily::V6Only)
+            .unwrap_err()
+            .get_ref()
+            .unwrap()
+            .is::<NoAllowedAddress>());
+    }
+
+    #[test]
+    fn test_set_resolver() {
+        let first = Arc::new(StubResolver::default());
+        let second = Arc::new(StubResolver::default());
+        let resolver = CachingResolver::new(first.clone(), None);
+
+        resolver.lookup("example.com").wait().unwrap();
+        resolver.set_resolver(second.clone());
+        resolver.lookup("example.com").wait().unwrap();
+
+        assert_eq!(first.lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(second.lookups.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,137 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+
+use super::RequestId;
+
+/// Requests, which are being executed by the working thread, shared with the queue.
+#[derive(Clone, Default)]
+pub(crate) struct ExecutingRequests {
+    inner: Arc<Mutex<Registry>>,
+}
+
+/// Requests of the bulk share the ID, so entries are keyed separately.
+#[derive(Default)]
+struct Registry {
+    entries: FnvHashMap<u64, (RequestId, url::Url, Instant)>,
+    next_key: u64,
+}
+
+/// Executing request. Request is removed from the registry when guard is dropped.
+pub(crate) struct ExecutingGuard {
+    key: u64,
+    requests: ExecutingRequests,
+}
+
+impl Drop for ExecutingGuard {
+    fn drop(&mut self) {
+        self.requests
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .remove(&self.key);
+    }
+}
+
+impl ExecutingRequests {
+    pub fn start(&self, id: RequestId, uri: &url::Url) -> ExecutingGuard {
+        let mut inner = self.inner.lock().unwrap();
+        let key = inner.next_key;
+        inner.next_key += 1;
+        inner.entries.insert(key, (id, uri.clone(), Instant::now()));
+
+        ExecutingGuard {
+            key,
+            requests: self.clone(),
+        }
+    }
+
+    /// Requests ordered by ID and start, with the time elapsed since they started.
+    pub fn snapshot(&self) -> Vec<(RequestId, hyper::Uri, Duration)> {
+        let mut requests: Vec<_> = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter_map(|(key, (id, uri, started))| {
+                Some((*id, *key, uri.as_str().parse().ok()?, started.elapsed()))
+            })
+            .collect();
+        requests.sort_by_key(|(id, key, ..)| (*id, *key));
+        requests
+            .into_iter()
+            .map(|(id, _, uri, elapsed)| (id, uri, elapsed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_executing_requests() {
+        let requests = ExecutingRequests::default();
+        let first = requests.start(2, &"http://localhost/a".parse().unwrap());
+        let second = requests.start(1, &"http://localhost/b".parse().unwrap());
+
+        let snapshot = requests.snapshot();
+        assert_eq!(
+            snapshot
+                .iter()
+                .map(|(id, uri, _)| (*id, uri.path()))
+                .collect::<Vec<_>>(),
+            vec![(1, "/b"), (2, "/a")]
+        );
+
+        // Requests of the bulk are listed separately.
+        let bulk = requests.start(1, &"http://localhost/c".parse().unwrap());
+        assert_eq!(
+            requests
+                .snapshot()
+                .iter()
+                .map(|(id, uri, _)| (*id, uri.path()))
+                .collect::<Vec<_>>(),
+            vec![(1, "/b"), (1, "/c"), (2, "/a")]
+        );
+
+        drop(bulk);
+        drop(second);
+        drop(first);
+        assert!(requests.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Requests with `Expect: 100-continue`.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::sync::oneshot;
+use futures::task::AtomicTask;
+use hyper::header::{self, HeaderMap};
+
+thread_local! {
+    /// Signal of the body, which was polled for the first time on this thread.
+    static AWAITING: RefCell<Option<Arc<ContinueSignal>>> = RefCell::new(None);
+}
+
+/// Opened, when the connection writing the request receives the interim `100 Continue`.
+/// `hyper` skips the interim responses, so they are observed by the stream of the connection.
+#[derive(Default)]
+pub(crate) struct ContinueSignal {
+    received: AtomicBool,
+    task: AtomicTask,
+}
+
+impl ContinueSignal {
+    pub fn open(&self) {
+        self.received.store(true, Ordering::SeqCst);
+        self.task.notify();
+    }
+}
+
+/// Takes the signal of the body polled last on this thread.
+/// `hyper` polls the body right after buffering the request head, and writes the head in the same poll
+/// of the connection, so the stream picks the signal up on its next write.
+pub(crate) fn take_awaiting() -> Option<Arc<ContinueSignal>> {
+    AWAITING.with(|awaiting| awaiting.borrow_mut().take())
+}
+
+/// Whether `headers` ask the server to confirm the request before its body is sent.
+pub(crate) fn expects_continue(headers: &HeaderMap) -> bool {
+    headers.get(header::EXPECT).map_or(false, |value| {
+        value.as_bytes().eq_ignore_ascii_case(b"100-continue")
+    })
+}
+
+/// Holds `body` back, until `100 Continue` is received, the returned gate is opened or `timeout` elapses.
+/// Body ends without any data, when the gate is dropped before that. `hyper` closes the connection then,
+/// as the declared `Content-Length` wasn't sent, while the response is still read to the end.
+pub(crate) fn gated(body: hyper::Body, timeout: Duration) -> (hyper::Body, ContinueGate) {
+    let (sender, receiver) = oneshot::channel();
+    let body = GatedBody {
+        body,
+        opened: Some(receiver),
+        signal: None,
+        timeout: tokio::timer::Delay::new(Instant::now() + timeout),
+    };
+
+    (hyper::Body::wrap_stream(body), ContinueGate(sender))
+}
+
+/// Releases the body held back by `gated`.
+pub(crate) struct ContinueGate(oneshot::Sender<()>);
+
+impl ContinueGate {
+    /// Releases the body, unless the server has rejected the request with the error status.
+    pub fn resolve(self, response: hyper::Response<hyper::Body>) -> hyper::Response<hyper::Body> {
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            debug!("Request was rejected with {}, its body isn't sent", status);
+        } else {
+            self.0.send(()).ok();
+        }
+
+        response
+    }
+}
+
+struct GatedBody {
+    body: hyper::Body,
+    opened: Option<oneshot::Receiver<()>>,
+    /// Set on the first poll.
+    signal: Option<Arc<ContinueSignal>>,
+    timeout: tokio::timer::Delay,
+}
+
+impl Stream for GatedBody {
+    type Item = hyper::Chunk;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, Self::Error> {
+        if let Some(opened) = &mut self.opened {
+            let signal = self.signal.get_or_insert_with(|| {
+                let signal = Arc::new(ContinueSignal::default());
+                AWAITING.with(|awaiting| *awaiting.borrow_mut() = Some(Arc::clone(&signal)));
+                signal
+            });
+            signal.task.register();
+
+            match opened.poll() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) if signal.received.load(Ordering::SeqCst) => {
+                    debug!("Received 100 Continue, sending the body");
+                }
+                Ok(Async::NotReady) => futures::try_ready!(self.timeout.poll()),
+                Err(oneshot::Canceled) => return Ok(Async::Ready(None)),
+            }
+            self.opened = None;
+        }
+
+        Ok(self.body.poll()?)
+    }
+}
@@ -0,0 +1,490 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Observation of the connections being closed.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::Buf;
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::expect::{self, ContinueSignal};
+
+thread_local! {
+    /// Set, when the connector starts connecting on this thread.
+    static CONNECTING: Cell<bool> = Cell::new(false);
+}
+
+/// Marks that the connector was asked for the new connection.
+/// `hyper` asks for it, while the request future is polled, so `ReuseObserved` can notice it.
+pub(crate) fn connecting() {
+    CONNECTING.with(|connecting| connecting.set(true));
+}
+
+/// Request future, which reports whether its connection was reused from the pool.
+pub(crate) struct ReuseObserved<F> {
+    inner: F,
+    connected: bool,
+}
+
+/// Errors of `inner` are paired with whether the request was sent over the pooled connection.
+/// Request counts as sent over the new connection, once the connector was asked for it,
+/// even though `hyper` may still hand over the pooled connection, which became idle first.
+pub(crate) fn observe_reuse<F: Future>(inner: F) -> ReuseObserved<F> {
+    ReuseObserved {
+        inner,
+        connected: false,
+    }
+}
+
+impl<F: Future> Future for ReuseObserved<F> {
+    type Item = F::Item;
+    type Error = (F::Error, bool);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let outer = CONNECTING.with(|connecting| connecting.replace(false));
+        let result = self.inner.poll();
+        self.connected |= CONNECTING.with(|connecting| connecting.replace(outer));
+
+        let reused = !self.connected;
+        result.map_err(|e| (e, reused))
+    }
+}
+
+/// Why the connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Response asked to close the connection with `Connection: close`.
+    ConnectionClose,
+    /// Server closed the connection.
+    ClosedByPeer,
+    /// Reading or writing failed.
+    Error,
+    /// Connection was dropped from the pool: it was idle for too long,
+    /// the pool was full, or the queue was stopped.
+    Evicted,
+}
+
+/// Called with the reason every time the connection of the queue is closed.
+/// Called on the working thread of the queue.
+#[derive(Clone)]
+pub struct ConnectionCloseHook(Arc<dyn Fn(CloseReason) + Send + Sync>);
+
+impl ConnectionCloseHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: 'static + Fn(CloseReason) + Send + Sync,
+    {
+        ConnectionCloseHook(Arc::new(hook))
+    }
+
+    pub fn call(&self, reason: CloseReason) {
+        (self.0)(reason)
+    }
+}
+
+impl fmt::Debug for ConnectionCloseHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ConnectionCloseHook")
+    }
+}
+
+/// Maximal length of the response head, which is inspected.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Whether HTTP/1 response head is the interim `100 Continue`.
+fn head_is_continue(head: &str) -> bool {
+    head.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        == Some("100")
+}
+
+/// Whether HTTP/1 response head asks to close the connection.
+/// Returns `None` for the interim `1xx` responses.
+fn head_requests_close(head: &str) -> Option<bool> {
+    let mut status_line = head.lines().next()?.split_whitespace();
+    let version = status_line.next()?;
+    if status_line.next()?.starts_with('1') {
+        return None;
+    }
+
+    let has_token = |token: &str| {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| {
+                let mut split = line.splitn(2, ':');
+                match (split.next(), split.next()) {
+                    (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("connection") => {
+                        Some(value)
+                    }
+                    _ => None,
+                }
+            })
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    };
+
+    if version == "HTTP/1.0" {
+        Some(!has_token("keep-alive"))
+    } else {
+        Some(has_token("close"))
+    }
+}
+
+/// Length of the body following the HTTP/1 response head, when it is declared by `Content-Length`.
+/// `None` for the chunked bodies and the responses, which never have a body.
+fn declared_body_length(head: &str) -> Option<u64> {
+    let status = head.lines().next()?.split_whitespace().nth(1)?;
+    if status.starts_with('1') || status == "204" || status == "304" {
+        return None;
+    }
+
+    let mut length = None;
+    for line in head.lines().skip(1) {
+        let mut split = line.splitn(2, ':');
+        let (name, value) = match (split.next(), split.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => continue,
+        };
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            return None;
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().ok();
+        }
+    }
+
+    length
+}
+
+/// Set, when the connection received bytes after the end of the response body declared by `Content-Length`.
+/// `hyper` doesn't reuse such connection, so the flag concerns its last response. Bytes are noticed
+/// only when they arrive with the end of the body, before `hyper` hands it over.
+#[derive(Clone, Default)]
+pub(crate) struct TrailingData(Arc<AtomicBool>);
+
+impl TrailingData {
+    pub fn received(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// When the first byte of the request, which response is received, was written to the connection.
+/// Only HTTP/1 requests are observed, as requests sent over HTTP/2 share the writes.
+#[derive(Clone, Default)]
+pub(crate) struct RequestWritten(Arc<Mutex<Option<Instant>>>);
+
+impl RequestWritten {
+    pub fn get(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Calls the hook, when connections of the inner connector are closed.
+#[derive(Clone)]
+pub(crate) struct TrackingConnector<C> {
+    pub inner: C,
+    pub hook: Option<ConnectionCloseHook>,
+    /// Attaches `TrailingData` to the responses.
+    pub detect_trailing_data: bool,
+}
+
+impl<C> Connect for TrackingConnector<C>
+where
+    C: Connect,
+    C::Future: 'static,
+{
+    type Transport = TrackedStream<C::Transport>;
+    type Error = C::Error;
+    type Future = Box<dyn Future<Item = (Self::Transport, Connected), Error = Self::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        connecting();
+        let hook = self.hook.clone();
+        let trailing_data = if self.detect_trailing_data {
+            Some(TrailingData::default())
+        } else {
+            None
+        };
+
+        Box::new(self.inner.connect(dst).map(move |(io, connected)| {
+            let request_written = RequestWritten::default();
+            let connected = connected.extra(request_written.clone());
+            let connected = match &trailing_data {
+                Some(trailing_data) => connected.extra(trailing_data.clone()),
+                None => connected,
+            };
+            let stream = TrackedStream {
+                inner: io,
+                hook,
+                head: None,
+                head_request: false,
+                body_remaining: None,
+                trailing_data,
+                request_written: Some(request_written),
+                continue_signal: None,
+                close_requested: false,
+                closed_by_peer: false,
+                failed: false,
+            };
+
+            (stream, connected)
+        }))
+    }
+}
+
+/// Stream, which reports its closing to the hook on drop.
+/// Heads of HTTP/1 responses are inspected for `Connection: close` and `100 Continue`,
+/// and for `Content-Length`, when the trailing data is detected. Start of every request is recorded.
+pub(crate) struct TrackedStream<T> {
+    inner: T,
+    hook: Option<ConnectionCloseHook>,
+    /// Received part of the response head, when response is awaited.
+    head: Option<Vec<u8>>,
+    /// Whether the awaited response is to the `HEAD` request, which has no body.
+    head_request: bool,
+    /// Length of the rest of the response body, when it is tracked.
+    body_remaining: Option<u64>,
+    trailing_data: Option<TrailingData>,
+    /// `None`, once the connection turned out not to be HTTP/1.
+    request_written: Option<RequestWritten>,
+    /// Signal of the request written last, which waits for `100 Continue`.
+    continue_signal: Option<Arc<ContinueSignal>>,
+    close_requested: bool,
+    closed_by_peer: bool,
+    failed: bool,
+}
+
+impl<T> TrackedStream<T> {
+    fn track<R>(&mut self, result: io::Result<R>, eof: impl Fn(&R) -> bool) -> io::Result<R> {
+        match &result {
+            Ok(value) if eof(value) => self.closed_by_peer = true,
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => self.failed = true,
+            _ => {}
+        }
+
+        result
+    }
+
+    fn writing(&mut self) {
+        if let Some(signal) = expect::take_awaiting() {
+            self.continue_signal = Some(signal);
+        }
+    }
+
+    fn written(&mut self, n: usize, head_request: bool) {
+        if n > 0 && self.head.is_none() {
+            self.head = Some(vec![]);
+            self.head_request = head_request;
+            self.body_remaining = None;
+            if let Some(request_written) = &self.request_written {
+                *request_written.0.lock().unwrap() = Some(Instant::now());
+            }
+        }
+    }
+
+    fn received_body(&mut self, data: &[u8]) {
+        if let (Some(remaining), Some(trailing_data)) = (self.body_remaining, &self.trailing_data) {
+            let received = data.len() as u64;
+            if received > remaining {
+                trailing_data.0.store(true, Ordering::SeqCst);
+                self.body_remaining = None;
+            } else {
+                self.body_remaining = Some(remaining - received);
+            }
+        }
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        let head = match &mut self.head {
+            Some(head) => head,
+            None => return self.received_body(data),
+        };
+
+        head.extend_from_slice(data);
+        let http1 = head.starts_with(&b"HTTP/1."[..head.len().min(7)]);
+        if !http1 {
+            if let Some(request_written) = self.request_written.take() {
+                *request_written.0.lock().unwrap() = None;
+            }
+        }
+        if !http1 || head.len() > MAX_HEAD_LENGTH {
+            self.head = None;
+            self.continue_signal = None;
+            return;
+        }
+
+        while let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let text = String::from_utf8_lossy(&head[..end]).into_owned();
+            head.drain(..end + 4);
+
+            if let Some(close) = head_requests_close(&text) {
+                let body = std::mem::take(head);
+                self.close_requested |= close;
+                self.head = None;
+                self.continue_signal = None;
+                if self.trailing_data.is_some() && !self.head_request {
+                    self.body_remaining = declared_body_length(&text);
+                    self.received_body(&body);
+                }
+                return;
+            }
+            if head_is_continue(&text) {
+                if let Some(signal) = self.continue_signal.take() {
+                    signal.open();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for TrackedStream<T> {
+    fn drop(&mut self) {
+        if let Some(hook) = &self.hook {
+            let reason = if self.failed {
+                CloseReason::Error
+            } else if self.close_requested {
+                CloseReason::ConnectionClose
+            } else if self.closed_by_peer {
+                CloseReason::ClosedByPeer
+            } else {
+                CloseReason::Evicted
+            };
+
+            hook.call(reason);
+        }
+    }
+}
+
+impl<T: Read> Read for TrackedStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let eof = !buf.is_empty();
+        let result = self.inner.read(buf);
+        if let Ok(n) = result {
+            self.received(&buf[..n]);
+        }
+        self.track(result, |n| eof && *n == 0)
+    }
+}
+
+impl<T: Write> Write for TrackedStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writing();
+        let result = self.inner.write(buf);
+        if let Ok(n) = result {
+            self.written(n, buf.starts_with(b"HEAD "));
+        }
+        self.track(result, |_| false)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.track(result, |_| false)
+    }
+}
+
+// Reads go through `read`, so the received bytes can be inspected.
+impl<T: AsyncRead> AsyncRead for TrackedStream<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for TrackedStream<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let result = self.inner.shutdown();
+        self.track(result, |_| false)
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        self.writing();
+        let head_request = buf.bytes().starts_with(b"HEAD ");
+        let result = self.inner.write_buf(buf);
+        if let Ok(Async::Ready(n)) = result {
+            self.written(n, head_request);
+        }
+        self.track(result, |_| false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_requests_close() {
+        assert_eq!(
+            head_requests_close("HTTP/1.1 200 OK\r\nContent-Length: 0"),
+            Some(false)
+        );
+        assert_eq!(
+            head_requests_close("HTTP/1.1 200 OK\r\nConnection: Upgrade, Close"),
+            Some(true)
+        );
+        assert_eq!(head_requests_close("HTTP/1.0 200 OK"), Some(true));
+        assert_eq!(
+            head_requests_close("HTTP/1.0 200 OK\r\nconnection: keep-alive"),
+            Some(false)
+        );
+        assert_eq!(head_requests_close("HTTP/1.1 100 Continue"), None);
+    }
+
+    #[test]
+    fn test_declared_body_length() {
+        assert_eq!(
+            declared_body_length("HTTP/1.1 200 OK\r\nContent-Length: 2"),
+            Some(2)
+        );
+        assert_eq!(
+            declared_body_length("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked"),
+            None
+        );
+        assert_eq!(
+            declared_body_length("HTTP/1.1 304 Not Modified\r\nContent-Length: 2"),
+            None
+        );
+        assert_eq!(declared_body_length("HTTP/1.1 200 OK"), None);
+    }
+
+    #[test]
+    fn test_head_is_continue() {
+        assert!(head_is_continue("HTTP/1.1 100 Continue"));
+        assert!(!head_is_continue("HTTP/1.1 101 Switching Protocols"));
+        assert!(!head_is_continue("HTTP/1.1 200 OK"));
+    }
+}
@@ -0,0 +1,8788 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::thread;
+
+use futures::future;
+use futures::prelude::*;
+use futures::stream;
+use futures::sync::oneshot;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+use tokio::prelude::FutureExt;
+
+use bytes::Bytes;
+use hyper::header;
+
+mod background;
+mod body;
+mod cache;
+mod coalesce;
+mod connector;
+mod curl;
+mod debug_log;
+mod dns;
+mod executing;
+mod expect;
+mod lifecycle;
+mod multipart;
+mod ordered;
+mod progress;
+mod rate_limit;
+mod retry;
+mod schema;
+mod slots;
+mod socks;
+mod sse;
+mod stream_body;
+mod tcp_info;
+mod text;
+mod transport;
+#[cfg(unix)]
+mod uds;
+mod websocket;
+
+use self::background::ForegroundGate;
+pub use self::body::{
+    decode_chunked, BodyStrategy, BodyStrategySelector, Compression, SpilledBody, TransferDecoder,
+};
+use self::body::{BodyAccumulator, GzipBody, Trailers, TrailersBody};
+use self::cache::ResponseCache;
+use self::coalesce::{CoalesceKey, InFlight, Waiter};
+use self::connector::OverrideConnector;
+pub use self::connector::{ConnectFuture, Connection, Connector};
+pub use self::dns::{AddressFamily, ResolveFuture, Resolver, SystemResolver};
+use self::dns::{CachingResolver, NoAllowedAddress, ResolveFailure, ResolveTimeout};
+use self::executing::ExecutingRequests;
+pub use self::lifecycle::{CloseReason, ConnectionCloseHook};
+use self::lifecycle::{RequestWritten, TrailingData};
+pub use self::multipart::{MultipartParser, Part, PartHandler};
+pub use self::progress::{DownloadProgress, DownloadProgressHandler};
+use self::progress::{Progress, ProgressCallBack};
+pub use self::rate_limit::RateLimit;
+use self::rate_limit::RateLimiter;
+pub use self::retry::{RetryPolicy, RetryPolicyBuilder, RetryableError};
+pub use self::schema::JsonSchema;
+use self::slots::{SlotGuard, Slots};
+pub use self::socks::{ProxyOverride, Socks5Proxy, SocksError};
+use self::sse::EventStreamParser;
+pub use self::sse::ServerSentEvent;
+use self::stream_body::LengthMismatch;
+pub use self::stream_body::StreamBody;
+use self::tcp_info::SocketHandle;
+pub use self::tcp_info::TcpStats;
+use self::transport::{check_framing, RequestTarget, MAX_REDIRECTS};
+pub use self::websocket::{WebSocket, WebSocketEvent, WebSocketMessage};
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use fnv::FnvHashMap;
+
+#[cfg(test)]
+mod test_server;
+
+#[derive(Clone, Debug)]
+pub enum RequestType {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl RequestType {
+    fn method(&self) -> hyper::Method {
+        match self {
+            RequestType::Get => hyper::Method::GET,
+            RequestType::Post => hyper::Method::POST,
+            RequestType::Put => hyper::Method::PUT,
+            RequestType::Delete => hyper::Method::DELETE,
+        }
+    }
+}
+
+pub type RequestId = u64;
+
+type CancellationSenders = Arc<Mutex<FnvHashMap<RequestId, oneshot::Sender<()>>>>;
+
+/// Cancels request when dropped.
+#[derive(Debug)]
+pub struct RequestCancellation {
+    id: RequestId,
+    cancellation_senders: CancellationSenders,
+}
+
+impl RequestCancellation {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+}
+
+impl Drop for RequestCancellation {
+    fn drop(&mut self) {
+        self.cancellation_senders.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Decides whether the received response is successful, e.g. by inspecting its body.
+#[derive(Clone)]
+pub struct SuccessPredicate(Arc<dyn Fn(&Response) -> bool + Send + Sync>);
+
+impl SuccessPredicate {
+    pub fn new<F>(predicate: F) -> Self
+    where
+        F: 'static + Fn(&Response) -> bool + Send + Sync,
+    {
+        SuccessPredicate(Arc::new(predicate))
+    }
+
+    pub fn is_success(&self, response: &Response) -> bool {
+        (self.0)(response)
+    }
+}
+
+impl std::fmt::Debug for SuccessPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("SuccessPredicate")
+    }
+}
+
+/// Observes the request of every response, e.g. for the centralized logging.
+/// Called on the thread executing the queue, right before `ResponseHook`.
+#[derive(Clone)]
+pub struct RequestHook(Arc<dyn Fn(&Request) + Send + Sync>);
+
+impl RequestHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: 'static + Fn(&Request) + Send + Sync,
+    {
+        RequestHook(Arc::new(hook))
+    }
+
+    pub fn call(&self, request: &Request) {
+        (self.0)(request)
+    }
+}
+
+impl std::fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("RequestHook")
+    }
+}
+
+/// Observes every response, before it is passed to the callback.
+/// Called on the thread executing the queue.
+#[derive(Clone)]
+pub struct ResponseHook(Arc<dyn Fn(&Response) + Send + Sync>);
+
+impl ResponseHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: 'static + Fn(&Response) + Send + Sync,
+    {
+        ResponseHook(Arc::new(hook))
+    }
+
+    pub fn call(&self, response: &Response) {
+        (self.0)(response)
+    }
+}
+
+impl std::fmt::Debug for ResponseHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ResponseHook")
+    }
+}
+
+type CancelFuture = dyn Future<Item = (), Error = ()> + Send;
+
+/// Cancels the request, once the future resolves. Failure of the future is ignored.
+#[derive(Clone)]
+pub struct CancelSignal(future::Shared<Box<CancelFuture>>);
+
+impl CancelSignal {
+    pub fn new<F>(future: F) -> Self
+    where
+        F: 'static + Future<Item = (), Error = ()> + Send,
+    {
+        CancelSignal(future::Future::shared(Box::new(future) as Box<CancelFuture>))
+    }
+
+    /// Resolves, when the request should be cancelled. Never resolves, if the future failed.
+    fn fired(&self) -> impl Future<Item = (), Error = ()> {
+        self.0.clone().then(|result| match result {
+            Ok(_) => future::Either::A(future::ok(())),
+            Err(_) => future::Either::B(future::empty()),
+        })
+    }
+}
+
+impl std::fmt::Debug for CancelSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("CancelSignal")
+    }
+}
+
+#[derive(Builder, Clone, Debug, Default)]
+pub struct RequestOptions {
+    /// `Host` set here is sent instead of the one derived from the URL, e.g. to reach the virtual host
+    /// by its IP address. It is dropped on redirects to the other hosts.
+    #[builder(default)]
+    pub headers: hyper::header::HeaderMap,
+
+    /// Timeout of the single attempt.
+    #[builder(default)]
+    pub timeout: Option<Duration>,
+
+    /// Timeout of the host name resolution, failing the attempt with `ErrorKind::DnsTimeout`.
+    /// Counts towards `timeout` as well.
+    #[builder(default)]
+    pub dns_timeout: Option<Duration>,
+
+    /// Attempts time out at this point, when it comes before `timeout` expires.
+    /// Attempts started past the deadline fail with `ErrorKind::RequestTimeout` right away.
+    #[builder(default)]
+    pub deadline: Option<Instant>,
+
+    #[builder(default)]
+    pub body_strategy: BodyStrategy,
+
+    /// Overrides `body_strategy`, based on the status and headers of the response.
+    #[builder(default)]
+    pub body_strategy_selector: Option<BodyStrategySelector>,
+
+    /// How long request may wait for a free slot, when `QueueOptions::max_concurrent_requests` is reached.
+    #[builder(default)]
+    pub acquire_timeout: Option<Duration>,
+
+    /// Sends request over the Unix domain socket instead of TCP. URL still provides path, query and `Host`.
+    #[builder(default)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Schema, which JSON responses are validated against. Forces `BodyStrategy::Buffer`.
+    #[builder(default)]
+    pub json_schema: Option<JsonSchema>,
+
+    /// Accept responses repeating the same `Content-Length`. Such responses are rejected by default.
+    #[builder(default)]
+    pub allow_identical_content_lengths: bool,
+
+    /// Background requests run only while no foreground requests are in flight,
+    /// and are paused, not cancelled, when foreground requests arrive. Timeouts keep running while paused.
+    /// They occupy slots under `QueueOptions::max_concurrent_requests` like other requests,
+    /// but always wait for them with the lowest priority, so `priority` doesn't apply to them.
+    #[builder(default)]
+    pub background: bool,
+
+    /// Requests waiting for the slot under `QueueOptions::max_concurrent_requests` are started
+    /// in the order of decreasing priority, and in the order they were sent within the same priority.
+    #[builder(default)]
+    pub priority: u8,
+
+    /// Responses rejected by the predicate are reported as `ErrorKind::RequestFailed`.
+    #[builder(default)]
+    pub success_predicate: Option<SuccessPredicate>,
+
+    /// Connector used for this request instead of the transport of the queue.
+    /// Takes precedence over `unix_socket`.
+    #[builder(default)]
+    pub connector_override: Option<Arc<dyn Connector>>,
+
+    /// Reports progress of the response body download. Events precede the response callback.
+    #[builder(default)]
+    pub download_progress: Option<DownloadProgressHandler>,
+
+    /// Set by `Queue::send_request_with_progress`.
+    #[builder(setter(skip))]
+    pub(crate) upload_progress: Option<Progress>,
+
+    /// Addresses of the resolved host, which may be used for connecting.
+    #[builder(default)]
+    pub address_family: AddressFamily,
+
+    /// Off by default. Interop workaround for servers with broken transfer coding.
+    #[builder(default)]
+    pub transfer_decoder: Option<TransferDecoder>,
+
+    #[builder(default)]
+    pub retry_policy: RetryPolicy,
+
+    /// Cancels the request, once resolved, same as dropping the `RequestCancellation`.
+    #[builder(default)]
+    pub cancel_on: Option<CancelSignal>,
+
+    /// In-memory body longer than this is delivered in fragments of this size,
+    /// calling the callback once per fragment. See `Response::fragment`.
+    #[builder(default)]
+    pub fragment_size: Option<usize>,
+
+    /// Follows `Refresh` header with zero delay, as if it was `303 See Other` redirect.
+    /// Off by default. Interop workaround for services using it instead of redirects.
+    #[builder(default)]
+    pub follow_refresh: bool,
+
+    /// Logs this request and its response, including headers and the body preview, at debug level
+    /// under the `grip::debug_log` target, regardless of the maximal level set globally.
+    /// Values of the sensitive headers are redacted.
+    #[builder(default)]
+    pub debug_log: bool,
+
+    /// Compresses in-memory `body` and sets `Content-Encoding`, unless the header is already set.
+    /// Bodies up to 1 KiB are sent as is.
+    #[builder(default)]
+    pub compress_request: Option<Compression>,
+
+    /// Sends `Expect: 100-continue` and holds the body back, so that the server may reject the request
+    /// before the body is transmitted. Body is sent after the interim `100 Continue` response,
+    /// the final successful response or `QueueOptions::continue_timeout`, whichever comes first.
+    #[builder(default)]
+    pub expect_continue: bool,
+
+    /// Overrides `QueueOptions::socks5_proxy`, e.g. to reach the internal service directly.
+    /// Connections of the other proxy are pooled separately. Pools of the 16 most recently used
+    /// overrides and DNS timeouts are kept, idle connections of the rest are closed.
+    #[builder(default)]
+    pub proxy: Option<ProxyOverride>,
+
+    /// Reports responses with the status other than 2xx as `ErrorKind::HttpStatus`.
+    #[builder(default)]
+    pub error_for_status: bool,
+}
+
+#[derive(Builder, Clone, Constructor, Debug)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct Request {
+    pub http_type: RequestType,
+    #[builder(private, setter(name = "replace_uri"))]
+    pub uri: url::Url,
+
+    /// Shared with the `Response` and every attempt of the request without copying.
+    #[builder(default, setter(into))]
+    pub body: Bytes,
+
+    /// Sent instead of `body`, when set.
+    #[builder(default)]
+    pub body_stream: Option<StreamBody>,
+
+    #[builder(default)]
+    pub options: RequestOptions,
+}
+
+/// Scheme of the placeholder URI, which keeps the query pairs set before `RequestBuilder::uri`.
+const PENDING_QUERY_SCHEME: &str = "pending-query";
+
+impl RequestBuilder {
+    /// Sets the URI, query pairs set by `query` before are appended to its query.
+    pub fn uri(&mut self, mut uri: url::Url) -> &mut Self {
+        if let Some(pending) = self
+            .uri
+            .take()
+            .filter(|pending| pending.scheme() == PENDING_QUERY_SCHEME)
+        {
+            uri.query_pairs_mut().extend_pairs(pending.query_pairs());
+        }
+        self.replace_uri(uri)
+    }
+
+    /// Appends `pairs` to the query of `uri`, existing query and fragment are preserved.
+    /// Pairs are `application/x-www-form-urlencoded`, i.e. space is encoded as `+`.
+    pub fn query(&mut self, pairs: &[(&str, &str)]) -> &mut Self {
+        self.uri
+            .get_or_insert_with(|| url::Url::parse(&format!("{}:", PENDING_QUERY_SCHEME)).unwrap())
+            .query_pairs_mut()
+            .extend_pairs(pairs);
+        self
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        match &self.uri {
+            Some(uri) if uri.scheme() == PENDING_QUERY_SCHEME => {
+                Err("`uri` must be initialized".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Builder, Debug)]
+#[builder(pattern = "owned")]
+pub struct Response {
+    pub base_request: Request,
+    /// In-memory body. Empty when body was spilled to the file.
+    /// Clones and slices share the same buffer.
+    pub body: Bytes,
+    pub spilled_body: Option<SpilledBody>,
+    pub status_code: hyper::StatusCode,
+    /// HTTP version negotiated for the final response, e.g. `HTTP/2.0` chosen by ALPN.
+    pub version: hyper::Version,
+    /// Headers of the final response. `Content-Encoding` and `Content-Length` are removed for `gzip` bodies.
+    pub headers: hyper::header::HeaderMap,
+    /// Human-readable reason of the status code, e.g. "Not Found".
+    pub reason: Option<String>,
+    pub timings: ResponseTimings,
+    /// Time from the start of the request until the whole body was received.
+    pub elapsed: Duration,
+    /// Number of attempts made, including the successful one.
+    pub attempts: u32,
+    /// Delay requested by `Retry-After` of the `429 Too Many Requests` or `503 Service Unavailable` response.
+    pub retry_after: Option<Duration>,
+    /// Statistics of the TCP connection, read after the body was received. Available on Linux only.
+    pub tcp_stats: Option<TcpStats>,
+    /// Set, when the body is delivered in fragments. See `RequestOptions::fragment_size`.
+    pub fragment: Option<BodyFragment>,
+    /// Trailers received after the body. Only HTTP/2 responses carry them,
+    /// as `hyper` discards trailers of the HTTP/1.1 chunked bodies.
+    pub trailers: Option<hyper::header::HeaderMap>,
+}
+
+/// Position of the body fragment among the others of the same response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyFragment {
+    /// Starts from zero. Fragments are delivered in order.
+    pub index: usize,
+    pub is_last: bool,
+}
+
+/// Points in time observed during the request execution.
+/// Reads are captured at the level of the response stream, the request write is observed on the connection.
+#[derive(Clone, Debug)]
+pub struct ResponseTimings {
+    /// Request execution started.
+    pub started: Instant,
+    /// First byte of the request was written to the connection. `None` for HTTP/2,
+    /// Unix sockets and the connections of `RequestOptions::connector`.
+    pub request_written: Option<Instant>,
+    /// Status line and headers were read.
+    pub head_received: Instant,
+    /// First chunk of the body was read. `None` for the empty body.
+    pub first_body_byte: Option<Instant>,
+    /// Last chunk of the body was read. `None` for the empty body.
+    pub last_body_byte: Option<Instant>,
+}
+
+impl Response {
+    /// Splits the in-memory body into the responses carrying fragments of `fragment_size`.
+    /// Response isn't split, when its body fits into the single fragment.
+    fn into_fragments(self, fragment_size: usize) -> Vec<Response> {
+        if self.body.len() <= fragment_size {
+            return vec![self];
+        }
+
+        let count = (self.body.len() + fragment_size - 1) / fragment_size;
+        (0..count)
+            .map(|index| Response {
+                base_request: self.base_request.clone(),
+                body: self.body.slice(
+                    index * fragment_size,
+                    std::cmp::min((index + 1) * fragment_size, self.body.len()),
+                ),
+                spilled_body: None,
+                status_code: self.status_code,
+                version: self.version,
+                headers: self.headers.clone(),
+                reason: self.reason.clone(),
+                timings: self.timings.clone(),
+                elapsed: self.elapsed,
+                attempts: self.attempts,
+                retry_after: self.retry_after,
+                tcp_stats: self.tcp_stats,
+                fragment: Some(BodyFragment {
+                    index,
+                    is_last: index + 1 == count,
+                }),
+                trailers: self.trailers.clone(),
+            })
+            .collect()
+    }
+
+    /// Converts the in-memory body into `Bytes` without copying it.
+    /// Slices of the result share the same buffer.
+    pub fn into_body_bytes(self) -> Bytes {
+        self.body
+    }
+
+    /// Copy of the in-memory body, for the callers expecting `Vec<u8>`.
+    pub fn body_vec(&self) -> Vec<u8> {
+        self.body.to_vec()
+    }
+
+    /// Returns reader over the body, independently of where it is stored.
+    pub fn body_reader(&self) -> Result<Box<dyn Read + '_>> {
+        match &self.spilled_body {
+            Some(spilled) => Ok(Box::new(spilled.reader()?)),
+            None => Ok(Box::new(&self.body[..])),
+        }
+    }
+
+    /// Status is 2xx.
+    pub fn is_success(&self) -> bool {
+        self.status_code.is_success()
+    }
+
+    /// Status is 3xx. Redirects are followed, so only the ones without the usable `Location` are seen.
+    pub fn is_redirect(&self) -> bool {
+        self.status_code.is_redirection()
+    }
+
+    /// Status is 4xx.
+    pub fn is_client_error(&self) -> bool {
+        self.status_code.is_client_error()
+    }
+
+    /// Status is 5xx.
+    pub fn is_server_error(&self) -> bool {
+        self.status_code.is_server_error()
+    }
+
+    /// One line for the logs, e.g. `GET https://host/path -> 200 (1234 bytes, 87ms)`.
+    pub fn summary(&self) -> String {
+        let length = match &self.spilled_body {
+            Some(spilled) => spilled.len(),
+            None => self.body.len(),
+        };
+
+        format!(
+            "{} {} -> {} ({} bytes, {}ms)",
+            self.base_request.http_type.method(),
+            self.base_request.uri,
+            self.status_code.as_u16(),
+            length,
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+// TODO: Replace with trait alias, when they became stable
+// https://github.com/rust-lang/rust/issues/41517
+type ResponseCallBack = Fn(Result<Response>) + Sync + Send;
+
+struct RequestCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    request: Request,
+    callback: Box<ResponseCallBack>,
+}
+
+type RawResponseCallBack = dyn Fn(Result<hyper::Response<hyper::Body>>) + Sync + Send;
+
+/// Request, which callback receives the response of `hyper` as is.
+struct RawCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    request: Request,
+    callback: Box<RawResponseCallBack>,
+}
+
+type WebSocketCallBack = dyn Fn(Result<WebSocketEvent>) + Sync + Send;
+
+type SseCallBack = dyn Fn(Result<Option<ServerSentEvent>>) + Sync + Send;
+
+/// Request of the `text/event-stream`, which events are delivered as they arrive.
+struct SseCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    request: Request,
+    reconnect: Option<Duration>,
+    callback: Arc<SseCallBack>,
+}
+
+/// Handshake of the WebSocket connection, which then exchanges messages until it is closed.
+struct WebSocketCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    request: Request,
+    outgoing: futures::sync::mpsc::UnboundedReceiver<WebSocketMessage>,
+    callback: Arc<WebSocketCallBack>,
+}
+
+/// Request, which response resolves the future instead of being passed to the callback.
+struct FutureCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    request: Request,
+    sender: oneshot::Sender<Result<Response>>,
+}
+
+type BulkResponseCallBack = dyn FnOnce(Vec<Result<Response>>) + Send;
+
+struct BulkCommand {
+    id: RequestId,
+    cancellation_signal: oneshot::Receiver<()>,
+    requests: Vec<Request>,
+    concurrency: usize,
+    callback: Box<BulkResponseCallBack>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum InputCommand {
+    Request(RequestCommand),
+    Bulk(BulkCommand),
+    Batch(Vec<RequestCommand>),
+    Future(FutureCommand),
+    Raw(RawCommand),
+    WebSocket(WebSocketCommand),
+    Sse(SseCommand),
+    Pause,
+    Resume,
+    MetricsReporter {
+        interval: Duration,
+        reporter: Box<MetricsReporter>,
+    },
+    Quit,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum OutputCommand {
+    Response {
+        response: Response,
+        callback: Box<ResponseCallBack>,
+    },
+    Error {
+        error: Error,
+        callback: Box<ResponseCallBack>,
+    },
+    Bulk {
+        results: Vec<Result<Response>>,
+        callback: Box<BulkResponseCallBack>,
+    },
+    Raw {
+        result: Result<hyper::Response<hyper::Body>>,
+        callback: Box<RawResponseCallBack>,
+    },
+    Progress {
+        transferred: u64,
+        total: u64,
+        callback: Arc<ProgressCallBack>,
+    },
+    WebSocket {
+        event: Result<WebSocketEvent>,
+        callback: Arc<WebSocketCallBack>,
+    },
+    ServerSentEvent {
+        event: Result<Option<ServerSentEvent>>,
+        callback: Arc<SseCallBack>,
+    },
+    DownloadProgress {
+        progress: DownloadProgress,
+        handler: DownloadProgressHandler,
+    },
+}
+
+struct ReceivedResponse {
+    status_code: hyper::StatusCode,
+    version: hyper::Version,
+    headers: hyper::header::HeaderMap,
+    body: Bytes,
+    spilled_body: Option<SpilledBody>,
+    timings: ResponseTimings,
+    elapsed: Duration,
+    attempts: u32,
+    retry_after: Option<Duration>,
+    tcp_stats: Option<TcpStats>,
+    trailers: Option<hyper::header::HeaderMap>,
+}
+
+impl ReceivedResponse {
+    fn duplicate(&self) -> Result<ReceivedResponse> {
+        Ok(ReceivedResponse {
+            status_code: self.status_code,
+            version: self.version,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            spilled_body: self
+                .spilled_body
+                .as_ref()
+                .map(SpilledBody::duplicate)
+                .transpose()?,
+            timings: self.timings.clone(),
+            elapsed: self.elapsed,
+            attempts: self.attempts,
+            retry_after: self.retry_after,
+            tcp_stats: self.tcp_stats,
+            trailers: self.trailers.clone(),
+        })
+    }
+}
+
+enum State {
+    Successful(Box<ReceivedResponse>),
+    Error(Error),
+    Canceled,
+    Timeout,
+}
+
+impl State {
+    /// Copy of the outcome for the coalesced request.
+    fn duplicate(&self) -> State {
+        match self {
+            State::Successful(received) => match received.duplicate() {
+                Ok(received) => State::Successful(Box::new(received)),
+                Err(e) => State::Error(e),
+            },
+            State::Error(e) => State::Error(coalesce::duplicate_error(e)),
+            State::Canceled => State::Canceled,
+            State::Timeout => State::Timeout,
+        }
+    }
+
+    fn retryable_error(&self) -> Option<RetryableError> {
+        match self {
+            State::Error(error) => RetryableError::classify(error),
+            State::Timeout => Some(RetryableError::RequestTimeout),
+            _ => None,
+        }
+    }
+
+    /// Delay before the next attempt, when the `policy` allows retrying.
+    fn retry_delay(&self, policy: &RetryPolicy, retries: u32) -> Option<Duration> {
+        match self {
+            State::Successful(received) => {
+                if policy.should_retry_status(received.status_code, retries) {
+                    debug!("Retrying request after status {}", received.status_code);
+                    Some(received.retry_after.unwrap_or(policy.delay))
+                } else {
+                    None
+                }
+            }
+            state => {
+                let error = state.retryable_error()?;
+                if policy.should_retry(error, retries) {
+                    debug!("Retrying request after {:?}", error);
+                    Some(policy.delay)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Records number of attempts. Failures after several attempts are reported as `ErrorKind::RetriesExhausted`.
+    fn with_attempts(self, attempts: u32) -> State {
+        let error = match self {
+            State::Successful(mut received) => {
+                received.attempts = attempts;
+                return State::Successful(received);
+            }
+            state if attempts == 1 => return state,
+            State::Error(error) => error,
+            State::Timeout => ErrorKind::RequestTimeout.into(),
+            State::Canceled => return State::Canceled,
+        };
+
+        State::Error(Error::with_chain(
+            error,
+            ErrorKind::RetriesExhausted(attempts),
+        ))
+    }
+
+    fn into_result(self, request: Request) -> Result<Response> {
+        match self {
+            State::Successful(received) => {
+                let throttled = received.status_code == hyper::StatusCode::TOO_MANY_REQUESTS
+                    || received.status_code == hyper::StatusCode::SERVICE_UNAVAILABLE;
+                let response = Response {
+                    base_request: request,
+                    body: received.body,
+                    spilled_body: received.spilled_body,
+                    status_code: received.status_code,
+                    version: received.version,
+                    headers: received.headers,
+                    reason: received
+                        .status_code
+                        .canonical_reason()
+                        .map(ToOwned::to_owned),
+                    timings: received.timings,
+                    elapsed: received.elapsed,
+                    attempts: received.attempts,
+                    retry_after: received.retry_after.filter(|_| throttled),
+                    tcp_stats: received.tcp_stats,
+                    fragment: None,
+                    trailers: received.trailers,
+                };
+
+                if response.base_request.options.error_for_status
+                    && !response.status_code.is_success()
+                {
+                    let mut body = vec![];
+                    if let Err(e) = response
+                        .body_reader()
+                        .and_then(|mut reader| Ok(reader.read_to_end(&mut body)?))
+                    {
+                        warn!("Can't read the body of the failed request: {}", e);
+                    }
+                    bail!(ErrorKind::HttpStatus(response.status_code, body))
+                }
+
+                let success = response
+                    .base_request
+                    .options
+                    .success_predicate
+                    .as_ref()
+                    .map_or(true, |predicate| predicate.is_success(&response));
+                if success {
+                    Ok(response)
+                } else {
+                    bail!(ErrorKind::RequestFailed(Box::new(response)))
+                }
+            }
+            State::Error(error) => Err(error),
+            State::Canceled => Err(ErrorKind::RequestCancelled.into()),
+            State::Timeout => Err(ErrorKind::RequestTimeout.into()),
+        }
+    }
+
+    fn into_output_command(
+        self,
+        request: Request,
+        callback: Box<ResponseCallBack>,
+    ) -> OutputCommand {
+        match self.into_result(request) {
+            Ok(response) => OutputCommand::Response { response, callback },
+            Err(error) => OutputCommand::Error { error, callback },
+        }
+    }
+}
+
+/// Whether `host` of the URL is either not an IP address, or an address allowed by the `family`.
+fn literal_address_allowed(host: &str, family: AddressFamily) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_or(true, |address| family.allows(&address))
+}
+
+/// Whether nobody listens on the address, which connection was made to.
+fn connection_refused(error: &hyper::Error) -> bool {
+    connect_failed_with(error, std::io::ErrorKind::ConnectionRefused)
+}
+
+/// Whether the socket couldn't be bound to the local address.
+fn address_unavailable(error: &hyper::Error) -> bool {
+    connect_failed_with(error, std::io::ErrorKind::AddrNotAvailable)
+}
+
+fn connect_failed_with(error: &hyper::Error, kind: std::io::ErrorKind) -> bool {
+    error.is_connect()
+        && error
+            .cause2()
+            .and_then(|cause| cause.downcast_ref::<std::io::Error>())
+            .map_or(false, |io_error| io_error.kind() == kind)
+}
+
+/// Whether the request failed, because the connection was closed by the server while it was sent.
+fn stale_connection(error: &hyper::Error) -> bool {
+    if error.is_connect() {
+        return false;
+    }
+
+    match error
+        .cause2()
+        .and_then(|cause| cause.downcast_ref::<std::io::Error>())
+    {
+        Some(io_error) => match io_error.kind() {
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof => true,
+            _ => false,
+        },
+        // `hyper` has no predicate for the response cut short by EOF. It is reported without the cause,
+        // like the closed connection, while the rest of such errors are about parsing or the request itself.
+        None => error.is_closed() || !(error.is_parse() || error.is_user() || error.is_canceled()),
+    }
+}
+
+/// Head of the response and its body, independent of the transport.
+struct RawResponse {
+    status_code: hyper::StatusCode,
+    version: hyper::Version,
+    headers: hyper::header::HeaderMap,
+    body: Box<dyn Stream<Item = hyper::Chunk, Error = Error> + Send>,
+    /// Set, once `body` is over.
+    trailers: Trailers,
+    socket: Option<SocketHandle>,
+    request_written: Option<Instant>,
+}
+
+#[derive(Default)]
+struct TrafficCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+/// Snapshot of the queue state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Total number of request body bytes sent.
+    pub bytes_sent: u64,
+    /// Total number of response body bytes received.
+    pub bytes_received: u64,
+    /// Number of requests, which were sent but didn't complete yet.
+    pub in_flight_requests: usize,
+}
+
+impl QueueMetrics {
+    fn snapshot(traffic: &TrafficCounters, cancellation_senders: &CancellationSenders) -> Self {
+        QueueMetrics {
+            bytes_sent: traffic.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: traffic.bytes_received.load(Ordering::Relaxed),
+            in_flight_requests: cancellation_senders.lock().unwrap().len(),
+        }
+    }
+}
+
+type MetricsReporter = dyn Fn(&QueueMetrics) + Send + Sync;
+
+/// Outcome of the single `Queue::execute` call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExecuteStats {
+    /// Number of the processed responses, including progress and streamed events.
+    pub processed: usize,
+    /// Number of the pending requests left after the call.
+    pub remaining_pending: usize,
+    /// Number of the requests completed with the error during the call.
+    pub errors: u64,
+    /// Number of the requests completed with the response during the call.
+    pub successes: u64,
+}
+
+/// Sends the outcome of the request to the queue, unless the queue was already dropped.
+fn deliver(response_sender: &crossbeam_channel::Sender<OutputCommand>, command: OutputCommand) {
+    if response_sender.send(command).is_err() {
+        debug!("Queue was dropped, discarding the response");
+    }
+}
+
+/// Calls the user callback, so that its panic doesn't unwind into the caller of the queue.
+fn guarded<F: FnOnce()>(callback: F) {
+    if let Err(panic) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(callback)) {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| (*message).to_owned())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        error!("Callback panicked: {}", message);
+    }
+}
+
+/// Takes back the request shared with its execution, which has already finished.
+/// Cloning is cheap, since the body is shared.
+fn unshared(request: Arc<Request>) -> Request {
+    Arc::try_unwrap(request).unwrap_or_else(|request| (*request).clone())
+}
+
+/// Spawns requests on the runtime of the queue.
+#[derive(Clone)]
+struct Dispatcher {
+    executor: tokio::runtime::TaskExecutor,
+    tcp_clients: transport::TcpClients,
+    #[cfg(unix)]
+    unix_client: hyper::Client<uds::UnixConnector>,
+    response_sender: crossbeam_channel::Sender<OutputCommand>,
+    cancellation_senders: CancellationSenders,
+    executing: ExecutingRequests,
+    traffic: Arc<TrafficCounters>,
+    slots: Slots,
+    in_flight: Option<InFlight>,
+    rate_limiter: Option<RateLimiter>,
+    max_uri_length: usize,
+    continue_timeout: Duration,
+    local_address: Option<IpAddr>,
+    response_cache: Option<Arc<ResponseCache>>,
+    foreground: ForegroundGate,
+}
+
+impl Dispatcher {
+    /// Performs the request, once free slot is available.
+    /// Resulting future never fails, all errors are reported through the `State`.
+    fn execute_request<C: Future>(
+        &self,
+        request: Arc<Request>,
+        cancellation_signal: C,
+    ) -> impl Future<Item = State, Error = ()> {
+        let dispatcher = self.clone();
+        let cancel_on = match &request.options.cancel_on {
+            Some(cancel_on) => future::Either::A(cancel_on.fired()),
+            None => future::Either::B(future::empty()),
+        };
+
+        // Background requests take a free slot only while there are no foreground requests,
+        // and give place in the line to them, by waiting with the lowest priority.
+        if request.options.background {
+            let slot = self.acquire_slot(0, request.options.acquire_timeout);
+            let execution = self.foreground.yielding(slot.then(move |slot| match slot {
+                Ok(slot) => {
+                    future::Either::A(dispatcher.execute_with_retries(request).map(move |state| {
+                        drop(slot);
+                        state
+                    }))
+                }
+                Err(state) => future::Either::B(future::ok(state)),
+            }));
+            return future::Either::A(Self::cancellable(execution, cancellation_signal, cancel_on));
+        }
+
+        // Foreground request pauses the background ones only once it occupies the slot.
+        // Otherwise it could wait forever for the slot held by the paused background request.
+        let gate = self.foreground.clone();
+        let foreground = self.slots.try_acquire().map(|slot| (slot, gate.enter()));
+        let execution = match foreground {
+            Some(foreground) => future::Either::A(future::ok(foreground)),
+            None => future::Either::B(
+                self.acquire_slot(request.options.priority, request.options.acquire_timeout)
+                    .map(move |slot| (slot, gate.enter())),
+            ),
+        }
+        .then(move |slot| match slot {
+            Ok((slot, foreground)) => {
+                future::Either::A(dispatcher.execute_with_retries(request).map(move |state| {
+                    drop(slot);
+                    drop(foreground);
+                    state
+                }))
+            }
+            Err(state) => future::Either::B(future::ok(state)),
+        });
+
+        future::Either::B(Self::cancellable(execution, cancellation_signal, cancel_on))
+    }
+
+    /// Waits for the free slot, failing with `AcquireTimeout` state after `acquire_timeout`.
+    fn acquire_slot(
+        &self,
+        priority: u8,
+        acquire_timeout: Option<Duration>,
+    ) -> impl Future<Item = SlotGuard, Error = State> {
+        self.slots
+            .acquire(priority)
+            .timeout(acquire_timeout.unwrap_or_else(|| Duration::new(u64::from(std::u16::MAX), 0)))
+            .map_err(|_| State::Error(ErrorKind::AcquireTimeout.into()))
+    }
+
+    /// Resolves to `State::Canceled`, once either of the signals fires.
+    fn cancellable<E, C, O>(
+        execution: E,
+        cancellation_signal: C,
+        cancel_on: O,
+    ) -> impl Future<Item = State, Error = ()>
+    where
+        E: Future<Item = State, Error = ()>,
+        C: Future,
+        O: Future<Item = (), Error = ()>,
+    {
+        execution
+            .select2(
+                cancellation_signal
+                    .then(|_| future::ok(()))
+                    .select(cancel_on)
+                    .then(|_| future::ok(State::Canceled)),
+            )
+            .map_err(|_: future::Either<((), _), ((), _)>| unreachable!())
+            .map(|either| either.split().0)
+    }
+
+    /// Performs the request, retrying it according to the `RetryPolicy`.
+    fn execute_with_retries(&self, request: Arc<Request>) -> impl Future<Item = State, Error = ()> {
+        let dispatcher = self.clone();
+
+        future::loop_fn(0, move |retries| {
+            let retry_policy = request.options.retry_policy.clone();
+
+            let rate_limit = match &dispatcher.rate_limiter {
+                Some(rate_limiter) => future::Either::A(rate_limiter.acquire()),
+                None => future::Either::B(future::ok(())),
+            };
+
+            let attempt = {
+                let dispatcher = dispatcher.clone();
+                let request = Arc::clone(&request);
+                rate_limit.and_then(move |_| dispatcher.execute_attempt(&request))
+            };
+
+            attempt.and_then(
+                move |state| match state.retry_delay(&retry_policy, retries) {
+                    Some(delay) => future::Either::A(
+                        tokio::timer::Delay::new(Instant::now() + delay)
+                            .then(move |_| future::ok(future::Loop::Continue(retries + 1))),
+                    ),
+                    None => future::Either::B(future::ok(future::Loop::Break(
+                        state.with_attempts(retries + 1),
+                    ))),
+                },
+            )
+        })
+    }
+
+    /// Performs single attempt of the request.
+    fn execute_attempt(&self, request: &Request) -> impl Future<Item = State, Error = ()> {
+        let now = Instant::now();
+        let timeout = match (request.options.timeout, request.options.deadline) {
+            (Some(timeout), Some(deadline)) => {
+                Some(timeout.min(deadline.saturating_duration_since(now)))
+            }
+            (timeout, deadline) => {
+                timeout.or_else(|| deadline.map(|deadline| deadline.saturating_duration_since(now)))
+            }
+        };
+        if timeout == Some(Duration::new(0, 0)) {
+            return future::Either::A(future::ok(State::Timeout));
+        }
+
+        let traffic = Arc::clone(&self.traffic);
+        let transfer_decoder = request.options.transfer_decoder.clone();
+        let json_schema = request.options.json_schema.clone();
+        let body_strategy = request.options.body_strategy.clone();
+        let body_strategy_selector = request.options.body_strategy_selector.clone();
+        let download_progress = request.options.download_progress.clone();
+        let response_sender = self.response_sender.clone();
+        let started = Instant::now();
+        let body_len = request.body.len() as u64;
+        let body_stream = request.body_stream.clone();
+
+        let cache = self.response_cache.as_ref().and_then(|cache| {
+            cache::key(request).map(|key| {
+                (
+                    Arc::clone(cache),
+                    key.to_owned(),
+                    request.options.headers.clone(),
+                )
+            })
+        });
+        let validators = self
+            .response_cache
+            .as_ref()
+            .map(|cache| cache.validators(request))
+            .unwrap_or_default();
+        let revalidating = !validators.is_empty();
+
+        let debug_request = if request.options.debug_log {
+            debug_log::log_request(request);
+            Some(Arc::new(request.clone()))
+        } else {
+            None
+        };
+        let debug_error_request = debug_request.clone();
+
+        let response = self
+            .send(request, validators)
+            .and_then(move |response| {
+                // Response is received only after the whole body was written.
+                let body_len = body_stream.as_ref().map_or(body_len, StreamBody::produced);
+                traffic.bytes_sent.fetch_add(body_len, Ordering::Relaxed);
+
+                let RawResponse {
+                    status_code,
+                    version,
+                    headers,
+                    body,
+                    trailers,
+                    socket,
+                    request_written,
+                } = response;
+                let retry_after = retry::retry_after(&headers);
+                let body_strategy = if transfer_decoder.is_some() || json_schema.is_some() {
+                    BodyStrategy::Buffer
+                } else if let Some(selector) = body_strategy_selector {
+                    selector.select(&headers, status_code)
+                } else {
+                    body_strategy
+                };
+
+                let timings = ResponseTimings {
+                    started,
+                    request_written,
+                    head_received: Instant::now(),
+                    first_body_byte: None,
+                    last_body_byte: None,
+                };
+
+                let accumulator = BodyAccumulator::for_response(body_strategy, &headers);
+                let mut progress = DownloadProgress {
+                    received: 0,
+                    total: headers
+                        .get(header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse().ok()),
+                };
+
+                future::result(accumulator)
+                    .and_then(move |accumulator| {
+                        body.fold((accumulator, timings), move |(body, mut timings), chunk| {
+                            traffic
+                                .bytes_received
+                                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+
+                            let now = Instant::now();
+                            timings.first_body_byte.get_or_insert(now);
+                            timings.last_body_byte = Some(now);
+
+                            if let Some(handler) = &download_progress {
+                                progress.received += chunk.len() as u64;
+                                deliver(
+                                    &response_sender,
+                                    OutputCommand::DownloadProgress {
+                                        progress,
+                                        handler: handler.clone(),
+                                    },
+                                );
+                            }
+
+                            body.push(&chunk[..]).map(|body| (body, timings))
+                        })
+                    })
+                    .and_then(move |(body, timings)| {
+                        let (mut body, spilled_body) = body.finish()?;
+                        if let Some(decoder) = transfer_decoder {
+                            body = decoder.decode(&headers, body)?;
+                        }
+                        if let Some(schema) = json_schema {
+                            if schema::is_json(&headers) {
+                                schema.validate_body(&body)?;
+                            }
+                        }
+                        let mut body = Bytes::from(body);
+
+                        let mut status_code = status_code;
+                        if let Some((cache, key, request_headers)) = cache {
+                            if status_code == hyper::StatusCode::NOT_MODIFIED && revalidating {
+                                // Served as if it was fresh.
+                                if let Some(cached) = cache.revalidated(&key, &request_headers) {
+                                    status_code = hyper::StatusCode::OK;
+                                    body = cached;
+                                }
+                            } else if status_code == hyper::StatusCode::OK && spilled_body.is_none()
+                            {
+                                cache.store(&key, &request_headers, &headers, &body);
+                            }
+                        }
+
+                        if let Some(request) = debug_request {
+                            debug_log::log_response(&request, status_code, &headers, &body);
+                        }
+
+                        Ok(ReceivedResponse {
+                            status_code,
+                            version,
+                            headers,
+                            body,
+                            spilled_body,
+                            timings,
+                            elapsed: started.elapsed(),
+                            attempts: 1,
+                            retry_after,
+                            tcp_stats: socket.and_then(|socket| socket.stats()),
+                            trailers: trailers.lock().unwrap().take(),
+                        })
+                    })
+            })
+            // Error handling.
+            .map(|received| State::Successful(Box::new(received)))
+            .or_else(move |e| {
+                if let Some(request) = debug_error_request {
+                    debug_log::log_error(&request, &e);
+                }
+                future::ok::<_, ()>(State::Error(e))
+            })
+            // Timeout.
+            .timeout(timeout.unwrap_or_else(|| Duration::new(u64::from(std::u16::MAX), 0)))
+            .or_else(|_| future::ok(State::Timeout))
+            .map_err(|_: tokio::timer::Error| unreachable!());
+
+        future::Either::B(response)
+    }
+
+    /// Sends the request, following redirects. Decompresses `gzip` encoded body.
+    fn send(
+        &self,
+        request: &Request,
+        extra_headers: header::HeaderMap,
+    ) -> Box<dyn Future<Item = RawResponse, Error = Error> + Send> {
+        let response = self.send_raw(request, extra_headers).map(|res| {
+            let (mut parts, body) = res.into_parts();
+            let trailers = Trailers::default();
+            let body = TrailersBody::new(body, Arc::clone(&trailers)).map_err(Error::from);
+
+            let gzip = parts
+                .headers
+                .get(header::CONTENT_ENCODING)
+                .map_or(false, |encoding| encoding == "gzip");
+
+            let body: Box<dyn Stream<Item = hyper::Chunk, Error = Error> + Send> = if gzip {
+                parts.headers.remove(header::CONTENT_ENCODING);
+                parts.headers.remove(header::CONTENT_LENGTH);
+                Box::new(GzipBody::new(body))
+            } else {
+                Box::new(body)
+            };
+            let body = match parts.extensions.remove::<TrailingData>() {
+                Some(trailing_data) => Box::new(body.chain(stream::poll_fn(move || {
+                    if trailing_data.received() {
+                        bail!(ErrorKind::MalformedResponse(
+                            "data after the end of the body".to_owned()
+                        ));
+                    }
+                    Ok(Async::Ready(None))
+                }))),
+                None => body,
+            };
+
+            RawResponse {
+                status_code: parts.status,
+                version: parts.version,
+                socket: parts.extensions.remove::<SocketHandle>(),
+                request_written: parts
+                    .extensions
+                    .get::<RequestWritten>()
+                    .and_then(RequestWritten::get),
+                headers: parts.headers,
+                body,
+                trailers,
+            }
+        });
+
+        Box::new(response)
+    }
+
+    /// Sends the request, following redirects.
+    /// Adds default headers along with the `extra_headers`. Response is returned as received.
+    fn send_raw(
+        &self,
+        request: &Request,
+        extra_headers: header::HeaderMap,
+    ) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = Error> + Send> {
+        let dispatcher = self.clone();
+        let unix_socket = request.options.unix_socket.clone();
+        let route = transport::TcpRoute {
+            family: request.options.address_family,
+            dns_timeout: request.options.dns_timeout,
+            proxy: request.options.proxy.clone(),
+        };
+        let upload_progress = request.options.upload_progress.clone();
+        let connector_override = request.options.connector_override.clone();
+        let allow_identical_lengths = request.options.allow_identical_content_lengths;
+        let follow_refresh = request.options.follow_refresh;
+
+        // Request is shared by the attempts and kept for the response, so only headers are copied.
+        let mut headers = request.options.headers.clone();
+        headers
+            .entry(header::ACCEPT)
+            .unwrap()
+            .or_insert_with(|| header::HeaderValue::from_static("*/*"));
+        if !headers.contains_key(header::ACCEPT_ENCODING) && !headers.contains_key(header::RANGE) {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                header::HeaderValue::from_static("gzip"),
+            );
+        }
+        headers.extend(extra_headers);
+        if request.options.expect_continue {
+            headers.insert(
+                header::EXPECT,
+                header::HeaderValue::from_static("100-continue"),
+            );
+        }
+
+        let compressed = match request.options.compress_request {
+            Some(compression)
+                if request.body_stream.is_none()
+                    && !headers.contains_key(header::CONTENT_ENCODING) =>
+            {
+                compression.compress(&request.body).map(|body| {
+                    headers.insert(
+                        header::CONTENT_ENCODING,
+                        header::HeaderValue::from_static(compression.content_encoding()),
+                    );
+                    body
+                })
+            }
+            _ => None,
+        };
+
+        let target = RequestTarget {
+            url: request.uri.clone(),
+            method: request.http_type.method(),
+            body: compressed.unwrap_or_else(|| request.body.clone()),
+            stream: request.body_stream.clone(),
+            headers,
+        };
+
+        let response = future::loop_fn((target, 0), move |(target, redirects)| {
+            let send = {
+                let dispatcher = dispatcher.clone();
+                let connector_override = connector_override.clone();
+                let unix_socket = unix_socket.clone();
+                let route = route.clone();
+                let upload_progress = upload_progress.clone();
+                move |target: &RequestTarget| {
+                    dispatcher.send_once(
+                        connector_override.as_ref(),
+                        unix_socket.as_deref(),
+                        &route,
+                        upload_progress.as_ref(),
+                        target,
+                    )
+                }
+            };
+
+            send(&target)
+                .then(move |result| match result {
+                    // Pooled connection may be closed by the server, while the request is sent on it.
+                    // Only the idempotent requests are sent again, as the server may have processed it.
+                    Err((e, true)) if target.method.is_idempotent() && target.stream.is_none() => {
+                        debug!("Connection was closed, sending request again: {}", e);
+                        future::Either::A(
+                            send(&target)
+                                .map(move |res| (res, target))
+                                .map_err(|(e, _)| e),
+                        )
+                    }
+                    result => future::Either::B(future::result(
+                        result.map(|res| (res, target)).map_err(|(e, _)| e),
+                    )),
+                })
+                .and_then(move |(res, target)| {
+                    check_framing(res.headers(), allow_identical_lengths)?;
+
+                    match target.redirect(&res, follow_refresh) {
+                        Some(_) if redirects == MAX_REDIRECTS => {
+                            bail!(ErrorKind::TooManyRedirects(MAX_REDIRECTS))
+                        }
+                        Some(next) => {
+                            debug!("Redirecting to {:?} '{}'", next.method, next.url);
+                            Ok(future::Loop::Continue((next, redirects + 1)))
+                        }
+                        None => Ok(future::Loop::Break(res)),
+                    }
+                })
+        });
+
+        Box::new(response)
+    }
+
+    /// Sends the request over TCP, or over the Unix domain socket when `unix_socket` is set.
+    fn send_once(
+        &self,
+        connector_override: Option<&Arc<dyn Connector>>,
+        unix_socket: Option<&std::path::Path>,
+        route: &transport::TcpRoute,
+        upload_progress: Option<&Progress>,
+        target: &RequestTarget,
+    ) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = (Error, bool)> + Send> {
+        // Checked here to cover the redirect targets too.
+        let uri_length = target.url.as_str().len();
+        if uri_length > self.max_uri_length {
+            return Box::new(future::err((
+                ErrorKind::UriTooLong(uri_length, self.max_uri_length).into(),
+                false,
+            )));
+        }
+
+        let body = match (&target.stream, upload_progress) {
+            (Some(stream), _) => match stream.take() {
+                Ok(body) => body,
+                Err(e) => return Box::new(future::err((e, false))),
+            },
+            (None, Some(progress)) if !target.body.is_empty() => {
+                let response_sender = self.response_sender.clone();
+                let callback = Arc::clone(&progress.0);
+
+                progress::upload_body(target.body.clone(), move |transferred, total| {
+                    deliver(
+                        &response_sender,
+                        OutputCommand::Progress {
+                            transferred,
+                            total,
+                            callback: Arc::clone(&callback),
+                        },
+                    );
+                })
+            }
+            _ => hyper::Body::from(target.body.clone()),
+        };
+        let (body, continue_gate) = if expect::expects_continue(&target.headers)
+            && (target.stream.is_some() || !target.body.is_empty())
+        {
+            let (body, gate) = expect::gated(body, self.continue_timeout);
+            (body, Some(gate))
+        } else {
+            (body, None)
+        };
+
+        let mut http_request = match target.build(body) {
+            Ok(http_request) => http_request,
+            Err(e) => return Box::new(future::err((e, false))),
+        };
+        // Length of the in-memory body is always known, so it is never sent chunked.
+        let expects_body =
+            target.method == hyper::Method::POST || target.method == hyper::Method::PUT;
+        if let Some(stream) = &target.stream {
+            if let Some(length) = stream.len() {
+                http_request
+                    .headers_mut()
+                    .insert(header::CONTENT_LENGTH, length.into());
+            }
+        } else if (!target.body.is_empty() || expects_body)
+            && !http_request.headers().contains_key(header::CONTENT_LENGTH)
+        {
+            http_request
+                .headers_mut()
+                .insert(header::CONTENT_LENGTH, target.body.len().into());
+        }
+
+        let host = target.url.host_str().unwrap_or_default().to_owned();
+
+        let response = match (connector_override, unix_socket) {
+            (Some(connector), _) => {
+                let connector = OverrideConnector {
+                    connector: Arc::clone(connector),
+                    url: target.url.clone(),
+                };
+                Ok(connector.client().request(http_request))
+            }
+            (None, Some(socket_path)) => self.request_unix(socket_path, &target.url, http_request),
+            // Literal addresses aren't resolved, so they are checked here.
+            (None, None) if !literal_address_allowed(&host, route.family) => {
+                Err(ErrorKind::NoReachableAddress(host.clone()).into())
+            }
+            (None, None) => Ok(self.tcp_clients.get(route).request(http_request)),
+        };
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => return Box::new(future::err((e, false))),
+        };
+        let response =
+            lifecycle::observe_reuse(response).map(move |response| match continue_gate {
+                Some(gate) => gate.resolve(response),
+                None => response,
+            });
+
+        let local_address = self.local_address;
+        Box::new(response.map_err(move |(e, reused)| {
+            let stale = reused && stale_connection(&e);
+            let e = if NoAllowedAddress::caused(&e) {
+                Error::with_chain(e, ErrorKind::NoReachableAddress(host))
+            } else if ResolveTimeout::caused(&e) {
+                Error::with_chain(e, ErrorKind::DnsTimeout(host))
+            } else if ResolveFailure::caused(&e) {
+                Error::with_chain(e, ErrorKind::DnsResolution(host))
+            } else if connection_refused(&e) {
+                Error::with_chain(e, ErrorKind::ConnectionRefused)
+            } else if let Some(reason) = SocksError::find(&e) {
+                let kind = ErrorKind::ProxyError(reason.clone());
+                Error::with_chain(e, kind)
+            } else if let Some(address) = local_address.filter(|_| address_unavailable(&e)) {
+                Error::with_chain(e, ErrorKind::LocalAddressUnavailable(address))
+            } else if let Some(mismatch) = LengthMismatch::find(&e) {
+                let kind = ErrorKind::BodyLengthMismatch(mismatch.declared, mismatch.produced);
+                Error::with_chain(e, kind)
+            } else if e.is_parse() {
+                let description = e.to_string();
+                Error::with_chain(e, ErrorKind::MalformedResponse(description))
+            } else {
+                Error::from(e)
+            };
+            (e, stale)
+        }))
+    }
+
+    #[cfg(unix)]
+    fn request_unix(
+        &self,
+        socket_path: &std::path::Path,
+        url: &url::Url,
+        mut http_request: hyper::Request<hyper::Body>,
+    ) -> Result<hyper::client::ResponseFuture> {
+        *http_request.uri_mut() = uds::unix_uri(socket_path, url)?;
+
+        // Otherwise `hyper` would use the encoded socket path.
+        if let Some(host) = url.host_str() {
+            if let Ok(host) = header::HeaderValue::from_str(host) {
+                http_request
+                    .headers_mut()
+                    .entry(header::HOST)
+                    .unwrap()
+                    .or_insert(host);
+            }
+        }
+
+        Ok(self.unix_client.request(http_request))
+    }
+
+    #[cfg(not(unix))]
+    fn request_unix(
+        &self,
+        _socket_path: &std::path::Path,
+        _url: &url::Url,
+        _http_request: hyper::Request<hyper::Body>,
+    ) -> Result<hyper::client::ResponseFuture> {
+        bail!(ErrorKind::UnsupportedScheme("unix".to_owned()))
+    }
+
+    fn dispatch(&self, command: InputCommand) {
+        match command {
+            InputCommand::Request(command) => self.dispatch_request(command),
+            InputCommand::Bulk(command) => self.dispatch_bulk(command),
+            InputCommand::Batch(commands) => self.dispatch_batch(commands),
+            InputCommand::Future(command) => self.dispatch_future(command),
+            InputCommand::Raw(command) => self.dispatch_raw(command),
+            InputCommand::WebSocket(command) => self.dispatch_websocket(command),
+            InputCommand::Sse(command) => self.dispatch_sse(command),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Periodically calls `reporter` until returned sender is dropped.
+    fn spawn_metrics_reporter(
+        &self,
+        interval: Duration,
+        reporter: Box<MetricsReporter>,
+    ) -> oneshot::Sender<()> {
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let traffic = Arc::clone(&self.traffic);
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+
+        self.executor.spawn(
+            tokio::timer::Interval::new(Instant::now() + interval, interval)
+                .map_err(|e| error!("Metrics reporter timer failed: {}", e))
+                .for_each(move |_| {
+                    reporter(&QueueMetrics::snapshot(&traffic, &cancellation_senders));
+                    Ok(())
+                })
+                .select2(stop_receiver)
+                .then(|_| Ok(())),
+        );
+
+        stop_sender
+    }
+
+    fn dispatch_request(&self, command: RequestCommand) {
+        if let Some(future) = self.request_future(command) {
+            self.executor.spawn(future);
+        }
+    }
+
+    /// Spawns requests of the batch as the single task.
+    fn dispatch_batch(&self, commands: Vec<RequestCommand>) {
+        let futures: Vec<_> = commands
+            .into_iter()
+            .filter_map(|command| self.request_future(command))
+            .collect();
+
+        self.executor
+            .spawn(stream::futures_unordered(futures).for_each(|_| Ok(())));
+    }
+
+    /// Resolves the sender on the working thread. Such requests are never coalesced.
+    fn dispatch_future(&self, command: FutureCommand) {
+        let FutureCommand {
+            id,
+            cancellation_signal,
+            request,
+            sender,
+        } = command;
+
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let executing = self.executing.start(id, &request.uri);
+
+        let request = Arc::new(request);
+        self.executor.spawn(
+            self.execute_request(Arc::clone(&request), cancellation_signal)
+                .map(move |state| {
+                    drop(executing);
+                    cancellation_senders.lock().unwrap().remove(&id);
+                    sender.send(state.into_result(unshared(request))).ok();
+                }),
+        )
+    }
+
+    /// Sends the response as soon as its head is received, leaving the body to the callback.
+    /// Only the redirects and the timeout of `RequestOptions` apply to such requests.
+    fn dispatch_raw(&self, command: RawCommand) {
+        let RawCommand {
+            id,
+            cancellation_signal,
+            request,
+            callback,
+        } = command;
+
+        let response_sender = self.response_sender.clone();
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let executing = self.executing.start(id, &request.uri);
+
+        self.executor.spawn(
+            self.send_raw(&request, header::HeaderMap::new())
+                .timeout(
+                    request
+                        .options
+                        .timeout
+                        .unwrap_or_else(|| Duration::new(u64::from(std::u16::MAX), 0)),
+                )
+                .map_err(|e| {
+                    e.into_inner()
+                        .unwrap_or_else(|| ErrorKind::RequestTimeout.into())
+                })
+                .select2(cancellation_signal)
+                .then(move |result| {
+                    drop(executing);
+                    cancellation_senders.lock().unwrap().remove(&id);
+
+                    let result = match result {
+                        Ok(future::Either::A((response, _))) => Ok(response),
+                        Err(future::Either::A((error, _))) => Err(error),
+                        _ => Err(ErrorKind::RequestCancelled.into()),
+                    };
+                    deliver(&response_sender, OutputCommand::Raw { result, callback });
+
+                    Ok(())
+                }),
+        )
+    }
+
+    fn dispatch_websocket(&self, command: WebSocketCommand) {
+        let WebSocketCommand {
+            id,
+            cancellation_signal,
+            request,
+            outgoing,
+            callback,
+        } = command;
+
+        let response_sender = self.response_sender.clone();
+        let deliver = move |event| {
+            deliver(
+                &response_sender,
+                OutputCommand::WebSocket {
+                    event,
+                    callback: Arc::clone(&callback),
+                },
+            );
+        };
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let executing = self.executing.start(id, &request.uri);
+        let finish = move || {
+            drop(executing);
+            cancellation_senders.lock().unwrap().remove(&id);
+        };
+
+        let key = match websocket::handshake_key() {
+            Ok(key) => key,
+            Err(e) => {
+                finish();
+                deliver(Err(e));
+                return;
+            }
+        };
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("Upgrade"),
+        );
+        headers.insert(
+            header::UPGRADE,
+            header::HeaderValue::from_static("websocket"),
+        );
+        headers.insert(
+            header::SEC_WEBSOCKET_VERSION,
+            header::HeaderValue::from_static("13"),
+        );
+        if let Ok(value) = header::HeaderValue::from_str(&key) {
+            headers.insert(header::SEC_WEBSOCKET_KEY, value);
+        }
+
+        let handshake = self
+            .send_raw(&request, headers)
+            .and_then(move |response| {
+                websocket::check_handshake(&response, &key)?;
+                Ok(response.into_body().on_upgrade().map_err(Error::from))
+            })
+            .flatten();
+
+        self.executor.spawn(
+            handshake
+                .timeout(
+                    request
+                        .options
+                        .timeout
+                        .unwrap_or_else(|| Duration::new(u64::from(std::u16::MAX), 0)),
+                )
+                .map_err(|e| {
+                    e.into_inner()
+                        .unwrap_or_else(|| ErrorKind::RequestTimeout.into())
+                })
+                .select2(cancellation_signal)
+                .then(move |result| match result {
+                    Ok(future::Either::A((upgraded, cancellation_signal))) => {
+                        deliver(Ok(WebSocketEvent::Opened));
+                        let connection = websocket::Connection::new(
+                            upgraded,
+                            outgoing,
+                            cancellation_signal,
+                            Box::new(deliver),
+                        );
+                        future::Either::A(connection.then(move |_| {
+                            finish();
+                            Ok(())
+                        }))
+                    }
+                    result => {
+                        finish();
+                        deliver(Err(match result {
+                            Err(future::Either::A((error, _))) => error,
+                            _ => ErrorKind::RequestCancelled.into(),
+                        }));
+                        future::Either::B(future::ok(()))
+                    }
+                }),
+        )
+    }
+
+    fn dispatch_sse(&self, command: SseCommand) {
+        let SseCommand {
+            id,
+            cancellation_signal,
+            request,
+            reconnect,
+            callback,
+        } = command;
+
+        let response_sender = self.response_sender.clone();
+        let deliver = move |event| {
+            deliver(
+                &response_sender,
+                OutputCommand::ServerSentEvent {
+                    event,
+                    callback: Arc::clone(&callback),
+                },
+            );
+        };
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let executing = self.executing.start(id, &request.uri);
+        let dispatcher = self.clone();
+        let request = Arc::new(request);
+
+        // Kept across reconnects for the last event ID and the reconnection time.
+        let parser = Arc::new(Mutex::new(EventStreamParser::default()));
+        let deliver_event = deliver.clone();
+
+        let stream = future::loop_fn((), move |()| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(
+                header::ACCEPT,
+                header::HeaderValue::from_static("text/event-stream"),
+            );
+            headers.insert(
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static("no-cache"),
+            );
+            {
+                let mut parser = parser.lock().unwrap();
+                if let Some(last_event_id) = parser
+                    .last_event_id()
+                    .and_then(|id| header::HeaderValue::from_str(id).ok())
+                {
+                    headers.insert("last-event-id", last_event_id);
+                }
+                parser.reset();
+            }
+
+            let deliver = deliver_event.clone();
+            let stream_parser = Arc::clone(&parser);
+            let retry_parser = Arc::clone(&parser);
+            dispatcher
+                .send(&request, headers)
+                .and_then(|response| {
+                    let content_type = response
+                        .headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    if !response.status_code.is_success() {
+                        bail!(ErrorKind::EventStream(format!(
+                            "unexpected status {}",
+                            response.status_code
+                        )));
+                    }
+                    if !content_type.starts_with("text/event-stream") {
+                        bail!(ErrorKind::EventStream(format!(
+                            "unexpected content type '{}'",
+                            content_type
+                        )));
+                    }
+                    Ok(response.body)
+                })
+                .and_then(move |body| {
+                    body.for_each(move |chunk| {
+                        for event in stream_parser.lock().unwrap().feed(&chunk) {
+                            deliver(Ok(Some(event)));
+                        }
+                        Ok(())
+                    })
+                })
+                .then(|result| match result {
+                    Ok(()) => Ok(None),
+                    // Response, which isn't the event stream, is never reconnected.
+                    Err(e) => match e.kind() {
+                        ErrorKind::EventStream(_) => Err(e),
+                        _ => Ok(Some(e)),
+                    },
+                })
+                .and_then(move |error| {
+                    let delay = match reconnect {
+                        Some(delay) => retry_parser.lock().unwrap().retry().unwrap_or(delay),
+                        None => {
+                            return future::Either::A(match error {
+                                Some(e) => future::err(e),
+                                None => future::ok(future::Loop::Break(())),
+                            })
+                        }
+                    };
+                    if let Some(e) = error {
+                        debug!("Event stream failed, reconnecting: {}", e);
+                    }
+
+                    future::Either::B(
+                        tokio::timer::Delay::new(Instant::now() + delay)
+                            .then(|_| Ok(future::Loop::Continue(()))),
+                    )
+                })
+        });
+
+        self.executor
+            .spawn(stream.select2(cancellation_signal).then(move |result| {
+                drop(executing);
+                cancellation_senders.lock().unwrap().remove(&id);
+
+                let result = match result {
+                    Ok(future::Either::A(_)) => Ok(None),
+                    Err(future::Either::A((error, _))) => Err(error),
+                    _ => Err(ErrorKind::RequestCancelled.into()),
+                };
+                deliver(result);
+
+                Ok(())
+            }))
+    }
+
+    /// Future performing the request and sending its response.
+    /// `None`, when request is attached to the identical in-flight request.
+    fn request_future(
+        &self,
+        command: RequestCommand,
+    ) -> Option<impl Future<Item = (), Error = ()>> {
+        let RequestCommand {
+            id,
+            cancellation_signal,
+            request,
+            callback,
+        } = command;
+
+        let in_flight = self.in_flight.clone();
+        let key = in_flight.as_ref().and_then(|_| CoalesceKey::new(&request));
+        let (request, callback) = match (&in_flight, &key) {
+            (Some(in_flight), Some(key)) => {
+                match in_flight.attach(
+                    key,
+                    Waiter {
+                        id,
+                        request,
+                        callback,
+                    },
+                ) {
+                    Some(Waiter {
+                        request, callback, ..
+                    }) => (request, callback),
+                    None => {
+                        debug!("Request {} attached to the identical in-flight request", id);
+                        self.watch_waiter(in_flight.clone(), key.clone(), id, cancellation_signal);
+                        return None;
+                    }
+                }
+            }
+            _ => (request, callback),
+        };
+
+        let response_sender = self.response_sender.clone();
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let executing = self.executing.start(id, &request.uri);
+
+        let request = Arc::new(request);
+        Some(
+            self.execute_request(Arc::clone(&request), cancellation_signal)
+                .map(move |state| {
+                    drop(executing);
+                    let waiters = match (in_flight, key) {
+                        (Some(in_flight), Some(key)) => in_flight.complete(&key),
+                        _ => vec![],
+                    };
+
+                    {
+                        let mut cancellation_senders = cancellation_senders.lock().unwrap();
+                        cancellation_senders.remove(&id);
+                        for waiter in &waiters {
+                            cancellation_senders.remove(&waiter.id);
+                        }
+                    }
+
+                    let duplicates: Vec<_> = waiters
+                        .into_iter()
+                        .map(|waiter| {
+                            state
+                                .duplicate()
+                                .into_output_command(waiter.request, waiter.callback)
+                        })
+                        .collect();
+
+                    deliver(
+                        &response_sender,
+                        state.into_output_command(unshared(request), callback),
+                    );
+                    for duplicate in duplicates {
+                        deliver(&response_sender, duplicate);
+                    }
+                }),
+        )
+    }
+
+    /// Detaches the waiter, once it is cancelled, so that it doesn't receive the shared response.
+    /// Signal fails, when the waiter is completed, as its cancellation sender is removed then.
+    fn watch_waiter(
+        &self,
+        in_flight: InFlight,
+        key: CoalesceKey,
+        id: RequestId,
+        cancellation_signal: oneshot::Receiver<()>,
+    ) {
+        let response_sender = self.response_sender.clone();
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+
+        self.executor
+            .spawn(cancellation_signal.then(move |cancelled| {
+                if cancelled.is_ok() {
+                    if let Some(waiter) = in_flight.detach(&key, id) {
+                        cancellation_senders.lock().unwrap().remove(&id);
+                        deliver(
+                            &response_sender,
+                            State::Canceled.into_output_command(waiter.request, waiter.callback),
+                        );
+                    }
+                }
+                Ok(())
+            }));
+    }
+
+    fn dispatch_bulk(&self, command: BulkCommand) {
+        let BulkCommand {
+            id,
+            cancellation_signal,
+            requests,
+            concurrency,
+            callback,
+        } = command;
+
+        let dispatcher = self.clone();
+        let response_sender = self.response_sender.clone();
+        let cancellation_senders = Arc::clone(&self.cancellation_senders);
+        let cancellation_signal = cancellation_signal.shared();
+
+        self.executor.spawn(
+            stream::iter_ok(requests)
+                .map(move |request| {
+                    let executing = dispatcher.executing.start(id, &request.uri);
+                    let request = Arc::new(request);
+                    dispatcher
+                        .execute_request(Arc::clone(&request), cancellation_signal.clone())
+                        .map(move |state| {
+                            drop(executing);
+                            state.into_result(unshared(request))
+                        })
+                })
+                .buffered(concurrency)
+                .collect()
+                .map(move |results| {
+                    cancellation_senders.lock().unwrap().remove(&id);
+
+                    deliver(&response_sender, OutputCommand::Bulk { results, callback });
+                }),
+        )
+    }
+}
+
+/// Options applied to the whole queue.
+#[derive(Builder, Clone, Debug, Default)]
+pub struct QueueOptions {
+    /// Use HTTP/2 without negotiation, including plain `http://` URLs.
+    /// Over TLS HTTP/2 is negotiated through ALPN regardless of this option.
+    #[builder(default)]
+    pub http2_prior_knowledge: bool,
+
+    /// Maximal number of simultaneously executed requests. Other requests wait for a free slot.
+    #[builder(default)]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// How long resolved addresses are reused. `None` disables caching.
+    #[builder(default)]
+    pub dns_cache_ttl: Option<Duration>,
+
+    /// Resolver of the host names. `SystemResolver` is used by default.
+    #[builder(default)]
+    pub resolver: Option<Arc<dyn Resolver>>,
+
+    /// Execute identical `GET` requests, which are in flight simultaneously, only once.
+    /// Requests are identical, when their URIs, bodies and headers are equal.
+    /// Every request receives its own copy of the response, produced with the options of the first one.
+    /// Cancelling the first request cancels the rest of them too, cancelling the others detaches only them.
+    #[builder(default)]
+    pub coalesce_duplicates: bool,
+
+    /// Limits rate at which requests are started, including retries.
+    /// Requests exceeding the limit wait for their turn. `max_requests` must be positive.
+    #[builder(default)]
+    pub rate_limit: Option<RateLimit>,
+
+    /// Size of the `SystemResolver` thread pool. Four threads are used by default, zero is invalid.
+    /// Ignored, when `resolver` is set.
+    #[builder(default)]
+    pub number_of_dns_threads: Option<usize>,
+
+    /// Maximal number of idle connections kept for every host. Unlimited by default.
+    /// Zero disables connection reuse.
+    #[builder(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// How long idle connections are kept. `hyper` default of 90 seconds is used, when unset.
+    #[builder(default)]
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// Accept any TLS certificate, including self-signed and expired ones, for any host name.
+    /// Meant only for testing against local servers: such connections can be intercepted.
+    #[builder(default)]
+    pub danger_accept_invalid_certs: bool,
+
+    /// Requests with longer URIs fail with `ErrorKind::UriTooLong` before being sent.
+    /// 64 KiB by default.
+    #[builder(default)]
+    pub max_uri_length: Option<usize>,
+
+    /// How long the body of the request with `Expect: 100-continue` is held back,
+    /// when neither `100 Continue` nor the final response is received. 1 second by default.
+    #[builder(default)]
+    pub continue_timeout: Option<Duration>,
+
+    /// Called every time the TCP connection is closed.
+    #[builder(default)]
+    pub on_connection_close: Option<ConnectionCloseHook>,
+
+    /// Called with the request of every response passed to a callback, including the responses of
+    /// batches and bulks. Requests which failed, futures, WebSockets and event streams aren't observed.
+    #[builder(default)]
+    pub on_request: Option<RequestHook>,
+
+    /// Called with every response, before the callback of the request.
+    /// Responses delivered in fragments are observed once, before being split.
+    #[builder(default)]
+    pub on_response: Option<ResponseHook>,
+
+    /// Maximal number of cached `GET` responses. `None` disables caching.
+    /// Responses with `ETag` or `Last-Modified` are cached, unless `Cache-Control: no-store` is set.
+    /// Cached response is used only for the requests with the same values of the headers named by `Vary`.
+    /// Responses to the requests with `Authorization` or `Cookie` are cached only with `Cache-Control: public`.
+    /// Cached responses are revalidated, and `304 Not Modified` is delivered as `200` with the cached body.
+    #[builder(default)]
+    pub response_cache_size: Option<usize>,
+
+    /// Maximal number of requests sent by `Queue::send_request`, which weren't received
+    /// by the worker yet. Requests over it fail with `ErrorKind::QueueFull`. Unbounded by default.
+    /// Has to be positive.
+    #[builder(default)]
+    pub input_capacity: Option<usize>,
+
+    /// How long `Queue::stop` waits for the worker to finish, before leaving it behind.
+    /// 5 seconds by default.
+    #[builder(default)]
+    pub stop_timeout: Option<Duration>,
+
+    /// Local address, which TCP connections are bound to before connecting, e.g. to pick the interface
+    /// of the multi-homed server. Requests fail with `ErrorKind::LocalAddressUnavailable`,
+    /// when the address isn't assigned to this host. Hosts of the other address family can't be reached.
+    #[builder(default)]
+    pub local_address: Option<IpAddr>,
+
+    /// Fails the response with `ErrorKind::MalformedResponse`, when bytes follow its body declared by
+    /// `Content-Length` in the same read. Connection receiving them is never reused regardless of this option.
+    #[builder(default)]
+    pub reject_trailing_data: bool,
+
+    /// Disables Nagle's algorithm on the TCP connections, so that small requests aren't delayed.
+    #[builder(default)]
+    pub tcp_nodelay: bool,
+
+    /// Enables TCP keep-alive probes on the connections idle for this long. Disabled by default.
+    #[builder(default)]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Tunnels TCP connections through the SOCKS5 proxy. Requests fail with `ErrorKind::ProxyError`,
+    /// when the proxy doesn't establish the connection.
+    #[builder(default)]
+    pub socks5_proxy: Option<Socks5Proxy>,
+
+    /// Absolute URL, which relative URIs passed to `Queue::resolve_uri` are resolved against.
+    /// `Request::uri` is always absolute, so `Queue::resolve_uri` is the only way to use the base.
+    #[builder(default)]
+    pub base_url: Option<hyper::Uri>,
+}
+
+impl QueueOptions {
+    /// Fails with `ErrorKind::InvalidOptions` on the values, which can't be applied.
+    fn validate(&self) -> Result<()> {
+        if self.resolver.is_none() && self.number_of_dns_threads == Some(0) {
+            bail!(ErrorKind::InvalidOptions(
+                "number_of_dns_threads must be positive".to_owned()
+            ));
+        }
+        if let Some(RateLimit {
+            max_requests: 0, ..
+        }) = self.rate_limit
+        {
+            bail!(ErrorKind::InvalidOptions(
+                "rate_limit.max_requests must be positive".to_owned()
+            ));
+        }
+        if self.input_capacity == Some(0) {
+            bail!(ErrorKind::InvalidOptions(
+                "input_capacity must be positive".to_owned()
+            ));
+        }
+        if let Some(base_url) = &self.base_url {
+            if base_url.scheme_part().is_none() || base_url.authority_part().is_none() {
+                bail!(ErrorKind::InvalidOptions(format!(
+                    "base_url must be absolute, got '{}'",
+                    base_url
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const DEFAULT_DNS_THREADS: usize = 4;
+
+const DEFAULT_MAX_URI_LENGTH: usize = 64 * 1024;
+
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Configuration currently applied to the queue. See `QueueOptions` for the meaning of the fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueConfigSnapshot {
+    pub http2_prior_knowledge: bool,
+    pub max_concurrent_requests: Option<usize>,
+    pub dns_cache_ttl: Option<Duration>,
+    /// Whether user supplied `QueueOptions::resolver` is used.
+    pub custom_resolver: bool,
+    /// Size of the `SystemResolver` thread pool. `None`, when `custom_resolver` is set.
+    pub number_of_dns_threads: Option<usize>,
+    pub coalesce_duplicates: bool,
+    pub rate_limit: Option<RateLimit>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub danger_accept_invalid_certs: bool,
+    pub max_uri_length: usize,
+    pub continue_timeout: Duration,
+    /// Whether `QueueOptions::on_connection_close` hook is set.
+    pub on_connection_close: bool,
+    /// Whether `QueueOptions::on_request` hook is set.
+    pub on_request: bool,
+    /// Whether `QueueOptions::on_response` hook is set.
+    pub on_response: bool,
+    pub response_cache_size: Option<usize>,
+    pub input_capacity: Option<usize>,
+    pub stop_timeout: Duration,
+    pub local_address: Option<IpAddr>,
+    pub reject_trailing_data: bool,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<Duration>,
+    /// Password of the proxy is redacted, when the snapshot is formatted with `Debug`.
+    pub socks5_proxy: Option<Socks5Proxy>,
+    pub base_url: Option<hyper::Uri>,
+    pub paused: bool,
+    /// Interval of the reporter set by `Queue::set_metrics_reporter`.
+    pub metrics_interval: Option<Duration>,
+}
+
+impl QueueConfigSnapshot {
+    fn new(options: &QueueOptions) -> Self {
+        let custom_resolver = options.resolver.is_some();
+
+        QueueConfigSnapshot {
+            http2_prior_knowledge: options.http2_prior_knowledge,
+            max_concurrent_requests: options.max_concurrent_requests,
+            dns_cache_ttl: options.dns_cache_ttl,
+            custom_resolver,
+            number_of_dns_threads: if custom_resolver {
+                None
+            } else {
+                Some(options.number_of_dns_threads.unwrap_or(DEFAULT_DNS_THREADS))
+            },
+            coalesce_duplicates: options.coalesce_duplicates,
+            rate_limit: options.rate_limit,
+            pool_max_idle_per_host: options.pool_max_idle_per_host,
+            pool_idle_timeout: options.pool_idle_timeout,
+            danger_accept_invalid_certs: options.danger_accept_invalid_certs,
+            max_uri_length: options.max_uri_length.unwrap_or(DEFAULT_MAX_URI_LENGTH),
+            continue_timeout: options.continue_timeout.unwrap_or(DEFAULT_CONTINUE_TIMEOUT),
+            on_connection_close: options.on_connection_close.is_some(),
+            on_request: options.on_request.is_some(),
+            on_response: options.on_response.is_some(),
+            response_cache_size: options.response_cache_size,
+            input_capacity: options.input_capacity,
+            stop_timeout: options.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT),
+            local_address: options.local_address,
+            reject_trailing_data: options.reject_trailing_data,
+            tcp_nodelay: options.tcp_nodelay,
+            tcp_keepalive: options.tcp_keepalive,
+            socks5_proxy: options.socks5_proxy.clone(),
+            base_url: options.base_url.clone(),
+            paused: false,
+            metrics_interval: None,
+        }
+    }
+}
+
+/// Runs the loop receiving commands of the queue.
+struct Worker {
+    /// Dedicated thread with its own runtime. `None`, when the runtime may be shared with other queues.
+    thread: Option<thread::JoinHandle<()>>,
+    /// Disconnected, once the worker is finished.
+    finished: crossbeam_channel::Receiver<()>,
+}
+
+impl Worker {
+    /// Waits at most `timeout` for the worker to finish. Worker, which didn't finish, is left behind.
+    fn join(self, timeout: Duration) {
+        if let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+            self.finished.recv_timeout(timeout)
+        {
+            warn!(
+                "Worker of the queue didn't finish in {:?}, leaving it behind",
+                timeout
+            );
+            return;
+        }
+
+        if let Some(thread) = self.thread {
+            if thread.join().is_err() {
+                error!("Worker thread of the queue panicked");
+            }
+        }
+    }
+}
+
+type CommandLoop = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+enum InputSender {
+    Unbounded(futures::sync::mpsc::UnboundedSender<InputCommand>),
+    Bounded(futures::sync::mpsc::Sender<InputCommand>),
+}
+
+impl InputSender {
+    /// Channel holding at most `capacity` commands sent by `try_send`, when it is set.
+    /// The bound is per sender: every clone of the sender adds a guaranteed slot, which `send` relies on.
+    /// `capacity` has to be positive.
+    fn channel(
+        capacity: Option<usize>,
+    ) -> (
+        Self,
+        Box<dyn Stream<Item = InputCommand, Error = ()> + Send>,
+    ) {
+        match capacity {
+            Some(capacity) => {
+                // Sender always has a slot in addition to the buffer.
+                let (sender, receiver) = futures::sync::mpsc::channel(capacity - 1);
+                (InputSender::Bounded(sender), Box::new(receiver))
+            }
+            None => {
+                let (sender, receiver) = futures::sync::mpsc::unbounded();
+                (InputSender::Unbounded(sender), Box::new(receiver))
+            }
+        }
+    }
+
+    /// Fails with `ErrorKind::QueueFull`, when the channel is bounded and full.
+    fn try_send(&mut self, command: InputCommand) -> Result<()> {
+        match self {
+            InputSender::Unbounded(sender) => {
+                sender.unbounded_send(command).ok();
+            }
+            InputSender::Bounded(sender) => match sender.try_send(command) {
+                Err(e) if e.is_full() => bail!(ErrorKind::QueueFull),
+                _ => {}
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Sends command regardless of the bound. Used only for the control commands,
+    /// requests are sent by `try_send`.
+    fn send(&self, command: InputCommand) {
+        match self {
+            InputSender::Unbounded(sender) => {
+                sender.unbounded_send(command).ok();
+            }
+            // Every sender has its own slot, so the new one is never full.
+            InputSender::Bounded(sender) => {
+                sender.clone().try_send(command).ok();
+            }
+        }
+    }
+}
+
+pub struct Queue {
+    worker: Option<Worker>,
+    input_command_sender: InputSender,
+    response_receiver: crossbeam_channel::Receiver<OutputCommand>,
+    last_time_executed_with_limit: Option<Instant>,
+    number_of_pending_requests: usize,
+    completed_success: u64,
+    completed_error: u64,
+    next_request_id: RequestId,
+    cancellation_senders: CancellationSenders,
+    executing: ExecutingRequests,
+    paused: bool,
+    stopped: bool,
+    stop_timeout: Duration,
+    base_url: Option<hyper::Uri>,
+    on_request: Option<RequestHook>,
+    on_response: Option<ResponseHook>,
+    traffic: Arc<TrafficCounters>,
+    resolver: CachingResolver,
+    response_cache: Option<Arc<ResponseCache>>,
+    config: Mutex<QueueConfigSnapshot>,
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Queue {
+    pub fn new() -> Result<Self> {
+        Queue::with_options(QueueOptions::default())
+    }
+
+    /// Fails with `ErrorKind::InvalidOptions`, when `options` can't be applied,
+    /// and with `ErrorKind::RuntimeInit`, when the runtime or its thread can't be started.
+    pub fn with_options(options: QueueOptions) -> Result<Self> {
+        options.validate()?;
+        let mut runtime = tokio::runtime::Runtime::new().chain_err(|| ErrorKind::RuntimeInit)?;
+        let executor = runtime.executor();
+
+        Queue::start(options, executor, move |command_loop| {
+            let (finished_sender, finished) = crossbeam_channel::bounded::<()>(0);
+            let thread = thread::Builder::new()
+                .spawn(move || {
+                    runtime.block_on(command_loop).unwrap();
+                    drop(runtime);
+                    drop(finished_sender);
+                })
+                .chain_err(|| ErrorKind::RuntimeInit)?;
+
+            Ok(Worker {
+                thread: Some(thread),
+                finished,
+            })
+        })
+    }
+
+    /// Queue spawning its requests on the `executor`, which may be shared by several queues,
+    /// instead of the runtime on the dedicated thread.
+    /// Runtime has to outlive the requests, otherwise they fail with `ErrorKind::RequestCancelled`.
+    /// `stop` blocks until the queue is finished, so it must not be called on the threads of the runtime.
+    /// Fails with `ErrorKind::InvalidOptions`, when `options` can't be applied.
+    pub fn with_executor(
+        executor: tokio::runtime::TaskExecutor,
+        options: QueueOptions,
+    ) -> Result<Self> {
+        options.validate()?;
+        Queue::start(options, executor.clone(), move |command_loop| {
+            // Dropped task, e.g. because the runtime was shut down, is finished too.
+            let (finished_sender, finished) = crossbeam_channel::bounded::<()>(0);
+            executor.spawn(command_loop.then(move |_| {
+                drop(finished_sender);
+                Ok(())
+            }));
+
+            Ok(Worker {
+                thread: None,
+                finished,
+            })
+        })
+    }
+
+    fn start<F>(
+        options: QueueOptions,
+        executor: tokio::runtime::TaskExecutor,
+        spawn: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(CommandLoop) -> Result<Worker>,
+    {
+        let (input_command_sender, input_command_receiver) =
+            InputSender::channel(options.input_capacity);
+        let (response_sender, response_receiver) = crossbeam_channel::unbounded();
+
+        let resolver = CachingResolver::new(
+            options.resolver.clone().unwrap_or_else(|| {
+                Arc::new(SystemResolver::new(
+                    options.number_of_dns_threads.unwrap_or(DEFAULT_DNS_THREADS),
+                ))
+            }),
+            options.dns_cache_ttl,
+        );
+
+        let config = Mutex::new(QueueConfigSnapshot::new(&options));
+        let tcp_clients = transport::TcpClients::new(&options, &resolver);
+        #[cfg(unix)]
+        let unix_client = transport::unix_client(&options);
+
+        let cancellation_senders: CancellationSenders = Default::default();
+        let executing = ExecutingRequests::default();
+        let traffic: Arc<TrafficCounters> = Default::default();
+        let response_cache = options
+            .response_cache_size
+            .map(|size| Arc::new(ResponseCache::new(size)));
+
+        let dispatcher = Dispatcher {
+            executor,
+            tcp_clients,
+            #[cfg(unix)]
+            unix_client,
+            response_sender,
+            cancellation_senders: Arc::clone(&cancellation_senders),
+            executing: executing.clone(),
+            traffic: Arc::clone(&traffic),
+            slots: Slots::new(options.max_concurrent_requests),
+            in_flight: if options.coalesce_duplicates {
+                Some(InFlight::default())
+            } else {
+                None
+            },
+            rate_limiter: options.rate_limit.map(RateLimiter::new),
+            max_uri_length: options.max_uri_length.unwrap_or(DEFAULT_MAX_URI_LENGTH),
+            continue_timeout: options.continue_timeout.unwrap_or(DEFAULT_CONTINUE_TIMEOUT),
+            local_address: options.local_address,
+            response_cache: response_cache.clone(),
+            foreground: ForegroundGate::default(),
+        };
+
+        let worker = spawn({
+            let mut paused = false;
+            let mut held_requests = VecDeque::new();
+            // Only keeps the current metrics reporter running, it stops when the sender is dropped.
+            let mut _metrics_reporter = None;
+
+            Box::new(future::lazy(move || {
+                input_command_receiver
+                    .take_while(|cmd| {
+                        Ok(match cmd {
+                            InputCommand::Quit => {
+                                info!("Received quit command. New commands will not be received");
+                                false
+                            }
+                            _ => true,
+                        })
+                    })
+                    .for_each(move |cmd| {
+                        match cmd {
+                            InputCommand::Quit => unreachable!(),
+                            InputCommand::Pause => paused = true,
+                            InputCommand::Resume => {
+                                paused = false;
+                                for command in held_requests.drain(..) {
+                                    dispatcher.dispatch(command);
+                                }
+                            }
+                            InputCommand::MetricsReporter { interval, reporter } => {
+                                // Dropping the previous sender stops the previous reporter.
+                                _metrics_reporter =
+                                    Some(dispatcher.spawn_metrics_reporter(interval, reporter));
+                            }
+                            command => {
+                                if paused {
+                                    held_requests.push_back(command);
+                                } else {
+                                    dispatcher.dispatch(command);
+                                }
+                            }
+                        }
+
+                        Ok(())
+                    })
+            }))
+        })?;
+
+        Ok(Queue {
+            worker: Some(worker),
+            input_command_sender,
+            response_receiver,
+            last_time_executed_with_limit: None,
+            number_of_pending_requests: 0,
+            completed_success: 0,
+            completed_error: 0,
+            next_request_id: 1,
+            cancellation_senders,
+            executing,
+            paused: false,
+            stopped: false,
+            stop_timeout: options.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT),
+            base_url: options.base_url,
+            on_request: options.on_request,
+            on_response: options.on_response,
+            traffic,
+            resolver,
+            response_cache,
+            config,
+        })
+    }
+
+    /// Stops the worker receiving commands. Before that, in-flight and held requests are cancelled,
+    /// and the callbacks of all pending requests are called: with the result, when it has already arrived,
+    /// otherwise with `ErrorKind::RequestCancelled`. Callbacks are never called after `stop` returns.
+    /// Calls after the first one do nothing.
+    pub fn stop(&mut self) {
+        if self.stopped {
+            return;
+        }
+
+        if self.worker.is_some() {
+            self.cancel_pending();
+        }
+
+        self.send_command(InputCommand::Quit);
+        if let Some(worker) = mem::replace(&mut self.worker, None) {
+            worker.join(self.stop_timeout);
+        }
+        self.stopped = true;
+
+        // Results, which arrived after the pending requests stopped being awaited.
+        while let Ok(command) = self.response_receiver.try_recv() {
+            self.execute_output_command(command);
+        }
+    }
+
+    /// Cancels all requests and delivers their callbacks.
+    fn cancel_pending(&mut self) {
+        let cancellation_senders: Vec<_> = self
+            .cancellation_senders
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, sender)| sender)
+            .collect();
+
+        for sender in cancellation_senders {
+            sender.send(()).ok();
+        }
+
+        // Held requests should observe cancellation too.
+        if self.paused {
+            self.resume();
+        }
+
+        while self.number_of_pending_requests > 0 {
+            match self.response_receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(command) => self.execute_output_command(command),
+                Err(_) => {
+                    warn!(
+                        "{} requests didn't finish while stopping the queue",
+                        self.number_of_pending_requests
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the pending requests to complete, delivering their callbacks, then stops the queue.
+    /// Requests still running after the timeout are cancelled and receive `ErrorKind::RequestCancelled`.
+    pub fn shutdown_graceful(&mut self, timeout: Duration) {
+        let started = Instant::now();
+        while self.number_of_pending_requests > 0 && started.elapsed() < timeout {
+            if self.try_recv_queue().is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        self.stop();
+    }
+
+    /// Fails with `ErrorKind::QueueStopped` after `stop`, and with `ErrorKind::QueueFull`,
+    /// when `QueueOptions::input_capacity` is reached, without calling the `callback`.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_request<T: 'static + Fn(Result<Response>) + Sync + Send>(
+        &mut self,
+        request: Request,
+        callback: T,
+    ) -> Result<RequestCancellation> {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+
+        self.send_request_command(InputCommand::Request(RequestCommand {
+            id,
+            cancellation_signal,
+            request,
+            callback: Box::new(callback),
+        }))?;
+
+        Ok(cancellation)
+    }
+
+    /// Same as `send_request`, but also calls `progress` with the number of body bytes
+    /// handed to the connection so far and the body length, as the body is uploaded.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_request_with_progress<P, T>(
+        &mut self,
+        mut request: Request,
+        progress: P,
+        callback: T,
+    ) -> Result<RequestCancellation>
+    where
+        P: 'static + Fn(u64, u64) + Sync + Send,
+        T: 'static + Fn(Result<Response>) + Sync + Send,
+    {
+        request.options.upload_progress = Some(Progress(Arc::new(progress)));
+        self.send_request(request, callback)
+    }
+
+    /// Sends the body received from `chunks` with the chunked transfer encoding,
+    /// as the chunks arrive. Body ends, when all the senders are dropped.
+    /// Such request is sent only once, see `StreamBody`.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_request_streaming_body<T>(
+        &mut self,
+        mut request: Request,
+        chunks: futures::sync::mpsc::Receiver<Vec<u8>>,
+        callback: T,
+    ) -> Result<RequestCancellation>
+    where
+        T: 'static + Fn(Result<Response>) + Sync + Send,
+    {
+        // Receiver never fails.
+        let chunks = chunks
+            .map(Bytes::from)
+            .map_err(|()| std::io::Error::from(std::io::ErrorKind::Other));
+        request.body_stream = Some(StreamBody::chunked(chunks));
+        self.send_request(request, callback)
+    }
+
+    /// Sends request over the Unix domain socket at `socket_path`.
+    /// Request fails with `ErrorKind::UnsupportedScheme` on platforms without Unix sockets.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_request_uds<P, T>(
+        &mut self,
+        socket_path: P,
+        mut request: Request,
+        callback: T,
+    ) -> Result<RequestCancellation>
+    where
+        P: Into<PathBuf>,
+        T: 'static + Fn(Result<Response>) + Sync + Send,
+    {
+        request.options.unix_socket = Some(socket_path.into());
+        self.send_request(request, callback)
+    }
+
+    /// Escape hatch for the advanced interop: `callback` receives the response of `hyper` as is,
+    /// once its head is received. Caller is responsible for consuming the body,
+    /// which is delivered as received, e.g. `gzip` encoded.
+    /// Only the redirects and the timeout of `RequestOptions` apply, the timeout covering the head only.
+    /// Fails with `ErrorKind::QueueStopped` and `ErrorKind::QueueFull` as `send_request` does.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_request_raw<T>(
+        &mut self,
+        request: Request,
+        callback: T,
+    ) -> Result<RequestCancellation>
+    where
+        T: 'static + Fn(Result<hyper::Response<hyper::Body>>) + Sync + Send,
+    {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+
+        self.send_request_command(InputCommand::Raw(RawCommand {
+            id,
+            cancellation_signal,
+            request,
+            callback: Box::new(callback),
+        }))?;
+
+        Ok(cancellation)
+    }
+
+    /// Opens WebSocket connection to `uri` with the `ws` or `wss` scheme. `callback` receives
+    /// `WebSocketEvent::Opened` after the handshake, then the received messages, and finally
+    /// `WebSocketEvent::Closed` or the error. Connection is counted as pending until then.
+    /// Pings are answered automatically. Stopping the queue closes the connection.
+    pub fn send_websocket<T>(&mut self, uri: url::Url, callback: T) -> Result<WebSocket>
+    where
+        T: 'static + Fn(Result<WebSocketEvent>) + Sync + Send,
+    {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let mut http_uri = uri.clone();
+        let scheme = match uri.scheme() {
+            "ws" => "http",
+            "wss" => "https",
+            scheme => bail!(ErrorKind::UnsupportedScheme(scheme.to_owned())),
+        };
+        http_uri
+            .set_scheme(scheme)
+            .map_err(|_| ErrorKind::UnsupportedScheme(uri.scheme().to_owned()))?;
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+        let (sender, outgoing) = futures::sync::mpsc::unbounded();
+
+        self.send_request_command(InputCommand::WebSocket(WebSocketCommand {
+            id,
+            cancellation_signal,
+            request: Request {
+                http_type: RequestType::Get,
+                uri: http_uri,
+                body: Bytes::new(),
+                body_stream: None,
+                options: RequestOptions::default(),
+            },
+            outgoing,
+            callback: Arc::new(callback),
+        }))?;
+
+        Ok(WebSocket {
+            sender,
+            _cancellation: cancellation,
+        })
+    }
+
+    /// Receives `text/event-stream` of the `request`. `callback` is called with every event,
+    /// and finally with `Ok(None)` once the stream ended, or with the error.
+    /// Request is counted as pending until then. `RequestOptions::timeout` doesn't apply.
+    /// When `reconnect` is set, ended or failed stream is requested again after that delay,
+    /// or after the delay set by the server, with `Last-Event-ID` of the last received event.
+    /// Responses, which aren't successful or aren't the event stream, fail with `ErrorKind::EventStream`.
+    pub fn send_sse_request<T>(
+        &mut self,
+        request: Request,
+        reconnect: Option<Duration>,
+        callback: T,
+    ) -> Result<RequestCancellation>
+    where
+        T: 'static + Fn(Result<Option<ServerSentEvent>>) + Sync + Send,
+    {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+
+        self.send_request_command(InputCommand::Sse(SseCommand {
+            id,
+            cancellation_signal,
+            request,
+            reconnect,
+            callback: Arc::new(callback),
+        }))?;
+
+        Ok(cancellation)
+    }
+
+    /// Sends request for every key, built using `request_template`.
+    /// At most `concurrency` of them are executed simultaneously.
+    /// `callback` is called once, when all requests are completed, with the results in the order of `keys`.
+    /// Fails with `ErrorKind::QueueStopped` and `ErrorKind::QueueFull` as `send_request` does.
+    #[must_use = "this `RequestCancellation` should be alive, because when it drops request cancels."]
+    pub fn send_bulk_request<K, T, F>(
+        &mut self,
+        keys: Vec<K>,
+        request_template: T,
+        concurrency: usize,
+        callback: F,
+    ) -> Result<RequestCancellation>
+    where
+        K: 'static + Send,
+        T: Fn(&K) -> Request,
+        F: 'static + FnOnce(Vec<(K, Result<Response>)>) + Send,
+    {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+
+        let requests: Vec<_> = keys.iter().map(request_template).collect();
+
+        self.send_request_command(InputCommand::Bulk(BulkCommand {
+            id,
+            cancellation_signal,
+            requests,
+            concurrency: std::cmp::max(concurrency, 1),
+            callback: Box::new(move |results| callback(keys.into_iter().zip(results).collect())),
+        }))?;
+
+        Ok(cancellation)
+    }
+
+    /// Sends request, which response resolves the returned future.
+    /// Future is resolved on the working thread, without `execute_queue` calls,
+    /// so the request isn't counted by `number_of_pending_requests`.
+    /// Dropping the future cancels the request. Future fails with `ErrorKind::QueueStopped`
+    /// after `stop`, and with `ErrorKind::QueueFull`, when `QueueOptions::input_capacity` is reached.
+    pub fn request_future(
+        &mut self,
+        request: Request,
+    ) -> impl Future<Item = Response, Error = Error> {
+        if self.stopped {
+            return future::Either::A(future::err(ErrorKind::QueueStopped.into()));
+        }
+
+        let (id, cancellation_signal, cancellation) = self.register_cancellation();
+        let (sender, receiver) = oneshot::channel();
+
+        let sent = self
+            .input_command_sender
+            .try_send(InputCommand::Future(FutureCommand {
+                id,
+                cancellation_signal,
+                request,
+                sender,
+            }));
+        if let Err(e) = sent {
+            return future::Either::A(future::err(e));
+        }
+
+        future::Either::B(receiver.then(move |result| {
+            drop(cancellation);
+            match result {
+                Ok(result) => result,
+                // Working thread has stopped.
+                Err(_) => Err(ErrorKind::RequestCancelled.into()),
+            }
+        }))
+    }
+
+    /// Sends all `requests` with the single command to the working thread.
+    /// Returns cancellation handles in the order of `requests`.
+    /// When `ordered` is set, callbacks are called in the order of `requests`,
+    /// so the result of the request waits for the results of all earlier ones.
+    /// Batch takes one place of `QueueOptions::input_capacity`. Fails with `ErrorKind::QueueStopped`
+    /// and `ErrorKind::QueueFull` as `send_request` does, without calling any callback.
+    #[must_use = "these `RequestCancellation`s should be alive, because when they drop requests cancel."]
+    pub fn send_batch(
+        &mut self,
+        requests: Vec<(Request, Box<ResponseCallBack>)>,
+        ordered: bool,
+    ) -> Result<Vec<RequestCancellation>> {
+        if self.stopped {
+            bail!(ErrorKind::QueueStopped);
+        }
+
+        let (requests, mut callbacks): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+        if ordered {
+            callbacks = ordered::in_order(callbacks);
+        }
+
+        let mut cancellations = Vec::with_capacity(requests.len());
+        let commands: Vec<_> = requests
+            .into_iter()
+            .zip(callbacks)
+            .map(|(request, callback)| {
+                let (id, cancellation_signal, cancellation) = self.register_cancellation();
+                cancellations.push(cancellation);
+
+                RequestCommand {
+                    id,
+                    cancellation_signal,
+                    request,
+                    callback,
+                }
+            })
+            .collect();
+
+        let count = commands.len();
+        self.input_command_sender
+            .try_send(InputCommand::Batch(commands))?;
+        self.number_of_pending_requests += count;
+
+        Ok(cancellations)
+    }
+
+    fn register_cancellation(&mut self) -> (RequestId, oneshot::Receiver<()>, RequestCancellation) {
+        let (cancellation_signal_sender, cancellation_signal) = oneshot::channel();
+
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+
+        self.cancellation_senders
+            .lock()
+            .unwrap()
+            .insert(id, cancellation_signal_sender);
+
+        (
+            id,
+            cancellation_signal,
+            RequestCancellation {
+                id,
+                cancellation_senders: Arc::clone(&self.cancellation_senders),
+            },
+        )
+    }
+
+    /// Cancels request with the given id.
+    /// Returns `false` if request is unknown or was already completed.
+    pub fn cancel(&mut self, id: RequestId) -> bool {
+        self.cancellation_senders
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|sender| sender.send(()).is_ok())
+            .unwrap_or(false)
+    }
+
+    /// Stops dispatching of the new requests, until `resume` is called.
+    /// Requests sent while queue is paused are held and still counted as pending.
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.send_command(InputCommand::Pause);
+    }
+
+    /// Resumes dispatching and dispatches all requests held while queue was paused.
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.send_command(InputCommand::Resume);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Sends command of the single request, counting it as pending until its callback is called.
+    /// Fails with `ErrorKind::QueueFull`, when `QueueOptions::input_capacity` is reached.
+    /// Control commands, e.g. `Quit`, are sent with `send_command`.
+    fn send_request_command(&mut self, input_command: InputCommand) -> Result<()> {
+        self.input_command_sender.try_send(input_command)?;
+        self.number_of_pending_requests += 1;
+        Ok(())
+    }
+
+    /// Sends command without counting it as the pending request.
+    fn send_command(&self, input_command: InputCommand) {
+        // Working thread may be already finished, in that case command is just dropped.
+        self.input_command_sender.send(input_command);
+    }
+
+    fn try_recv_queue(&mut self) -> Result<()> {
+        let command = self.response_receiver.try_recv()?;
+        self.execute_output_command(command);
+
+        Ok(())
+    }
+
+    fn execute_output_command(&mut self, command: OutputCommand) {
+        if self.run_callbacks(command) {
+            self.number_of_pending_requests = self.number_of_pending_requests.saturating_sub(1);
+        }
+    }
+
+    /// Returns whether the request is completed, i.e. it isn't pending anymore.
+    fn run_callbacks(&mut self, command: OutputCommand) -> bool {
+        match command {
+            OutputCommand::Response { response, callback } => {
+                if let Some(hook) = &self.on_request {
+                    guarded(|| hook.call(&response.base_request));
+                }
+                if let Some(hook) = &self.on_response {
+                    guarded(|| hook.call(&response));
+                }
+                match response.base_request.options.fragment_size {
+                    Some(fragment_size) => {
+                        for fragment in response.into_fragments(std::cmp::max(fragment_size, 1)) {
+                            guarded(|| (callback)(Ok(fragment)));
+                        }
+                    }
+                    None => guarded(|| (callback)(Ok(response))),
+                }
+                self.completed_success += 1;
+            }
+            OutputCommand::Error { error, callback } => {
+                guarded(|| (callback)(Err(error)));
+                self.completed_error += 1;
+            }
+            OutputCommand::Bulk { results, callback } => {
+                for response in results.iter().flatten() {
+                    if let Some(hook) = &self.on_request {
+                        guarded(|| hook.call(&response.base_request));
+                    }
+                    if let Some(hook) = &self.on_response {
+                        guarded(|| hook.call(response));
+                    }
+                }
+                let errors = results.iter().filter(|result| result.is_err()).count() as u64;
+                self.completed_success += results.len() as u64 - errors;
+                self.completed_error += errors;
+                guarded(|| (callback)(results));
+            }
+            OutputCommand::Raw { result, callback } => {
+                if result.is_ok() {
+                    self.completed_success += 1;
+                } else {
+                    self.completed_error += 1;
+                }
+                guarded(|| (callback)(result));
+            }
+            OutputCommand::Progress {
+                transferred,
+                total,
+                callback,
+            } => {
+                // Request is still pending.
+                guarded(|| (callback)(transferred, total));
+                return false;
+            }
+            OutputCommand::DownloadProgress { progress, handler } => {
+                // Request is still pending.
+                guarded(|| handler.handle(progress));
+                return false;
+            }
+            OutputCommand::ServerSentEvent { event, callback } => {
+                let finished = match &event {
+                    Ok(Some(_)) => false,
+                    Ok(None) => {
+                        self.completed_success += 1;
+                        true
+                    }
+                    Err(_) => {
+                        self.completed_error += 1;
+                        true
+                    }
+                };
+                guarded(|| (callback)(event));
+                return finished;
+            }
+            OutputCommand::WebSocket { event, callback } => {
+                let finished = match &event {
+                    Ok(WebSocketEvent::Closed { .. }) => {
+                        self.completed_success += 1;
+                        true
+                    }
+                    Ok(_) => false,
+                    Err(_) => {
+                        self.completed_error += 1;
+                        true
+                    }
+                };
+                guarded(|| (callback)(event));
+                return finished;
+            }
+        }
+
+        true
+    }
+
+    /// Calls callbacks of at most `limit` responses, unless less than `delay_between_executions`
+    /// has passed since the previous call. Returns number of the processed responses.
+    pub fn execute_queue_with_limit(
+        &mut self,
+        limit: usize,
+        delay_between_executions: Duration,
+    ) -> usize {
+        if let Some(last_time) = self.last_time_executed_with_limit {
+            if Instant::now().duration_since(last_time) <= delay_between_executions {
+                return 0;
+            }
+        }
+
+        self.last_time_executed_with_limit = Some(Instant::now());
+
+        let mut counter = 0;
+        while counter < limit {
+            if self.try_recv_queue().is_err() {
+                break;
+            }
+            counter += 1;
+        }
+        counter
+    }
+
+    /// Same as `execute_queue_with_limit`, but takes all the ready responses at once
+    /// and calls their callbacks without the delay. Returns number of the processed responses.
+    pub fn drain_queue_with_limit(&mut self, limit: usize) -> usize {
+        let commands: Vec<_> = self.response_receiver.try_iter().take(limit).collect();
+        let count = commands.len();
+
+        let mut completed = 0;
+        for command in commands {
+            if self.run_callbacks(command) {
+                completed += 1;
+            }
+        }
+        self.number_of_pending_requests = self.number_of_pending_requests.saturating_sub(completed);
+
+        count
+    }
+
+    /// Same as `drain_queue_with_limit`, but also reports how the processed requests completed.
+    pub fn execute(&mut self, limit: usize) -> ExecuteStats {
+        let (successes, errors) = (self.completed_success, self.completed_error);
+        let processed = self.drain_queue_with_limit(limit);
+
+        ExecuteStats {
+            processed,
+            remaining_pending: self.number_of_pending_requests,
+            errors: self.completed_error - errors,
+            successes: self.completed_success - successes,
+        }
+    }
+
+    pub fn execute_query_with_timeout(&mut self, timeout: Duration, one_step_timeout: Duration) {
+        let instant = Instant::now();
+
+        while Instant::now().duration_since(instant) <= timeout {
+            self.try_recv_queue().ok();
+            thread::sleep(one_step_timeout);
+        }
+    }
+
+    pub fn number_of_pending_requests(&self) -> usize {
+        self.number_of_pending_requests
+    }
+
+    /// Total number of requests, which responses were delivered to the callbacks.
+    /// Every request of the bulk is counted separately.
+    pub fn number_of_completed_requests(&self) -> u64 {
+        self.completed_success + self.completed_error
+    }
+
+    /// Number of the completed requests, which callbacks received the response.
+    pub fn completed_success(&self) -> u64 {
+        self.completed_success
+    }
+
+    /// Number of the completed requests, which callbacks received the error.
+    pub fn completed_error(&self) -> u64 {
+        self.completed_error
+    }
+
+    /// Parses the absolute `uri`, or resolves the relative one, e.g. `/api/v1/players`,
+    /// against `QueueOptions::base_url`. Fails with `ErrorKind::RelativeUriWithoutBase`,
+    /// when the base isn't set.
+    pub fn resolve_uri(&self, uri: &str) -> Result<url::Url> {
+        let invalid = || ErrorKind::RequestBuildError(format!("invalid URI '{}'", uri));
+
+        match url::Url::parse(uri) {
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                let base = self
+                    .base_url
+                    .as_ref()
+                    .ok_or_else(|| ErrorKind::RelativeUriWithoutBase(uri.to_owned()))?;
+                url::Url::parse(&base.to_string())
+                    .and_then(|base| base.join(uri))
+                    .chain_err(invalid)
+            }
+            result => result.chain_err(invalid),
+        }
+    }
+
+    /// Forgets all cached DNS answers.
+    pub fn flush_dns_cache(&self) {
+        self.resolver.flush();
+    }
+
+    /// Forgets all cached responses. Does nothing, when caching is disabled.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.response_cache {
+            cache.clear();
+        }
+    }
+
+    /// Replaces the `SystemResolver` with the one using `threads` threads.
+    /// Applies to the lookups started afterwards, in-flight requests aren't affected.
+    /// Cached DNS answers are kept. Fails with `ErrorKind::InvalidOptions`, when `threads` is zero
+    /// or `QueueOptions::resolver` is set, which is kept then.
+    pub fn set_dns_threads(&self, threads: usize) -> Result<()> {
+        let mut config = self.config.lock().unwrap();
+        if threads == 0 {
+            bail!(ErrorKind::InvalidOptions(
+                "number of DNS threads must be positive".to_owned()
+            ));
+        }
+        if config.custom_resolver {
+            bail!(ErrorKind::InvalidOptions(
+                "custom resolver can't be replaced".to_owned()
+            ));
+        }
+
+        self.resolver
+            .set_resolver(Arc::new(SystemResolver::new(threads)));
+        config.number_of_dns_threads = Some(threads);
+
+        Ok(())
+    }
+
+    /// Configuration currently applied to the queue.
+    pub fn config(&self) -> QueueConfigSnapshot {
+        let mut config = self.config.lock().unwrap().clone();
+        config.paused = self.paused;
+        config
+    }
+
+    /// Current state of the queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics::snapshot(&self.traffic, &self.cancellation_senders)
+    }
+
+    /// ID, URI and elapsed time of every request, which is executed by the working thread,
+    /// including requests waiting for a free slot. Ordered by ID.
+    /// Every started request of the bulk is listed under the ID of the bulk. WebSockets and event streams
+    /// are listed as long as they are open. Requests coalesced with the identical one aren't listed.
+    pub fn inflight_requests(&self) -> Vec<(RequestId, hyper::Uri, Duration)> {
+        self.executing.snapshot()
+    }
+
+    /// Calls `reporter` with the `QueueMetrics` every `interval`, until the queue is stopped.
+    /// `reporter` is called on the working thread. Replaces the previously set reporter.
+    /// Fails with `ErrorKind::InvalidOptions`, when `interval` is zero.
+    pub fn set_metrics_reporter<F>(&mut self, interval: Duration, reporter: F) -> Result<()>
+    where
+        F: Fn(&QueueMetrics) + Send + Sync + 'static,
+    {
+        if interval == Duration::from_secs(0) {
+            bail!(ErrorKind::InvalidOptions(
+                "metrics interval must be positive".to_owned()
+            ));
+        }
+
+        self.config.lock().unwrap().metrics_interval = Some(interval);
+        self.send_command(InputCommand::MetricsReporter {
+            interval,
+            reporter: Box::new(reporter),
+        });
+
+        Ok(())
+    }
+
+    /// Total number of request body bytes sent during the lifetime of the queue.
+    pub fn bytes_sent(&self) -> u64 {
+        self.traffic.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Total number of response body bytes received during the lifetime of the queue.
+    pub fn bytes_received(&self) -> u64 {
+        self.traffic.bytes_received.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_basic_request() {
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let mut queue = Queue::new().unwrap();
+
+        use std::default::Default;
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri("https://docs.rs/".parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+                    assert!(String::from_utf8_lossy(&req.unwrap().body[..]).contains("docs.rs"));
+                },
+            )
+            .unwrap();
+
+        assert_eq!(*control_variable.lock().unwrap(), false);
+
+        queue.execute_query_with_timeout(Duration::from_secs(5), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_cancelling() {
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let mut queue = Queue::new().unwrap();
+
+        use std::default::Default;
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri("https://docs.rs/".parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+
+                    match req {
+                        Ok(_) => {
+                            unreachable!();
+                        }
+                        Err(e) => match e.kind() {
+                            ErrorKind::RequestCancelled => {}
+                            _ => unreachable!(),
+                        },
+                    };
+                },
+            )
+            .unwrap();
+
+        assert_eq!(*control_variable.lock().unwrap(), false);
+
+        drop(handle);
+
+        queue.execute_query_with_timeout(Duration::from_secs(5), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_timeout() {
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let mut queue = Queue::new().unwrap();
+
+        use std::default::Default;
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .timeout(Some(Duration::new(0, 0)))
+                            .build()
+                            .unwrap(),
+                    )
+                    .uri("https://docs.rs/".parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+
+                    match req {
+                        Ok(_) => {
+                            unreachable!();
+                        }
+                        Err(e) => match e.kind() {
+                            ErrorKind::RequestTimeout => {}
+                            _ => unreachable!(),
+                        },
+                    };
+                },
+            )
+            .unwrap();
+
+        assert_eq!(*control_variable.lock().unwrap(), false);
+
+        queue.execute_query_with_timeout(Duration::from_secs(5), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_cancel_by_id() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_secs(2));
+            response("200 OK", &[], b"late")
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+
+                    match req {
+                        Ok(_) => unreachable!(),
+                        Err(e) => match e.kind() {
+                            ErrorKind::RequestCancelled => {}
+                            _ => unreachable!(),
+                        },
+                    };
+                },
+            )
+            .unwrap();
+
+        assert!(queue.cancel(handle.id()));
+        assert!(!queue.cancel(handle.id()));
+        assert!(!queue.cancel(handle.id() + 100));
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::with_body("resumed");
+
+        let mut queue = Queue::new().unwrap();
+        queue.pause();
+        assert!(queue.is_paused());
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+                    assert_eq!(&req.unwrap().body[..], b"resumed");
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), false);
+        assert_eq!(queue.number_of_pending_requests(), 1);
+        assert!(server.requests().is_empty());
+
+        queue.resume();
+        assert!(!queue.is_paused());
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_bulk_request() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        // Currently and maximally in flight requests.
+        let in_flight = Arc::new(Mutex::new((0, 0)));
+
+        let server = {
+            let in_flight = Arc::clone(&in_flight);
+            TestServer::new(move |request| {
+                {
+                    let mut in_flight = in_flight.lock().unwrap();
+                    in_flight.0 += 1;
+                    in_flight.1 = std::cmp::max(in_flight.0, in_flight.1);
+                }
+                thread::sleep(Duration::from_millis(100));
+                in_flight.lock().unwrap().0 -= 1;
+
+                let path = request.head.split(' ').nth(1).unwrap().to_owned();
+                response("200 OK", &[], path.as_bytes())
+            })
+        };
+
+        let mut queue = Queue::new().unwrap();
+
+        let results = Arc::new(Mutex::new(None));
+        let results_c = Arc::clone(&results);
+        let _handle = queue
+            .send_bulk_request(
+                (0..10).collect(),
+                |id: &usize| {
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(&format!("/jobs/{}", id)))
+                        .build()
+                        .unwrap()
+                },
+                3,
+                move |results| {
+                    *results_c.lock().unwrap() = Some(results);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+
+        let results = results.lock().unwrap().take().unwrap();
+        assert_eq!(results.len(), 10);
+        for (expected_id, (id, response)) in results.into_iter().enumerate() {
+            assert_eq!(id, expected_id);
+            assert_eq!(
+                String::from_utf8(response.unwrap().body_vec()).unwrap(),
+                format!("/jobs/{}", id)
+            );
+        }
+
+        assert!(in_flight.lock().unwrap().1 <= 3);
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_shutdown_graceful() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(300));
+            response("200 OK", &[], b"finished")
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+                    assert_eq!(&req.unwrap().body[..], b"finished");
+                },
+            )
+            .unwrap();
+
+        queue.shutdown_graceful(Duration::from_secs(5));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_shutdown_graceful_timeout() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_secs(3));
+            response("200 OK", &[], b"finished")
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+
+                    match req {
+                        Ok(_) => unreachable!(),
+                        Err(e) => match e.kind() {
+                            ErrorKind::RequestCancelled => {}
+                            _ => unreachable!(),
+                        },
+                    };
+                },
+            )
+            .unwrap();
+
+        queue.shutdown_graceful(Duration::from_millis(200));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_response_timings() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::with_body("timings");
+
+        let mut queue = Queue::new().unwrap();
+
+        let timings = Arc::new(Mutex::new(None));
+        let timings_c = Arc::clone(&timings);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *timings_c.lock().unwrap() = Some(req.unwrap().timings);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        let timings = timings.lock().unwrap().take().unwrap();
+        let request_written = timings.request_written.unwrap();
+        let first_body_byte = timings.first_body_byte.unwrap();
+        let last_body_byte = timings.last_body_byte.unwrap();
+
+        assert!(timings.started <= request_written);
+        assert!(request_written <= timings.head_received);
+        assert!(timings.head_received <= first_body_byte);
+        assert!(first_body_byte <= last_body_byte);
+    }
+
+    #[test]
+    fn test_traffic_counters() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("0123456789");
+
+        let mut queue = Queue::new().unwrap();
+        assert_eq!(queue.bytes_sent(), 0);
+        assert_eq!(queue.bytes_received(), 0);
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Post)
+                            .uri(server.url("/"))
+                            .body(b"payload".to_vec())
+                            .build()
+                            .unwrap(),
+                        |req| {
+                            req.unwrap();
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(queue.number_of_pending_requests(), 0);
+        assert!(queue.bytes_sent() >= 2 * 7);
+        assert!(queue.bytes_received() >= 2 * 10);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_retry_connection_refused() {
+        use super::test_server::*;
+        use super::*;
+        use std::net::TcpListener;
+        use std::sync::{Arc, Mutex};
+
+        // Nobody listens on the address, until server is started after the first attempts.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            TestServer::bind(address, |_| response("200 OK", &[], b"retried"))
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(format!("http://{}/", address).parse().unwrap())
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .retry_policy(
+                                RetryPolicyBuilder::default()
+                                    .max_retries(20)
+                                    .delay(Duration::from_millis(100))
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+                    assert_eq!(&req.unwrap().body[..], b"retried");
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+        assert_eq!(server.join().unwrap().requests().len(), 1);
+    }
+
+    #[test]
+    fn test_no_retry_on_request_timeout() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"late")
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .timeout(Some(Duration::from_millis(100)))
+                            .retry_policy(
+                                RetryPolicyBuilder::default()
+                                    .max_retries(3)
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+
+                    match req {
+                        Ok(_) => unreachable!(),
+                        Err(e) => match e.kind() {
+                            ErrorKind::RequestTimeout => {}
+                            _ => unreachable!(),
+                        },
+                    };
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_response_elapsed() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(50));
+            response("200 OK", &[], b"slow")
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let elapsed = Arc::new(Mutex::new(None));
+        let elapsed_c = Arc::clone(&elapsed);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *elapsed_c.lock().unwrap() = Some(req.unwrap().elapsed);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        let elapsed = elapsed.lock().unwrap().take().unwrap();
+        assert!(elapsed >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_transfer_decoder() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        // Server applies chunked coding twice, `hyper` removes only the outer one.
+        let server = TestServer::new(|_| {
+            let inner = b"4\r\ngRIP\r\n6\r\n works\r\n0\r\n\r\n";
+            let mut raw =
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, chunked\r\n\r\n".to_vec();
+            raw.extend_from_slice(format!("{:x}\r\n", inner.len()).as_bytes());
+            raw.extend_from_slice(inner);
+            raw.extend_from_slice(b"\r\n0\r\n\r\n");
+            raw
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let body = Arc::new(Mutex::new(None));
+        let body_c = Arc::clone(&body);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .transfer_decoder(Some(TransferDecoder::chunked()))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *body_c.lock().unwrap() = Some(req.unwrap().body);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(&body.lock().unwrap().take().unwrap()[..], b"gRIP works");
+    }
+
+    #[test]
+    fn test_response_reason() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| response("404 Not Found", &[], b""));
+
+        let mut queue = Queue::new().unwrap();
+
+        let reason = Arc::new(Mutex::new(None));
+        let reason_c = Arc::clone(&reason);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/missing"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *reason_c.lock().unwrap() = Some(req.unwrap().reason);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(
+            reason.lock().unwrap().take().unwrap(),
+            Some("Not Found".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_retry_attempts() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        // First two requests to every path time out.
+        let server = TestServer::new({
+            let counter = Mutex::new(FnvHashMap::default());
+            move |request| {
+                let path = request.head.split(' ').nth(1).unwrap().to_owned();
+                let count = {
+                    let mut counter = counter.lock().unwrap();
+                    let count = counter.entry(path).or_insert(0);
+                    *count += 1;
+                    *count
+                };
+
+                if count <= 2 {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                response("200 OK", &[], b"")
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let request = |path, max_retries| {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url(path))
+                .options(
+                    RequestOptionsBuilder::default()
+                        .timeout(Some(Duration::from_millis(100)))
+                        .retry_policy(
+                            RetryPolicyBuilder::default()
+                                .max_retries(max_retries)
+                                .retry_on(vec![RetryableError::RequestTimeout])
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap()
+        };
+
+        let attempts = Arc::new(Mutex::new(vec![]));
+
+        let attempts_c = Arc::clone(&attempts);
+        let _succeeded = queue
+            .send_request(request("/succeeded", 5), move |req| {
+                attempts_c.lock().unwrap().push(req.unwrap().attempts);
+            })
+            .unwrap();
+
+        let attempts_c = Arc::clone(&attempts);
+        let _failed = queue
+            .send_request(request("/failed", 1), move |req| {
+                match req.err().unwrap().kind() {
+                    ErrorKind::RetriesExhausted(attempts) => {
+                        attempts_c.lock().unwrap().push(*attempts)
+                    }
+                    _ => unreachable!(),
+                }
+            })
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+
+        let mut attempts = attempts.lock().unwrap().clone();
+        attempts.sort();
+        assert_eq!(attempts, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_http2_prior_knowledge() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        // Server speaks only HTTP/1.1, so it just records the connection preface.
+        let server = TestServer::new(|_| vec![]);
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .http2_prior_knowledge(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let control_variable = Arc::new(Mutex::new(false));
+        let control_variable_c = Arc::clone(&control_variable);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .timeout(Some(Duration::from_millis(300)))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *control_variable_c.lock().unwrap() = true;
+                    assert!(req.is_err());
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(*control_variable.lock().unwrap(), true);
+        assert!(server.requests()[0].head.starts_with("PRI * HTTP/2.0"));
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"slow")
+        });
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .max_concurrent_requests(Some(1))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let results = Arc::clone(&results);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .acquire_timeout(Some(Duration::from_millis(100)))
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |req| {
+                            results.lock().unwrap().push(match req {
+                                Ok(response) => Ok(response.body_vec()),
+                                Err(e) => match e.kind() {
+                                    ErrorKind::AcquireTimeout => Err(()),
+                                    _ => unreachable!(),
+                                },
+                            });
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![Err(()), Ok(b"slow".to_vec())]
+        );
+        assert_eq!(server.requests().len(), 1);
+
+        drop(handles);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let directory = tempfile::tempdir().unwrap();
+        let socket_path = directory.path().join("grip.sock");
+        let server = TestServer::bind_unix(&socket_path, |request| {
+            response(
+                "200 OK",
+                &[],
+                request.head.lines().next().unwrap().as_bytes(),
+            )
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let body = Arc::new(Mutex::new(None));
+        let body_c = Arc::clone(&body);
+        let _handle = queue
+            .send_request_uds(
+                &socket_path,
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri("http://localhost/stats?full=1".parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *body_c.lock().unwrap() = Some(req.unwrap().body);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(
+            &body.lock().unwrap().take().unwrap()[..],
+            b"GET /stats?full=1 HTTP/1.1"
+        );
+        assert_eq!(server.requests()[0].header("Host"), Some("localhost"));
+    }
+
+    #[test]
+    fn test_dns_cache() {
+        use super::dns::tests::StubResolver;
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::Ordering;
+
+        // Every request needs new connection, so pooled connections don't hide lookups.
+        let server = TestServer::new(|_| response("200 OK", &[("Connection", "close")], b"cached"));
+        let resolver = Arc::new(StubResolver::default());
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .resolver(Some(resolver.clone() as Arc<dyn Resolver>))
+                .dns_cache_ttl(Some(Duration::from_secs(60)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let url: url::Url = format!("http://stub.test:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        for expected_lookups in &[1, 1, 2] {
+            if *expected_lookups == 2 {
+                queue.flush_dns_cache();
+            }
+
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(url.clone())
+                        .build()
+                        .unwrap(),
+                    |req| assert_eq!(&req.unwrap().body[..], b"cached"),
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+
+            assert_eq!(resolver.lookups.load(Ordering::SeqCst), *expected_lookups);
+        }
+
+        assert_eq!(
+            server.requests()[0].header("Host"),
+            Some(&*format!("stub.test:{}", server.address().port()))
+        );
+    }
+
+    #[test]
+    fn test_set_dns_threads() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[("Connection", "close")], b"ok"));
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .number_of_dns_threads(Some(1))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let url: url::Url = format!("http://localhost:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        for threads in &[8, 2] {
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(url.clone())
+                        .build()
+                        .unwrap(),
+                    |req| assert_eq!(&req.unwrap().body[..], b"ok"),
+                )
+                .unwrap();
+
+            queue.set_dns_threads(*threads).unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_dns_threads() {
+        use super::dns::tests::StubResolver;
+        use super::*;
+
+        let options = |threads, resolver: Option<Arc<dyn Resolver>>| {
+            QueueOptionsBuilder::default()
+                .number_of_dns_threads(Some(threads))
+                .resolver(resolver)
+                .build()
+                .unwrap()
+        };
+
+        match Queue::with_options(options(0, None)) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            _ => panic!("zero DNS threads should be rejected"),
+        }
+
+        let queue = Queue::with_options(options(1, None)).unwrap();
+        match queue.set_dns_threads(0) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(queue.config().number_of_dns_threads, Some(1));
+
+        // Thread count is ignored with the custom resolver, which isn't replaced.
+        let queue =
+            Queue::with_options(options(0, Some(Arc::new(StubResolver::default())))).unwrap();
+        match queue.set_dns_threads(2) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        let config = queue.config();
+        assert!(config.custom_resolver);
+        assert_eq!(config.number_of_dns_threads, None);
+    }
+
+    #[test]
+    fn test_redirect_followed() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("POST /old ") {
+                response("303 See Other", &[("Location", "/new")], b"")
+            } else {
+                response(
+                    "200 OK",
+                    &[],
+                    request.head.lines().next().unwrap().as_bytes(),
+                )
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let body = Arc::new(Mutex::new(None));
+        let body_c = Arc::clone(&body);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(server.url("/old"))
+                    .body(b"payload".to_vec())
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *body_c.lock().unwrap() = Some(req.unwrap().body);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(
+            &body.lock().unwrap().take().unwrap()[..],
+            b"GET /new HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_gzip_response() {
+        use super::test_server::*;
+        use super::*;
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|request| {
+            assert_eq!(request.header("Accept-Encoding"), Some("gzip"));
+
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(b"decompressed").unwrap();
+            response(
+                "200 OK",
+                &[("Content-Encoding", "gzip")],
+                &encoder.finish().unwrap(),
+            )
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let body = Arc::new(Mutex::new(None));
+        let body_c = Arc::clone(&body);
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    *body_c.lock().unwrap() = Some(req.unwrap().body);
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(&body.lock().unwrap().take().unwrap()[..], b"decompressed");
+    }
+
+    #[test]
+    fn test_body_strategy_selector() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /json ") {
+                response("200 OK", &[("Content-Type", "application/json")], b"{}")
+            } else {
+                response(
+                    "200 OK",
+                    &[("Content-Type", "application/octet-stream")],
+                    b"binary",
+                )
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let selector =
+            BodyStrategySelector::new(|headers, _| match headers.get(header::CONTENT_TYPE) {
+                Some(content_type) if content_type == "application/octet-stream" => {
+                    BodyStrategy::SpillToFile { threshold: 0 }
+                }
+                _ => BodyStrategy::Buffer,
+            });
+
+        let responses = Arc::new(Mutex::new(vec![]));
+        let handles: Vec<_> = ["/json", "/binary"]
+            .iter()
+            .map(|path| {
+                let responses = Arc::clone(&responses);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url(path))
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .body_strategy_selector(Some(selector.clone()))
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |req| {
+                            let response = req.unwrap();
+                            let spilled = response.spilled_body.is_some();
+
+                            let mut body = vec![];
+                            response
+                                .body_reader()
+                                .unwrap()
+                                .read_to_end(&mut body)
+                                .unwrap();
+
+                            responses.lock().unwrap().push((
+                                response.base_request.uri.path().to_owned(),
+                                spilled,
+                                body,
+                            ));
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        let mut responses = responses.lock().unwrap().clone();
+        responses.sort();
+        assert_eq!(
+            responses,
+            vec![
+                ("/binary".to_owned(), true, b"binary".to_vec()),
+                ("/json".to_owned(), false, b"{}".to_vec()),
+            ]
+        );
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_metrics_reporter() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::{Arc, Mutex};
+
+        let server = TestServer::with_body("metrics");
+        let mut queue = Queue::new().unwrap();
+
+        let snapshots = Arc::new(Mutex::new(vec![]));
+        {
+            let snapshots = Arc::clone(&snapshots);
+            queue
+                .set_metrics_reporter(Duration::from_millis(20), move |metrics| {
+                    snapshots.lock().unwrap().push(*metrics)
+                })
+                .unwrap();
+        }
+
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"metrics"),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_millis(300), Duration::from_millis(10));
+        assert_eq!(queue.number_of_pending_requests(), 0);
+
+        {
+            let snapshots = snapshots.lock().unwrap();
+            assert!(snapshots.len() >= 3, "{:?}", *snapshots);
+            assert!(snapshots
+                .windows(2)
+                .all(|w| w[0].bytes_received <= w[1].bytes_received));
+            assert!(snapshots.iter().all(|m| m.in_flight_requests <= 1));
+        }
+
+        assert_eq!(
+            queue.metrics(),
+            QueueMetrics {
+                bytes_sent: 0,
+                bytes_received: 7,
+                in_flight_requests: 0,
+            }
+        );
+
+        match queue.set_metrics_reporter(Duration::from_secs(0), |_| {}) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        queue.stop();
+        let reported = snapshots.lock().unwrap().len();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(snapshots.lock().unwrap().len(), reported);
+    }
+
+    #[test]
+    fn test_address_family() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        /// Resolves every host into the discard-only IPv6 address and the IPv4 loopback.
+        #[derive(Debug)]
+        struct DualStackResolver;
+
+        impl Resolver for DualStackResolver {
+            fn resolve(&self, _host: &str) -> ResolveFuture {
+                Box::new(future::ok(vec![
+                    "100::1".parse().unwrap(),
+                    "127.0.0.1".parse().unwrap(),
+                ]))
+            }
+        }
+
+        let server = TestServer::with_body("v4");
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .resolver(Some(Arc::new(DualStackResolver) as Arc<dyn Resolver>))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let dual_stack = format!("http://dual.test:{}/", server.address().port());
+        for (uri, family) in &[
+            (dual_stack.as_str(), AddressFamily::V4Only),
+            ("http://127.0.0.1:1/", AddressFamily::V6Only),
+        ] {
+            let results = Arc::clone(&results);
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(uri.parse().unwrap())
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .address_family(*family)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        results.lock().unwrap().push(match req {
+                            Ok(response) => Ok(response.body_vec()),
+                            Err(e) => Err(e.to_string()),
+                        })
+                    },
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![
+                Ok(b"v4".to_vec()),
+                Err("No address of the allowed family for 127.0.0.1".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        use super::test_server::*;
+        use super::*;
+        use serde_json::json;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            let body: &[u8] = if request.head.starts_with("GET /good ") {
+                br#"{"id": 1}"#
+            } else {
+                br#"{"id": "1"}"#
+            };
+            response("200 OK", &[("Content-Type", "application/json")], body)
+        });
+
+        let schema = JsonSchema::compile(&json!({
+            "type": "object",
+            "required": ["id"],
+            "properties": { "id": { "type": "integer" } }
+        }))
+        .unwrap();
+
+        let mut queue = Queue::new().unwrap();
+        let results = Arc::new(Mutex::new(vec![]));
+
+        for path in &["/good", "/bad"] {
+            let results = Arc::clone(&results);
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .json_schema(Some(schema.clone()))
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        results.lock().unwrap().push(match req {
+                            Ok(response) => Ok(response.body_vec()),
+                            Err(e) => match e.kind() {
+                                ErrorKind::SchemaValidation(errors) => Err(errors.clone()),
+                                _ => unreachable!(),
+                            },
+                        })
+                    },
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![
+                Ok(br#"{"id": 1}"#.to_vec()),
+                Err(vec!["/id: expected integer, found string".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_on_status() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        let served = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let served = Arc::clone(&served);
+            TestServer::new(move |_| {
+                if served.fetch_add(1, Ordering::SeqCst) == 0 {
+                    response("503 Service Unavailable", &[("Retry-After", "1")], b"busy")
+                } else {
+                    response("200 OK", &[], b"ready")
+                }
+            })
+        };
+
+        let mut queue = Queue::new().unwrap();
+        let results = Arc::new(Mutex::new(vec![]));
+        let started = Instant::now();
+
+        let _handle = {
+            let results = Arc::clone(&results);
+            queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .retry_policy(
+                                    RetryPolicyBuilder::default()
+                                        .max_retries(2)
+                                        .retry_on_status(vec![
+                                            hyper::StatusCode::TOO_MANY_REQUESTS,
+                                            hyper::StatusCode::SERVICE_UNAVAILABLE,
+                                        ])
+                                        .build()
+                                        .unwrap(),
+                                )
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        let response = req.unwrap();
+                        results.lock().unwrap().push((
+                            response.status_code,
+                            response.body_vec(),
+                            response.attempts,
+                        ));
+                    },
+                )
+                .unwrap()
+        };
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![(hyper::StatusCode::OK, b"ready".to_vec(), 2)]
+        );
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_malformed_response() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            let path = request.head.split(' ').nth(1).unwrap();
+            let (head, body) = match path {
+                "/conflicting" => ("Content-Length: 2\r\nContent-Length: 4\r\n", "ok"),
+                "/identical" => ("Content-Length: 2\r\nContent-Length: 2\r\n", "ok"),
+                _ => (
+                    "Content-Length: 2\r\nTransfer-Encoding: chunked\r\n",
+                    "2\r\nok\r\n0\r\n\r\n",
+                ),
+            };
+            format!("HTTP/1.1 200 OK\r\n{}\r\n{}", head, body).into_bytes()
+        });
+
+        let mut queue = Queue::new().unwrap();
+        let results = Arc::new(Mutex::new(vec![]));
+
+        for (path, allow_identical) in &[
+            ("/conflicting", true),
+            ("/identical", false),
+            ("/both", false),
+            ("/identical", true),
+        ] {
+            let results = Arc::clone(&results);
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .allow_identical_content_lengths(*allow_identical)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        results.lock().unwrap().push(match req {
+                            Ok(response) => Some(response.body_vec()),
+                            Err(e) => match e.kind() {
+                                ErrorKind::MalformedResponse(_) => None,
+                                _ => unreachable!(),
+                            },
+                        })
+                    },
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![None, None, None, Some(b"ok".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_duplicates() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(100));
+            response("200 OK", &[], b"shared")
+        });
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .coalesce_duplicates(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let bodies = Arc::clone(&bodies);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/poll"))
+                            .build()
+                            .unwrap(),
+                        move |req| bodies.lock().unwrap().push(req.unwrap().body),
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(*bodies.lock().unwrap(), vec![b"shared".to_vec(); 3]);
+        assert_eq!(server.requests().len(), 1);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_coalesced_request_cancelled() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(300));
+            response("200 OK", &[], b"shared")
+        });
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .coalesce_duplicates(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let mut handles: Vec<_> = (0..2)
+            .map(|index| {
+                let results = Arc::clone(&results);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/poll"))
+                            .build()
+                            .unwrap(),
+                        move |req| {
+                            let result = match req {
+                                Ok(response) => Ok(response.body),
+                                Err(e) => Err(e.to_string()),
+                            };
+                            results.lock().unwrap().push((index, result))
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        // Waiter is attached, once the worker has received both requests.
+        thread::sleep(Duration::from_millis(100));
+        let waiter = handles.pop().unwrap();
+        assert!(queue.cancel(waiter.id()));
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let results = results.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0],
+            (1, Err(Error::from(ErrorKind::RequestCancelled).to_string()))
+        );
+        assert_eq!(results[1], (0, Ok(Bytes::from_static(b"shared"))));
+        assert_eq!(server.requests().len(), 1);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_multipart_stream() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|_| {
+            response(
+                "200 OK",
+                &[("Content-Type", "multipart/x-mixed-replace; boundary=frame")],
+                b"--frame\r\nContent-Type: text/plain\r\n\r\nfirst\r\n\
+                  --frame\r\nContent-Type: application/json\r\n\r\n{}\r\n\
+                  --frame--\r\n",
+            )
+        });
+
+        let parts = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let parts = Arc::clone(&parts);
+            PartHandler::new(move |part| {
+                parts.lock().unwrap().push((
+                    part.headers[header::CONTENT_TYPE]
+                        .to_str()
+                        .unwrap()
+                        .to_owned(),
+                    part.body,
+                ))
+            })
+        };
+
+        let mut queue = Queue::new().unwrap();
+        let completed = Arc::new(Mutex::new(false));
+        let _handle = {
+            let completed = Arc::clone(&completed);
+            queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/stream"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .body_strategy(BodyStrategy::Multipart(handler))
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        assert!(req.unwrap().body.is_empty());
+                        *completed.lock().unwrap() = true;
+                    },
+                )
+                .unwrap()
+        };
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert!(*completed.lock().unwrap());
+        assert_eq!(
+            *parts.lock().unwrap(),
+            vec![
+                ("text/plain".to_owned(), b"first".to_vec()),
+                ("application/json".to_owned(), b"{}".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(50));
+            response("200 OK", &[], b"")
+        });
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .max_concurrent_requests(Some(1))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        // First request occupies the only slot, the rest are waiting for it.
+        let handles: Vec<_> = [
+            ("/first", 0),
+            ("/logging", 0),
+            ("/auth", 200),
+            ("/logging2", 0),
+            ("/stats", 100),
+        ]
+        .iter()
+        .map(|(path, priority)| {
+            queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .priority(*priority)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    |req| {
+                        req.unwrap();
+                    },
+                )
+                .unwrap()
+        })
+        .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let paths: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|request| request.head.split(' ').nth(1).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["/first", "/auth", "/stats", "/logging", "/logging2"]
+        );
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_trailing_data_retires_connection() {
+        use super::test_server::*;
+        use super::*;
+
+        for (trailing, reject, expected_connections) in &[
+            (&b""[..], false, 1),
+            (&b"GARBAGE"[..], false, 2),
+            (&b""[..], true, 1),
+            (&b"GARBAGE"[..], true, 2),
+        ] {
+            let trailing = trailing.to_vec();
+            let malformed = *reject && !trailing.is_empty();
+            let server = TestServer::new(move |_| {
+                let mut raw = response("200 OK", &[], b"ok");
+                raw.extend_from_slice(&trailing);
+                raw
+            });
+            let mut queue = Queue::with_options(
+                QueueOptionsBuilder::default()
+                    .reject_trailing_data(*reject)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+            for _ in 0..2 {
+                let _handle = queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        move |req| match req {
+                            Err(Error(ErrorKind::MalformedResponse(_), _)) if malformed => {}
+                            Ok(response) if !malformed => assert_eq!(&response.body[..], b"ok"),
+                            _ => panic!("unexpected result {:?}", req.map(|r| r.status_code)),
+                        },
+                    )
+                    .unwrap();
+
+                while queue.number_of_pending_requests() > 0 {
+                    queue.execute_query_with_timeout(
+                        Duration::from_millis(50),
+                        Duration::from_millis(10),
+                    );
+                }
+            }
+
+            assert_eq!(server.connections(), *expected_connections);
+        }
+    }
+
+    #[test]
+    fn test_invalid_rate_limit() {
+        use super::*;
+
+        let options = QueueOptionsBuilder::default()
+            .rate_limit(Some(RateLimit {
+                max_requests: 0,
+                per: Duration::from_secs(1),
+            }))
+            .build()
+            .unwrap();
+
+        match Queue::with_options(options) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            _ => panic!("zero max_requests should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("limited");
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .rate_limit(Some(RateLimit {
+                    max_requests: 2,
+                    per: Duration::from_secs(1),
+                }))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let started = Instant::now();
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        |req| assert_eq!(&req.unwrap().body[..], b"limited"),
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        // Two requests start right away, the rest one per half of a second.
+        assert!(started.elapsed() >= Duration::from_millis(1900));
+        assert_eq!(server.requests().len(), 6);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_success_predicate() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /ok ") {
+                response("200 OK", &[], br#"{"status": "ok"}"#)
+            } else {
+                response("200 OK", &[], br#"{"status": "error"}"#)
+            }
+        });
+
+        let predicate = SuccessPredicate::new(|response| {
+            serde_json::from_slice::<serde_json::Value>(&response.body)
+                .map(|body| body["status"] == "ok")
+                .unwrap_or(false)
+        });
+
+        let mut queue = Queue::new().unwrap();
+        let results = Arc::new(Mutex::new(vec![]));
+
+        for path in &["/ok", "/fail"] {
+            let results = Arc::clone(&results);
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .success_predicate(Some(predicate.clone()))
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        results.lock().unwrap().push(match req {
+                            Ok(response) => Ok(response.body_vec()),
+                            Err(e) => match e.kind() {
+                                ErrorKind::RequestFailed(response) => Err(response.body_vec()),
+                                _ => unreachable!(),
+                            },
+                        })
+                    },
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![
+                Ok(br#"{"status": "ok"}"#.to_vec()),
+                Err(br#"{"status": "error"}"#.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upload_progress() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            response("200 OK", &[], request.body.len().to_string().as_bytes())
+        });
+
+        let body = vec![7; 1024 * 1024];
+        let progress = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = {
+            let progress = Arc::clone(&progress);
+            queue
+                .send_request_with_progress(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Post)
+                        .uri(server.url("/upload"))
+                        .body(body.clone())
+                        .build()
+                        .unwrap(),
+                    move |transferred, total| progress.lock().unwrap().push((transferred, total)),
+                    |req| assert_eq!(&req.unwrap().body[..], b"1048576"),
+                )
+                .unwrap()
+        };
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let progress = progress.lock().unwrap();
+        assert!(progress.len() > 1);
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(progress
+            .iter()
+            .all(|(_, total)| *total == body.len() as u64));
+        assert_eq!(
+            progress.last(),
+            Some(&(body.len() as u64, body.len() as u64))
+        );
+        assert_eq!(
+            server.requests()[0].header("Content-Length"),
+            Some("1048576")
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        use super::*;
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .max_concurrent_requests(Some(3))
+                .dns_cache_ttl(Some(Duration::from_secs(30)))
+                .coalesce_duplicates(true)
+                .rate_limit(Some(RateLimit {
+                    max_requests: 10,
+                    per: Duration::from_secs(1),
+                }))
+                .on_request(Some(RequestHook::new(|_| {})))
+                .reject_trailing_data(true)
+                .socks5_proxy(Some(Socks5Proxy {
+                    host: "proxy.test".to_owned(),
+                    port: 1080,
+                    credentials: Some(("user".to_owned(), "secret".to_owned())),
+                }))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let config = queue.config();
+        assert!(!config.http2_prior_knowledge);
+        assert_eq!(config.max_concurrent_requests, Some(3));
+        assert_eq!(config.dns_cache_ttl, Some(Duration::from_secs(30)));
+        assert!(!config.custom_resolver);
+        assert_eq!(config.number_of_dns_threads, Some(DEFAULT_DNS_THREADS));
+        assert!(config.coalesce_duplicates);
+        assert_eq!(config.rate_limit.unwrap().max_requests, 10);
+        assert_eq!(config.continue_timeout, DEFAULT_CONTINUE_TIMEOUT);
+        assert!(!config.on_connection_close);
+        assert!(config.on_request);
+        assert!(!config.on_response);
+        assert!(config.reject_trailing_data);
+        assert_eq!(config.socks5_proxy.as_ref().unwrap().host, "proxy.test");
+        assert!(!format!("{:?}", config).contains("secret"));
+        assert!(!config.paused);
+        assert_eq!(config.metrics_interval, None);
+
+        queue.pause();
+        queue.set_dns_threads(2).unwrap();
+        queue
+            .set_metrics_reporter(Duration::from_secs(60), |_| {})
+            .unwrap();
+
+        let config = queue.config();
+        assert!(config.paused);
+        assert_eq!(config.number_of_dns_threads, Some(2));
+        assert_eq!(config.metrics_interval, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_download_progress() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let body = vec![b'x'; 512 * 1024];
+        let server = {
+            let body = body.clone();
+            TestServer::new(move |_| response("200 OK", &[], &body))
+        };
+
+        let events = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = {
+            let events_progress = Arc::clone(&events);
+            let events_response = Arc::clone(&events);
+            queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/download"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .download_progress(Some(DownloadProgressHandler::new(move |p| {
+                                    events_progress.lock().unwrap().push(Some(p))
+                                })))
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        assert_eq!(req.unwrap().body.len(), 512 * 1024);
+                        events_response.lock().unwrap().push(None);
+                    },
+                )
+                .unwrap()
+        };
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let events = events.lock().unwrap();
+        let (last, progress) = events.split_last().unwrap();
+        assert_eq!(*last, None);
+
+        let progress: Vec<_> = progress.iter().map(|p| p.unwrap()).collect();
+        assert!(!progress.is_empty());
+        assert!(progress.windows(2).all(|w| w[0].received < w[1].received));
+        assert!(progress.iter().all(|p| p.total == Some(body.len() as u64)));
+
+        let last = progress.last().unwrap();
+        assert_eq!(last.received, body.len() as u64);
+        assert_eq!(last.fraction(), Some(1.0));
+    }
+
+    #[test]
+    fn test_stream_body() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            response("200 OK", &[], request.body.len().to_string().as_bytes())
+        });
+
+        let chunks = || {
+            futures::stream::iter_ok::<_, std::io::Error>(vec![
+                Bytes::from_static(b"hello "),
+                Bytes::from_static(b"streamed "),
+                Bytes::from_static(b"body"),
+            ])
+        };
+
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::new().unwrap();
+        let _handles: Vec<_> = vec![StreamBody::new(chunks(), 19), StreamBody::new(chunks(), 25)]
+            .into_iter()
+            .map(|stream| {
+                let results = Arc::clone(&results);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Post)
+                            .uri(server.url("/upload"))
+                            .body_stream(Some(stream))
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .headers({
+                                        let mut headers = header::HeaderMap::new();
+                                        headers
+                                            .insert(header::CONNECTION, "close".parse().unwrap());
+                                        headers
+                                    })
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |req| results.lock().unwrap().push(req),
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let results = results.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().any(|result| match result {
+            Ok(response) => &response.body[..] == b"19",
+            Err(_) => false,
+        }));
+        assert!(results.iter().any(|result| match result {
+            Err(e) => match e.kind() {
+                ErrorKind::BodyLengthMismatch(25, 19) => true,
+                _ => false,
+            },
+            Ok(_) => false,
+        }));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("Content-Length"), Some("19"));
+        assert_eq!(requests[0].body, b"hello streamed body");
+    }
+
+    #[test]
+    fn test_connection_pool() {
+        use super::test_server::*;
+        use super::*;
+
+        let run = |options: QueueOptions| {
+            let server = TestServer::with_body("pooled");
+            let mut queue = Queue::with_options(options).unwrap();
+
+            for _ in 0..3 {
+                let _handle = queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        |req| assert_eq!(&req.unwrap().body[..], b"pooled"),
+                    )
+                    .unwrap();
+
+                while queue.number_of_pending_requests() > 0 {
+                    queue.execute_query_with_timeout(
+                        Duration::from_millis(50),
+                        Duration::from_millis(10),
+                    );
+                }
+            }
+
+            server.connections()
+        };
+
+        assert_eq!(run(QueueOptions::default()), 1);
+        assert_eq!(
+            run(QueueOptionsBuilder::default()
+                .pool_max_idle_per_host(Some(0))
+                .build()
+                .unwrap()),
+            3
+        );
+
+        let queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .pool_idle_timeout(Some(Duration::from_secs(5)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            queue.config().pool_idle_timeout,
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_tcp_stats() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("stats");
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| {
+                    let stats = req.unwrap().tcp_stats.unwrap();
+                    assert!(stats.rtt > Duration::from_secs(0));
+                    assert!(stats.rtt < Duration::from_secs(1));
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_danger_accept_invalid_certs() {
+        use super::test_server::*;
+        use super::*;
+
+        #[derive(Debug)]
+        struct LocalhostResolver;
+
+        impl Resolver for LocalhostResolver {
+            fn resolve(&self, _host: &str) -> ResolveFuture {
+                Box::new(future::ok(vec!["127.0.0.1".parse().unwrap()]))
+            }
+        }
+
+        let server = TestServer::bind_tls(|_| response("200 OK", &[], b"secure"));
+        let url: url::Url = format!("https://localhost:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        let run = |danger_accept_invalid_certs| {
+            let mut queue = Queue::with_options(
+                QueueOptionsBuilder::default()
+                    .resolver(Some(Arc::new(LocalhostResolver) as Arc<dyn Resolver>))
+                    .danger_accept_invalid_certs(danger_accept_invalid_certs)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(url.clone())
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req).unwrap(),
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+
+            receiver.try_recv().unwrap()
+        };
+
+        assert_eq!(&run(true).unwrap().body[..], b"secure");
+
+        let error = run(false).unwrap_err();
+        let mut cause = error.hyper_error().and_then(|e| e.cause2());
+        // `hyper-rustls` wraps the TLS error into `io::Error` more than once.
+        while let Some(io_error) = cause.and_then(|c| c.downcast_ref::<std::io::Error>()) {
+            cause = io_error.get_ref().map(|inner| inner as _);
+        }
+        match cause.and_then(|c| c.downcast_ref::<rustls::TLSError>()) {
+            Some(rustls::TLSError::WebPKIError(_)) => {}
+            _ => panic!("Expected certificate error, got {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_uri_too_long() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("short");
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .max_uri_length(Some(64))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let _short = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/short"))
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"short"),
+            )
+            .unwrap();
+        let _long = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url(&format!("/long?q={}", "x".repeat(64))))
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::UriTooLong(length, 64) => assert!(*length > 64),
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_dns_resolution_error() {
+        use super::*;
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri("http://nonexistent.invalid/".parse().unwrap())
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::DnsResolution(host) => assert_eq!(host, "nonexistent.invalid"),
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_connector_override() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::AtomicUsize;
+
+        #[derive(Debug)]
+        struct RedirectingConnector {
+            address: std::net::SocketAddr,
+            connections: AtomicUsize,
+        }
+
+        impl Connector for RedirectingConnector {
+            fn connect(&self, _url: &url::Url) -> ConnectFuture {
+                self.connections.fetch_add(1, Ordering::SeqCst);
+                Box::new(
+                    tokio::net::TcpStream::connect(&self.address)
+                        .map(|stream| Box::new(stream) as Box<dyn Connection>),
+                )
+            }
+        }
+
+        let default_server = TestServer::with_body("default");
+        let tunnel_server = TestServer::with_body("tunnel");
+        let connector = Arc::new(RedirectingConnector {
+            address: tunnel_server.address(),
+            connections: AtomicUsize::new(0),
+        });
+
+        let mut queue = Queue::new().unwrap();
+        let _default = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(default_server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"default"),
+            )
+            .unwrap();
+        let _tunneled = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(default_server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .connector_override(Some(connector.clone() as Arc<dyn Connector>))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"tunnel"),
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(connector.connections.load(Ordering::SeqCst), 1);
+        assert_eq!(default_server.requests().len(), 1);
+        assert_eq!(tunnel_server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        use super::*;
+
+        // Port of the closed listener is unlikely to be taken again right away.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(format!("http://{}/", address).parse().unwrap())
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::ConnectionRefused => {}
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_connection_close_hook() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|_| response("200 OK", &[("Connection", "close")], b"bye"));
+        let reasons = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .on_connection_close(Some({
+                    let reasons = Arc::clone(&reasons);
+                    ConnectionCloseHook::new(move |reason| reasons.lock().unwrap().push(reason))
+                }))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"bye"),
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        // Connection is closed on the working thread, after the response is delivered.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reasons.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(*reasons.lock().unwrap(), vec![CloseReason::ConnectionClose]);
+    }
+
+    #[test]
+    fn test_transport_error_kind() {
+        use super::test_server::*;
+        use super::*;
+        use crate::errors::TransportErrorKind;
+        use error_chain::ChainedError;
+
+        let server = TestServer::new(|_| b"NOT HTTP\r\n\r\n".to_vec());
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| {
+                    let error = req.unwrap_err();
+                    assert!(error.hyper_error().unwrap().is_parse());
+                    assert_eq!(
+                        error.transport_error_kind(),
+                        Some(TransportErrorKind::Parse)
+                    );
+
+                    let hyper_message = error.hyper_error().unwrap().to_string();
+                    assert!(error.display_chain().to_string().contains(&hyper_message));
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let error: Error = ErrorKind::RequestTimeout.into();
+        assert!(error.hyper_error().is_none());
+        assert_eq!(error.transport_error_kind(), None);
+    }
+
+    #[test]
+    fn test_response_cache() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| match request.header("If-None-Match") {
+            Some("\"v1\"") => response("304 Not Modified", &[("ETag", "\"v1\"")], b""),
+            _ => response("200 OK", &[("ETag", "\"v1\"")], b"cached"),
+        });
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .response_cache_size(Some(16))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        for i in 0..3 {
+            if i == 2 {
+                queue.clear_cache();
+            }
+
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/"))
+                        .build()
+                        .unwrap(),
+                    |req| {
+                        let response = req.unwrap();
+                        assert_eq!(response.status_code, hyper::StatusCode::OK);
+                        assert_eq!(&response.body[..], b"cached");
+                    },
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].header("If-None-Match"), None);
+        assert_eq!(requests[1].header("If-None-Match"), Some("\"v1\""));
+        assert_eq!(requests[2].header("If-None-Match"), None);
+    }
+
+    #[test]
+    fn test_request_build_error() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("alive");
+        let mut queue = Queue::new().unwrap();
+
+        // Accepted by `Url`, but rejected by `http::Uri`.
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/a|b"))
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::RequestBuildError(_) => {}
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |req| assert_eq!(&req.unwrap().body[..], b"alive"),
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_send_batch() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::AtomicUsize;
+
+        let server = TestServer::new(|request| {
+            let path = request.head.split_whitespace().nth(1).unwrap().to_owned();
+            response("200 OK", &[], path.as_bytes())
+        });
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        let mut queue = Queue::new().unwrap();
+        let requests = (0..10)
+            .map(|i| {
+                let request = RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url(&format!("/{}", i)))
+                    .build()
+                    .unwrap();
+
+                let completed = Arc::clone(&completed);
+                let callback: Box<ResponseCallBack> = Box::new(move |req| {
+                    assert_eq!(req.unwrap().body, format!("/{}", i).as_bytes());
+                    completed.fetch_add(1, Ordering::SeqCst);
+                });
+
+                (request, callback)
+            })
+            .collect();
+
+        let handles = queue.send_batch(requests, false).unwrap();
+        assert_eq!(handles.len(), 10);
+        assert_eq!(queue.number_of_pending_requests(), 10);
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(completed.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_send_batch_ordered() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        // Later requests are answered sooner.
+        let server = TestServer::new(|request| {
+            let path = request.head.split_whitespace().nth(1).unwrap().to_owned();
+            let index: u64 = path[1..].parse().unwrap();
+            thread::sleep(Duration::from_millis(200 - index * 100));
+            response("200 OK", &[], path.as_bytes())
+        });
+
+        let mut queue = Queue::new().unwrap();
+        let run = |queue: &mut Queue, ordered| {
+            let delivered = Arc::new(Mutex::new(vec![]));
+            let requests = (0..3)
+                .map(|i| {
+                    let request = RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(&format!("/{}", i)))
+                        .build()
+                        .unwrap();
+
+                    let delivered = Arc::clone(&delivered);
+                    let callback: Box<ResponseCallBack> = Box::new(move |req| {
+                        delivered.lock().unwrap().push(req.unwrap().body_vec());
+                    });
+
+                    (request, callback)
+                })
+                .collect();
+
+            let _handles = queue.send_batch(requests, ordered).unwrap();
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(20),
+                    Duration::from_millis(10),
+                );
+            }
+
+            Arc::try_unwrap(delivered)
+                .ok()
+                .unwrap()
+                .into_inner()
+                .unwrap()
+        };
+
+        assert_eq!(
+            run(&mut queue, true),
+            vec![b"/0".to_vec(), b"/1".to_vec(), b"/2".to_vec()]
+        );
+        assert_eq!(
+            run(&mut queue, false),
+            vec![b"/2".to_vec(), b"/1".to_vec(), b"/0".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_debug_log() {
+        use super::test_server::*;
+        use super::*;
+
+        struct CapturingLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                if record.target() == debug_log::TARGET {
+                    self.0.lock().unwrap().push(record.args().to_string());
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        lazy_static::lazy_static! {
+            static ref LOGGER: CapturingLogger = CapturingLogger(Mutex::new(vec![]));
+        }
+        // Global maximal level is left `Off`.
+        log::set_logger(&*LOGGER).unwrap();
+
+        let server = TestServer::with_body("logged body");
+        let mut queue = Queue::new().unwrap();
+
+        for (path, debug_log) in &[("/flagged", true), ("/plain", false)] {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .headers(headers)
+                                .debug_log(*debug_log)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    |req| assert!(req.is_ok()),
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        let records = LOGGER.0.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].starts_with("Request Get"));
+        assert!(records[0].contains("/flagged"));
+        assert!(records[0].contains("authorization: <redacted>"));
+        assert!(!records[0].contains("secret"));
+        assert!(records[1].starts_with("Response 200 OK"));
+        assert!(records[1].contains("logged body"));
+        assert!(records.iter().all(|record| !record.contains("/plain")));
+    }
+
+    #[test]
+    fn test_request_future() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("future");
+        let mut queue = Queue::new().unwrap();
+
+        let request = |path| {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url(path))
+                .build()
+                .unwrap()
+        };
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (first, second) = runtime
+            .block_on(
+                queue
+                    .request_future(request("/1"))
+                    .join(queue.request_future(request("/2"))),
+            )
+            .unwrap();
+        assert_eq!(&first.body[..], b"future");
+        assert_eq!(&second.body[..], b"future");
+        assert_eq!(queue.number_of_pending_requests(), 0);
+
+        // Dropped future cancels the request, long before the server responds.
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_secs(2));
+            response("200 OK", &[], b"late")
+        });
+        let future = queue.request_future(
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url("/"))
+                .build()
+                .unwrap(),
+        );
+
+        let started = Instant::now();
+        while server.requests().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(queue.metrics().in_flight_requests, 1);
+
+        drop(future);
+        let dropped = Instant::now();
+        while queue.metrics().in_flight_requests > 0 {
+            assert!(dropped.elapsed() < Duration::from_millis(500));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_body_fragments() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("0123456789");
+        let fragments = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .fragment_size(Some(4))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                {
+                    let fragments = Arc::clone(&fragments);
+                    move |req| {
+                        let response = req.unwrap();
+                        let fragment = response.fragment.unwrap();
+                        fragments.lock().unwrap().push((
+                            fragment.index,
+                            fragment.is_last,
+                            response.body_vec(),
+                        ));
+                    }
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            *fragments.lock().unwrap(),
+            vec![
+                (0, false, b"0123".to_vec()),
+                (1, false, b"4567".to_vec()),
+                (2, true, b"89".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completed_counters() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("ok");
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut queue = Queue::new().unwrap();
+        let mut handles = vec![];
+        for uri in &[
+            server.url("/"),
+            server.url("/"),
+            format!("http://{}/", refused).parse().unwrap(),
+        ] {
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(uri.clone())
+                            .build()
+                            .unwrap(),
+                        |_| {},
+                    )
+                    .unwrap(),
+            );
+        }
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(queue.completed_success(), 2);
+        assert_eq!(queue.completed_error(), 1);
+        assert_eq!(queue.number_of_completed_requests(), 3);
+    }
+
+    #[test]
+    fn test_cancel_on() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"late")
+        });
+        let (cancel, cancel_on) = oneshot::channel::<()>();
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .cancel_on(Some(CancelSignal::new(cancel_on.map_err(|_| ()))))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::RequestCancelled => {}
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        let started = Instant::now();
+        queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        cancel.send(()).unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_stop_delivers_pending_callbacks() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::AtomicUsize;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"late")
+        });
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                {
+                    let calls = Arc::clone(&calls);
+                    move |req| {
+                        match req.unwrap_err().kind() {
+                            ErrorKind::RequestCancelled => {}
+                            e => panic!("Unexpected error {:?}", e),
+                        }
+                        calls.fetch_add(1, Ordering::SeqCst);
+                    }
+                },
+            )
+            .unwrap();
+
+        queue.stop();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        drop(queue);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_into_body_bytes() {
+        use super::test_server::*;
+        use super::*;
+
+        // Short bodies are copied inline by `Bytes`.
+        let body: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        let server = {
+            let body = body.clone();
+            TestServer::new(move |_| response("200 OK", &[], &body))
+        };
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    let response = req.unwrap();
+                    let data = response.body.as_ptr();
+
+                    let bytes = response.into_body_bytes();
+                    assert_eq!(&bytes[..], &body[..]);
+                    assert_eq!(bytes.as_ptr(), data);
+
+                    let slice = bytes.slice(100, 200);
+                    assert_eq!(&slice[..], &body[100..200]);
+                    assert_eq!(slice.as_ptr(), unsafe { data.add(100) });
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_response_text() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            let content_type = if request.head.starts_with("GET /latin1 ") {
+                "text/html; charset=iso-8859-1"
+            } else {
+                "text/html; charset=utf-8"
+            };
+            response(
+                "200 OK",
+                &[("Content-Type", content_type)],
+                b"Caf\xe9 \xbfQu\xe9?",
+            )
+        });
+
+        let mut queue = Queue::new().unwrap();
+        let _latin1 = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/latin1"))
+                    .build()
+                    .unwrap(),
+                |req| {
+                    let response = req.unwrap();
+                    assert_eq!(response.text().unwrap(), "Café ¿Qué?");
+                    assert_eq!(response.text_strict().unwrap(), "Café ¿Qué?");
+                },
+            )
+            .unwrap();
+        let _utf8 = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/utf8"))
+                    .build()
+                    .unwrap(),
+                |req| {
+                    let response = req.unwrap();
+                    assert_eq!(response.text().unwrap(), "Caf\u{fffd} \u{fffd}Qu\u{fffd}?");
+                    match response.text_strict().unwrap_err().kind() {
+                        ErrorKind::DecodeError(charset) => assert_eq!(charset, "UTF-8"),
+                        e => panic!("Unexpected error {:?}", e),
+                    }
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_follow_refresh() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /next ") {
+                response("200 OK", &[], b"refreshed")
+            } else {
+                response("200 OK", &[("Refresh", "0; url=/next")], b"legacy")
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+        for &(follow_refresh, body) in &[(true, "refreshed"), (false, "legacy")] {
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .follow_refresh(follow_refresh)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| assert_eq!(req.unwrap().body, body.as_bytes()),
+                )
+                .unwrap();
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_request_builder_query() {
+        use super::*;
+
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Get)
+            .uri("http://example.com/search?lang=en#top".parse().unwrap())
+            .query(&[("q", "a&b c"), ("path", "é/?=")])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            request.uri.as_str(),
+            "http://example.com/search?lang=en&q=a%26b+c&path=%C3%A9%2F%3F%3D#top"
+        );
+
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Get)
+            .uri("http://example.com/".parse().unwrap())
+            .query(&[("a", "1")])
+            .query(&[("b", "2")])
+            .build()
+            .unwrap();
+
+        assert_eq!(request.uri.query(), Some("a=1&b=2"));
+
+        let request = RequestBuilder::default()
+            .http_type(RequestType::Get)
+            .query(&[("a", "1 2")])
+            .uri("http://example.com/?lang=en".parse().unwrap())
+            .query(&[("b", "3")])
+            .build()
+            .unwrap();
+
+        assert_eq!(request.uri.query(), Some("lang=en&a=1+2&b=3"));
+
+        assert!(RequestBuilder::default()
+            .http_type(RequestType::Get)
+            .query(&[("a", "1")])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_deadline() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("too late");
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .timeout(Some(Duration::from_secs(60)))
+                            .deadline(Some(Instant::now() - Duration::from_millis(1)))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                |req| match req.unwrap_err().kind() {
+                    ErrorKind::RequestTimeout => {}
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn test_background_yields() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /foreground ") {
+                thread::sleep(Duration::from_millis(300));
+            }
+            response("200 OK", &[], b"")
+        });
+        let completed = Arc::new(Mutex::new(vec![]));
+
+        let mut queue = Queue::new().unwrap();
+        let mut handles = vec![];
+        for &(path, background) in &[("/foreground", false), ("/background", true)] {
+            let completed = Arc::clone(&completed);
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url(path))
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .background(background)
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |req| {
+                            assert!(req.is_ok());
+                            completed.lock().unwrap().push(path);
+                        },
+                    )
+                    .unwrap(),
+            );
+        }
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        // Without yielding, the fast background request would complete first.
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec!["/foreground", "/background"]
+        );
+        let requests = server.requests();
+        assert!(requests[0].head.starts_with("GET /foreground "));
+        assert!(requests[1].head.starts_with("GET /background "));
+    }
+
+    #[test]
+    fn test_background_occupies_slots() {
+        use super::test_server::*;
+        use super::*;
+
+        // Number of requests handled right now, and the maximum of it.
+        let running = Arc::new(Mutex::new((0, 0)));
+        let server = {
+            let running = Arc::clone(&running);
+            TestServer::new(move |_| {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = std::cmp::max(running.0, running.1);
+                }
+                thread::sleep(Duration::from_millis(100));
+                running.lock().unwrap().0 -= 1;
+                response("200 OK", &[], b"")
+            })
+        };
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .max_concurrent_requests(Some(1))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let handles: Vec<_> = ["/first", "/second", "/third"]
+            .iter()
+            .map(|path| {
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url(path))
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .background(true)
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        |req| {
+                            req.unwrap();
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(running.lock().unwrap().1, 1);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_send_request_raw() {
+        use super::test_server::*;
+        use super::*;
+
+        let server =
+            TestServer::new(|_| response("202 Accepted", &[("X-Custom", "raw")], b"raw body"));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request_raw(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |res| {
+                    let (parts, body) = res.unwrap().into_parts();
+                    assert_eq!(parts.status, hyper::StatusCode::ACCEPTED);
+                    assert_eq!(parts.headers["x-custom"], "raw");
+                    assert_eq!(&body.concat2().wait().unwrap()[..], b"raw body");
+                },
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_pending_requests_after_stop() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+
+        let mut queue = Queue::new().unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |_| {},
+            )
+            .unwrap();
+        assert_eq!(queue.number_of_pending_requests(), 1);
+
+        queue.stop();
+        assert_eq!(queue.number_of_pending_requests(), 0);
+
+        assert!(queue.try_recv_queue().is_err());
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_dns_timeout() {
+        use super::test_server::*;
+        use super::*;
+
+        /// Resolves every host into the loopback address after a delay.
+        #[derive(Debug)]
+        struct SlowResolver;
+
+        impl Resolver for SlowResolver {
+            fn resolve(&self, _host: &str) -> ResolveFuture {
+                Box::new(
+                    tokio::timer::Delay::new(Instant::now() + Duration::from_millis(500))
+                        .then(|_| Ok(vec!["127.0.0.1".parse().unwrap()])),
+                )
+            }
+        }
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"resolved"));
+        let url: url::Url = format!("http://slow.test:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .resolver(Some(Arc::new(SlowResolver) as Arc<dyn Resolver>))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let started = Instant::now();
+        let mut handles = Vec::new();
+        for dns_timeout in &[Some(Duration::from_millis(50)), None] {
+            let sender = sender.clone();
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(url.clone())
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .timeout(Some(Duration::from_secs(5)))
+                                    .dns_timeout(*dns_timeout)
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |res| sender.send((res, started.elapsed())).unwrap(),
+                    )
+                    .unwrap(),
+            );
+        }
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let (timed_out, elapsed) = receiver.try_recv().unwrap();
+        match timed_out.unwrap_err().kind() {
+            ErrorKind::DnsTimeout(host) => assert_eq!(host, "slow.test"),
+            e => panic!("Unexpected error: {}", e),
+        }
+        assert!(elapsed < Duration::from_millis(500));
+
+        let (resolved, _) = receiver.try_recv().unwrap();
+        assert_eq!(&resolved.unwrap().body[..], b"resolved");
+    }
+
+    #[test]
+    fn test_stop_without_requests() {
+        use super::*;
+
+        let mut queue = Queue::new().unwrap();
+        queue.stop();
+        assert_eq!(queue.number_of_pending_requests(), 0);
+
+        queue.execute_query_with_timeout(Duration::from_millis(20), Duration::from_millis(10));
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_send_request_after_stop() {
+        use super::*;
+
+        let mut queue = Queue::new().unwrap();
+        queue.stop();
+
+        let result = queue.send_request(
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri("http://localhost/".parse().unwrap())
+                .build()
+                .unwrap(),
+            |_| panic!("Callback of the rejected request was called"),
+        );
+
+        match result {
+            Err(Error(ErrorKind::QueueStopped, _)) => {}
+            _ => panic!("Request was accepted by the stopped queue"),
+        }
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_other_requests_after_stop() {
+        use super::*;
+
+        fn assert_stopped<T>(result: Result<T>) {
+            match result {
+                Err(Error(ErrorKind::QueueStopped, _)) => {}
+                _ => panic!("Request was accepted by the stopped queue"),
+            }
+        }
+
+        let mut queue = Queue::new().unwrap();
+        queue.stop();
+
+        let request = || {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri("http://localhost/".parse().unwrap())
+                .build()
+                .unwrap()
+        };
+        assert_stopped(queue.send_request_raw(request(), |_| {
+            panic!("Callback of the rejected request was called")
+        }));
+        assert_stopped(queue.send_bulk_request(
+            vec![()],
+            |_| request(),
+            1,
+            |_| panic!("Callback of the rejected request was called"),
+        ));
+        assert_stopped(queue.send_batch(
+            vec![(
+                request(),
+                Box::new(|_| panic!("Callback of the rejected request was called")),
+            )],
+            false,
+        ));
+        assert_stopped(queue.request_future(request()).wait());
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_request_body_not_copied() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+        let body = Bytes::from(vec![7; 1024 * 1024]);
+        let body_ptr = body.as_ptr();
+
+        let mut queue = Queue::new().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(server.url("/"))
+                    .body(body)
+                    .build()
+                    .unwrap(),
+                move |res| sender.send(res).unwrap(),
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        // Response keeps the very buffer, which was sent.
+        let response = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(response.base_request.body.as_ptr(), body_ptr);
+        assert_eq!(server.requests()[0].body.len(), 1024 * 1024);
+    }
+
+    #[test]
+    fn test_with_executor() {
+        use super::test_server::*;
+        use super::*;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let first_server = TestServer::with_body("first");
+        let second_server = TestServer::with_body("second");
+
+        let mut queues: Vec<_> = (0..2)
+            .map(|_| Queue::with_executor(runtime.executor(), QueueOptions::default()).unwrap())
+            .collect();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut handles = Vec::new();
+        for (queue, server) in queues.iter_mut().zip(&[&first_server, &second_server]) {
+            let sender = sender.clone();
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        move |req| sender.send(req.unwrap().body_vec()).unwrap(),
+                    )
+                    .unwrap(),
+            );
+        }
+
+        while queues
+            .iter()
+            .any(|queue| queue.number_of_pending_requests() > 0)
+        {
+            for queue in &mut queues {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(20),
+                    Duration::from_millis(10),
+                );
+            }
+        }
+
+        let mut bodies: Vec<_> = receiver.try_iter().collect();
+        bodies.sort();
+        assert_eq!(bodies, vec![b"first".to_vec(), b"second".to_vec()]);
+
+        // Stopping one queue leaves the runtime serving the other one.
+        queues[0].stop();
+        let _handle = queues[1]
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(second_server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.unwrap().body_vec()).unwrap(),
+            )
+            .unwrap();
+        while queues[1].number_of_pending_requests() > 0 {
+            queues[1]
+                .execute_query_with_timeout(Duration::from_millis(20), Duration::from_millis(10));
+        }
+        assert_eq!(receiver.try_recv().unwrap(), b"second");
+
+        drop(queues);
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_input_capacity() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("accepted");
+
+        // Commands aren't received, while the only thread of the runtime is blocked.
+        let runtime = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .build()
+            .unwrap();
+        let (unblock, blocked) = crossbeam_channel::bounded::<()>(0);
+        runtime.executor().spawn(future::lazy(move || {
+            blocked.recv().ok();
+            Ok(())
+        }));
+
+        let mut queue = Queue::with_executor(
+            runtime.executor(),
+            QueueOptionsBuilder::default()
+                .input_capacity(Some(2))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let send = |queue: &mut Queue| {
+            let sender = sender.clone();
+            queue.send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.unwrap().body_vec()).unwrap(),
+            )
+        };
+
+        fn assert_full<T>(result: Result<T>) {
+            match result {
+                Err(Error(ErrorKind::QueueFull, _)) => {}
+                _ => panic!("Request over the capacity was accepted"),
+            }
+        }
+
+        let handles = vec![send(&mut queue).unwrap(), send(&mut queue).unwrap()];
+        assert_full(send(&mut queue));
+
+        let request = || {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url("/"))
+                .build()
+                .unwrap()
+        };
+        assert_full(queue.send_request_raw(request(), |_| {}));
+        assert_full(queue.send_bulk_request(vec![()], |_| request(), 1, |_| {}));
+        assert_full(queue.send_batch(vec![(request(), Box::new(|_| {}))], false));
+        assert_full(queue.request_future(request()).wait());
+        assert_full(queue.send_sse_request(request(), None, |_| {}));
+        assert_full(queue.send_websocket("ws://localhost/".parse().unwrap(), |_| {}));
+        assert_eq!(queue.number_of_pending_requests(), 2);
+
+        unblock.send(()).unwrap();
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+        assert_eq!(receiver.try_iter().count(), 2);
+
+        drop(handles);
+        drop(queue);
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        let options = QueueOptionsBuilder::default()
+            .input_capacity(Some(0))
+            .build()
+            .unwrap();
+        match Queue::with_options(options) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            _ => panic!("Zero input_capacity must be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_drain_queue_with_limit() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("burst");
+
+        let mut queue = Queue::new().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let sender = sender.clone();
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        move |req| sender.send(req.unwrap().body_vec()).unwrap(),
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.response_receiver.len() < 10 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(queue.drain_queue_with_limit(100), 10);
+        assert_eq!(queue.number_of_pending_requests(), 0);
+        assert_eq!(receiver.try_iter().count(), 10);
+        assert_eq!(queue.drain_queue_with_limit(100), 0);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_execute_queue_with_limit() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("limited");
+
+        let mut queue = Queue::new().unwrap();
+        let callbacks = Arc::new(AtomicU64::new(0));
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let callbacks = Arc::clone(&callbacks);
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url("/"))
+                            .build()
+                            .unwrap(),
+                        move |_| {
+                            callbacks.fetch_add(1, Ordering::SeqCst);
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.response_receiver.len() < 20 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(queue.execute_queue_with_limit(5, Duration::new(0, 0)), 5);
+        assert_eq!(callbacks.load(Ordering::SeqCst), 5);
+        assert_eq!(queue.drain_queue_with_limit(5), 5);
+        assert_eq!(callbacks.load(Ordering::SeqCst), 10);
+        assert_eq!(queue.number_of_pending_requests(), 10);
+
+        drop(handles);
+    }
+
+    #[test]
+    fn test_panicking_callback() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::with_body("ok");
+
+        let mut queue = Queue::new().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let request = || {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url("/"))
+                .build()
+                .unwrap()
+        };
+
+        let _panicking = queue
+            .send_request(request(), |_| panic!("Callback failed"))
+            .unwrap();
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let _handle = queue
+            .send_request(request(), move |req| {
+                sender.send(req.unwrap().body_vec()).unwrap()
+            })
+            .unwrap();
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(receiver.try_recv().unwrap(), b"ok");
+        assert_eq!(queue.completed_success(), 2);
+    }
+
+    #[test]
+    fn test_response_after_queue_dropped() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::atomic::AtomicBool;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(100));
+            response("200 OK", &[], b"slow")
+        });
+
+        let panicked = Arc::new(AtomicBool::new(false));
+        let runtime = {
+            let panicked = Arc::clone(&panicked);
+            tokio::runtime::Builder::new()
+                .panic_handler(move |_| panicked.store(true, Ordering::SeqCst))
+                .build()
+                .unwrap()
+        };
+
+        let mut queue = Queue::with_executor(runtime.executor(), QueueOptions::default()).unwrap();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                |_| {},
+            )
+            .unwrap();
+
+        // Receiving end of the responses is gone, as if the queue was dropped.
+        queue.response_receiver = crossbeam_channel::unbounded().1;
+        while !queue.cancellation_senders.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(queue);
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert!(!panicked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_stop_twice() {
+        use super::*;
+
+        let mut queue = Queue::new().unwrap();
+        queue.stop();
+        queue.stop();
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_stop_timeout() {
+        use super::test_server::*;
+        use super::*;
+
+        // Blocks the thread of the runtime, so neither cancellation nor shutdown can proceed.
+        #[derive(Debug)]
+        struct StuckResolver;
+
+        impl Resolver for StuckResolver {
+            fn resolve(&self, _host: &str) -> ResolveFuture {
+                thread::sleep(Duration::from_secs(3));
+                Box::new(futures::future::ok(vec!["127.0.0.1".parse().unwrap()]))
+            }
+        }
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"resolved"));
+        let url: url::Url = format!("http://stuck.test:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .resolver(Some(Arc::new(StuckResolver) as Arc<dyn Resolver>))
+                .stop_timeout(Some(Duration::from_millis(200)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(url)
+                    .build()
+                    .unwrap(),
+                |_| {},
+            )
+            .unwrap();
+
+        // Let the request reach the resolver.
+        thread::sleep(Duration::from_millis(200));
+
+        let started = Instant::now();
+        queue.stop();
+        assert!(started.elapsed() < Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn test_compress_request() {
+        use super::test_server::*;
+        use super::*;
+        use flate2::read::GzDecoder;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+        let payload = format!("[{}]", vec!["{\"name\": \"player\"}"; 100].join(","));
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(server.url("/"))
+                    .body(payload.clone().into_bytes())
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .compress_request(Some(Compression::Gzip))
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.is_ok()).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        assert_eq!(receiver.try_recv(), Ok(true));
+
+        let request = server.requests().remove(0);
+        assert_eq!(request.header("Content-Encoding"), Some("gzip"));
+        assert!(request.body.len() < payload.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&request.body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[test]
+    fn test_content_length() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+        let chunks = || {
+            futures::stream::iter_ok::<_, std::io::Error>(vec![
+                Bytes::from_static(b"pay"),
+                Bytes::from_static(b"load"),
+            ])
+        };
+
+        let mut queue = Queue::new().unwrap();
+
+        let mut send = |body: &[u8], body_stream: Option<StreamBody>| {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Post)
+                        .body(body.to_vec())
+                        .body_stream(body_stream)
+                        .uri(server.url("/"))
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req.is_ok()).unwrap(),
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+            assert_eq!(receiver.try_recv(), Ok(true));
+            server.requests().pop().unwrap()
+        };
+
+        let request = send(b"payload", None);
+        assert_eq!(request.header("Content-Length"), Some("7"));
+        assert_eq!(request.header("Transfer-Encoding"), None);
+        assert_eq!(&request.body[..], b"payload");
+
+        // Streamed body of the known length isn't chunked either.
+        let request = send(b"", Some(StreamBody::new(chunks(), 7)));
+        assert_eq!(request.header("Content-Length"), Some("7"));
+        assert_eq!(request.header("Transfer-Encoding"), None);
+        assert_eq!(&request.body[..], b"payload");
+
+        let request = send(b"", Some(StreamBody::chunked(chunks())));
+        assert_eq!(request.header("Content-Length"), None);
+        assert_eq!(request.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(&request.body[..], b"payload");
+    }
+
+    #[test]
+    fn test_trailers() {
+        use super::*;
+        use hyper::body::Payload;
+
+        struct WithTrailers(Option<hyper::Chunk>);
+
+        impl Payload for WithTrailers {
+            type Data = hyper::Chunk;
+            type Error = std::io::Error;
+
+            fn poll_data(&mut self) -> Poll<Option<hyper::Chunk>, std::io::Error> {
+                Ok(Async::Ready(self.0.take()))
+            }
+
+            fn poll_trailers(&mut self) -> Poll<Option<header::HeaderMap>, std::io::Error> {
+                let mut trailers = header::HeaderMap::new();
+                trailers.insert("grpc-status", header::HeaderValue::from_static("0"));
+                Ok(Async::Ready(Some(trailers)))
+            }
+        }
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(|| {
+                hyper::service::service_fn_ok(|_| {
+                    hyper::Response::new(WithTrailers(Some("body".into())))
+                })
+            });
+        let url: url::Url = format!("http://{}/", server.local_addr()).parse().unwrap();
+        runtime.spawn(server.map_err(|_| ()));
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .http2_prior_knowledge(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(url)
+                    .build()
+                    .unwrap(),
+                move |req| {
+                    sender
+                        .send(req.map(|res| (res.version, res.body, res.trailers)))
+                        .unwrap()
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        let (version, body, trailers) = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(version, hyper::Version::HTTP_2);
+        assert_eq!(&body[..], b"body");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    #[test]
+    fn test_host_override() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+        let url: url::Url = format!("http://127.0.0.1:{}/", server.address().port())
+            .parse()
+            .unwrap();
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::HOST, "game.example.com".parse().unwrap());
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(url)
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .headers(headers)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.is_ok()).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        assert_eq!(receiver.try_recv(), Ok(true));
+
+        assert_eq!(
+            server.requests()[0].header("Host"),
+            Some("game.example.com")
+        );
+    }
+
+    #[test]
+    fn test_websocket() {
+        use super::test_server::*;
+        use super::*;
+
+        let address = websocket_echo_server();
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let websocket = queue
+            .send_websocket(
+                format!("ws://{}/echo", address).parse().unwrap(),
+                move |event| sender.send(event.unwrap()).unwrap(),
+            )
+            .unwrap();
+
+        websocket
+            .send(WebSocketMessage::Text("hello".to_owned()))
+            .unwrap();
+        websocket
+            .send(WebSocketMessage::Binary(Bytes::from_static(&[0, 1, 2])))
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_millis(500), Duration::from_millis(100));
+        assert_eq!(queue.number_of_pending_requests(), 1);
+
+        websocket.close();
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![
+                WebSocketEvent::Opened,
+                WebSocketEvent::Message(WebSocketMessage::Text("hello".to_owned())),
+                WebSocketEvent::Message(WebSocketMessage::Binary(Bytes::from_static(&[0, 1, 2]))),
+                WebSocketEvent::Closed {
+                    code: Some(1000),
+                    reason: String::new(),
+                },
+            ]
+        );
+        assert_eq!(queue.number_of_pending_requests(), 0);
+    }
+
+    #[test]
+    fn test_sse_request() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            let body: &[u8] = match request.header("Last-Event-ID") {
+                None => b": welcome\n\nid: 1\ndata: first\n\nevent: score\nid: 2\ndata: line 1\ndata: line 2\n\n",
+                Some("2") => b"id: 3\ndata: after reconnect\n\n",
+                Some(_) => b"",
+            };
+            response("200 OK", &[("Content-Type", "text/event-stream")], body)
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let cancellation = queue
+            .send_sse_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/events"))
+                    .build()
+                    .unwrap(),
+                Some(Duration::from_millis(10)),
+                move |event| sender.send(event).unwrap(),
+            )
+            .unwrap();
+
+        let mut events = vec![];
+        let started = Instant::now();
+        while events.len() < 3 && started.elapsed() < Duration::from_secs(2) {
+            queue.execute_query_with_timeout(Duration::from_millis(100), Duration::from_millis(10));
+            events.extend(receiver.try_iter().map(|event| event.unwrap().unwrap()));
+        }
+
+        let event = |event: &str, data: &str, id: &str| ServerSentEvent {
+            event: event.to_owned(),
+            data: data.to_owned(),
+            id: Some(id.to_owned()),
+        };
+        assert_eq!(
+            events[..3],
+            [
+                event("message", "first", "1"),
+                event("score", "line 1\nline 2", "2"),
+                event("message", "after reconnect", "3"),
+            ]
+        );
+
+        drop(cancellation);
+        queue.execute_query_with_timeout(Duration::from_millis(500), Duration::from_millis(100));
+        assert_eq!(queue.number_of_pending_requests(), 0);
+        assert_eq!(server.requests()[1].header("Last-Event-ID"), Some("2"));
+    }
+
+    #[test]
+    fn test_stale_connection_retry() {
+        use super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Answers the first request on every connection, and closes it on the next one.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buffer = [0; 4096];
+                    if stream.read(&mut buffer).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .unwrap();
+                    stream.read(&mut buffer).ok();
+                });
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        for _ in 0..3 {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(format!("http://{}/", address).parse().unwrap())
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req.map(|res| res.attempts)).unwrap(),
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(50));
+            assert_eq!(receiver.try_recv().unwrap().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_new_connection_closed_not_retried() {
+        use super::*;
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Closes every connection without the response.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        {
+            let connections = Arc::clone(&connections);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let mut stream = stream.unwrap();
+                    let mut buffer = [0; 4096];
+                    stream.read(&mut buffer).ok();
+                }
+            });
+        }
+
+        let mut queue = Queue::new().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(format!("http://{}/", address).parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.is_ok()).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(50));
+        assert_eq!(receiver.try_recv(), Ok(false));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_inflight_requests() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", &[], b"")
+        });
+
+        let mut queue = Queue::new().unwrap();
+        assert!(queue.inflight_requests().is_empty());
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/slow"))
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.is_ok()).unwrap(),
+            )
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        let inflight = queue.inflight_requests();
+        assert_eq!(inflight.len(), 1);
+        let (id, uri, elapsed) = &inflight[0];
+        assert_eq!(*id, handle.id());
+        assert_eq!(uri.path(), "/slow");
+        assert!(*elapsed >= Duration::from_millis(150));
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+        assert_eq!(receiver.try_recv(), Ok(true));
+        assert!(queue.inflight_requests().is_empty());
+
+        // Running requests of the bulk are listed under its ID.
+        let bulk = queue
+            .send_bulk_request(
+                vec!["/a", "/b", "/c"],
+                |path| {
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .build()
+                        .unwrap()
+                },
+                2,
+                |_| {},
+            )
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        let inflight = queue.inflight_requests();
+        assert_eq!(
+            inflight
+                .iter()
+                .map(|(id, uri, _)| (*id, uri.path()))
+                .collect::<Vec<_>>(),
+            vec![(bulk.id(), "/a"), (bulk.id(), "/b")]
+        );
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+        assert!(queue.inflight_requests().is_empty());
+    }
+
+    #[test]
+    fn test_error_source() {
+        use super::*;
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        // Closes the connection without responding.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.read(&mut [0; 4096]).ok();
+            }
+        });
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(format!("http://{}/", address).parse().unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.err().unwrap()).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+        let error = receiver.try_recv().unwrap();
+        assert!(match error.kind() {
+            ErrorKind::HyperError(_) => true,
+            _ => false,
+        });
+
+        let mut chain = vec![];
+        let mut source = std::error::Error::source(&error);
+        while let Some(e) = source {
+            chain.push(e);
+            source = e.source();
+        }
+        let hyper_error = chain[0].downcast_ref::<hyper::Error>().unwrap();
+        assert!(std::ptr::eq(hyper_error, error.hyper_error().unwrap()));
+        assert_eq!(error.to_string(), hyper_error.to_string());
+    }
+
+    /// Response to `http_type` request to `uri`, with the empty body and no timings.
+    fn response_to(http_type: super::RequestType, uri: &str, status: u16) -> super::Response {
+        use super::*;
+
+        let now = Instant::now();
+        Response {
+            base_request: RequestBuilder::default()
+                .http_type(http_type)
+                .uri(uri.parse().unwrap())
+                .build()
+                .unwrap(),
+            body: Bytes::new(),
+            spilled_body: None,
+            status_code: hyper::StatusCode::from_u16(status).unwrap(),
+            version: hyper::Version::HTTP_11,
+            headers: header::HeaderMap::new(),
+            reason: None,
+            timings: ResponseTimings {
+                started: now,
+                request_written: None,
+                head_received: now,
+                first_body_byte: None,
+                last_body_byte: None,
+            },
+            elapsed: Duration::default(),
+            attempts: 1,
+            retry_after: None,
+            tcp_stats: None,
+            fragment: None,
+            trailers: None,
+        }
+    }
+
+    #[test]
+    fn test_response_summary() {
+        use super::*;
+
+        let mut response = response_to(RequestType::Post, "https://localhost/path?x=1", 201);
+        response.body = Bytes::from(vec![0; 1234]);
+        response.elapsed = Duration::from_micros(87_600);
+
+        assert_eq!(
+            response.summary(),
+            "POST https://localhost/path?x=1 -> 201 (1234 bytes, 87ms)"
+        );
+    }
+
+    #[test]
+    fn test_streaming_body() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b""));
+
+        let mut queue = Queue::new().unwrap();
+
+        let (mut chunks, receiver) = futures::sync::mpsc::channel(0);
+        let (sender, results) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request_streaming_body(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(server.url("/logs"))
+                    .build()
+                    .unwrap(),
+                receiver,
+                move |req| sender.send(req.is_ok()).unwrap(),
+            )
+            .unwrap();
+
+        thread::spawn(move || {
+            for line in &["first\n", "second\n", "third\n"] {
+                chunks = chunks.send(line.as_bytes().to_vec()).wait().unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+        assert_eq!(results.try_recv(), Ok(true));
+
+        let request = server.requests().remove(0);
+        assert_eq!(request.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(request.header("Content-Length"), None);
+        assert_eq!(&request.body[..], b"first\nsecond\nthird\n");
+        assert_eq!(queue.metrics().bytes_sent, 19);
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        use super::test_server::*;
+        use super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Rejects the request right after its head, and reports the bytes received after that.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (head_sender, heads) = crossbeam_channel::unbounded();
+        let (body_sender, bodies) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 4096];
+            while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            head_sender
+                .send(String::from_utf8_lossy(&received).into_owned())
+                .unwrap();
+            stream
+                .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 6\r\n\r\ndenied")
+                .unwrap();
+
+            stream
+                .set_read_timeout(Some(Duration::from_secs(3)))
+                .unwrap();
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).ok();
+            body_sender.send(rest).unwrap();
+        });
+
+        let server = TestServer::new(|request| response("200 OK", &[], &request.body));
+
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handles: Vec<_> = vec![format!("http://{}/", address), server.url("/").to_string()]
+            .into_iter()
+            .map(|uri| {
+                let sender = sender.clone();
+                let started = Instant::now();
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Post)
+                            .uri(uri.parse().unwrap())
+                            .body(vec![b'x'; 4096])
+                            .options(
+                                RequestOptionsBuilder::default()
+                                    .expect_continue(true)
+                                    .build()
+                                    .unwrap(),
+                            )
+                            .build()
+                            .unwrap(),
+                        move |req| {
+                            let response = req.unwrap();
+                            sender
+                                .send((response.status_code, response.body, started.elapsed()))
+                                .unwrap()
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let mut results: Vec<_> = receiver.try_iter().collect();
+        results.sort_by_key(|(status, ..)| *status);
+
+        // Accepted body is sent after the timeout, as the test server doesn't answer `100 Continue`.
+        let (status, body, elapsed) = &results[0];
+        assert_eq!(*status, hyper::StatusCode::OK);
+        assert_eq!(body.len(), 4096);
+        assert!(*elapsed >= DEFAULT_CONTINUE_TIMEOUT);
+        assert_eq!(server.requests()[0].header("Expect"), Some("100-continue"));
+
+        let (status, body, elapsed) = &results[1];
+        assert_eq!(*status, hyper::StatusCode::UNAUTHORIZED);
+        assert_eq!(&body[..], b"denied");
+        assert!(*elapsed < DEFAULT_CONTINUE_TIMEOUT);
+
+        assert!(heads
+            .recv()
+            .unwrap()
+            .to_lowercase()
+            .contains("expect: 100-continue"));
+        assert!(bodies.recv().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expect_continue_interim_response() {
+        use super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Answers `100 Continue` right after the head, and reports the body received after that.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (body_sender, bodies) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 4096];
+            let head_end = loop {
+                if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            };
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+
+            let mut body = received.split_off(head_end);
+            while body.len() < 4096 {
+                let n = stream.read(&mut buffer).unwrap();
+                body.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+            body_sender.send(body).unwrap();
+        });
+
+        let timeout = Duration::from_secs(5);
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .continue_timeout(Some(timeout))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(queue.config().continue_timeout, timeout);
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let started = Instant::now();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Post)
+                    .uri(format!("http://{}/", address).parse().unwrap())
+                    .body(vec![b'x'; 4096])
+                    .options(
+                        RequestOptionsBuilder::default()
+                            .expect_continue(true)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+                move |req| sender.send((req.unwrap(), started.elapsed())).unwrap(),
+            )
+            .unwrap();
+
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        let (response, elapsed) = receiver.recv().unwrap();
+        assert_eq!(response.status_code, hyper::StatusCode::OK);
+        assert_eq!(&response.body[..], b"ok");
+        assert!(elapsed < Duration::from_millis(500));
+        assert_eq!(bodies.recv().unwrap(), vec![b'x'; 4096]);
+    }
+
+    // Whole `127.0.0.0/8` is assigned to the loopback interface only on Linux.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_local_address() {
+        use super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (peer_sender, peers) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                peer_sender.send(stream.peer_addr().unwrap().ip()).unwrap();
+                stream.read(&mut [0; 4096]).ok();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .ok();
+            }
+        });
+
+        let run = |local_address: &str| {
+            let mut queue = Queue::with_options(
+                QueueOptionsBuilder::default()
+                    .local_address(Some(local_address.parse().unwrap()))
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(format!("http://{}/", address).parse().unwrap())
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req.map(|_| ())).unwrap(),
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+            receiver.try_recv().unwrap()
+        };
+
+        run("127.0.0.2").unwrap();
+        assert_eq!(peers.try_recv(), Ok("127.0.0.2".parse().unwrap()));
+
+        // Documentation address, which isn't assigned to any interface.
+        match run("192.0.2.1").unwrap_err().kind() {
+            ErrorKind::LocalAddressUnavailable(address) => {
+                assert_eq!(*address, "192.0.2.1".parse::<IpAddr>().unwrap())
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(peers.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"ok"));
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .tcp_nodelay(true)
+                .tcp_keepalive(Some(Duration::from_secs(30)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request_raw(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |res| {
+                    let response = res.unwrap();
+                    let socket = response.extensions().get::<SocketHandle>().cloned();
+                    sender.send((response.status(), socket)).unwrap();
+                },
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        let (status, socket) = receiver.try_recv().unwrap();
+        assert_eq!(status, hyper::StatusCode::OK);
+
+        #[cfg(target_os = "linux")]
+        {
+            let socket = socket.unwrap();
+            assert_eq!(socket.option(libc::IPPROTO_TCP, libc::TCP_NODELAY), Some(1));
+            assert_eq!(socket.option(libc::SOL_SOCKET, libc::SO_KEEPALIVE), Some(1));
+            assert_eq!(
+                socket.option(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+                Some(30)
+            );
+        }
+        #[cfg(not(target_os = "linux"))]
+        drop(socket);
+    }
+
+    #[test]
+    fn test_socks5_proxy() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"proxied"));
+        let (proxy, targets) = socks5_server(Some(("user", "secret")));
+
+        let run = |uri: String, password: &str| {
+            let mut queue = Queue::with_options(
+                QueueOptionsBuilder::default()
+                    .socks5_proxy(Some(Socks5Proxy {
+                        host: proxy.ip().to_string(),
+                        port: proxy.port(),
+                        credentials: Some(("user".to_owned(), password.to_owned())),
+                    }))
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(uri.parse().unwrap())
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req.map(|res| res.body)).unwrap(),
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+            receiver.try_recv().unwrap()
+        };
+
+        let port = server.url("/").port().unwrap();
+        let body = run(format!("http://localhost:{}/", port), "secret").unwrap();
+        assert_eq!(&body[..], b"proxied");
+        assert_eq!(
+            targets.lock().unwrap().as_slice(),
+            &[format!("localhost:{}", port)]
+        );
+
+        match run(format!("http://localhost:{}/", port), "wrong")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::ProxyError(SocksError::AuthenticationFailed) => {}
+            kind => panic!("Unexpected error: {}", kind),
+        }
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        match run(format!("http://{}/", closed), "secret")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::ProxyError(SocksError::ConnectFailed(5)) => {}
+            kind => panic!("Unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn test_proxy_override() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"ok"));
+        let (default_proxy, default_targets) = socks5_server(None);
+        let (other_proxy, other_targets) = socks5_server(None);
+        let socks5 = |address: std::net::SocketAddr| Socks5Proxy {
+            host: address.ip().to_string(),
+            port: address.port(),
+            credentials: None,
+        };
+
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .socks5_proxy(Some(socks5(default_proxy)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for proxy in vec![
+            None,
+            Some(ProxyOverride::Direct),
+            Some(ProxyOverride::Socks5(socks5(other_proxy))),
+        ] {
+            let sender = sender.clone();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .proxy(proxy)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| sender.send(req.unwrap().status_code).unwrap(),
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(2), Duration::from_millis(100));
+            assert_eq!(receiver.try_recv(), Ok(hyper::StatusCode::OK));
+        }
+
+        let target = server.url("/").host_str().unwrap().to_owned();
+        let target = format!("{}:{}", target, server.url("/").port().unwrap());
+        assert_eq!(
+            default_targets.lock().unwrap().as_slice(),
+            &[target.clone()]
+        );
+        assert_eq!(other_targets.lock().unwrap().as_slice(), &[target]);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_error_for_status() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("404 Not Found", &[], b"missing"));
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for error_for_status in &[false, true] {
+            let sender = sender.clone();
+            let _handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url("/"))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .error_for_status(*error_for_status)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    move |req| {
+                        sender
+                            .send(match req {
+                                Ok(response) => Ok((response.status_code, response.body_vec())),
+                                Err(e) => match e.kind() {
+                                    ErrorKind::HttpStatus(status, body) => {
+                                        Err((*status, body.clone()))
+                                    }
+                                    kind => panic!("Unexpected error: {}", kind),
+                                },
+                            })
+                            .unwrap()
+                    },
+                )
+                .unwrap();
+
+            queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        }
+
+        let not_found = (hyper::StatusCode::NOT_FOUND, b"missing".to_vec());
+        assert_eq!(receiver.try_recv(), Ok(Ok(not_found.clone())));
+        assert_eq!(receiver.try_recv(), Ok(Err(not_found)));
+    }
+
+    #[test]
+    fn test_status_predicates() {
+        use super::*;
+
+        let classify = |status| {
+            let response = response_to(RequestType::Get, "http://localhost/", status);
+            [
+                response.is_success(),
+                response.is_redirect(),
+                response.is_client_error(),
+                response.is_server_error(),
+            ]
+        };
+
+        assert_eq!(classify(199), [false, false, false, false]);
+        assert_eq!(classify(200), [true, false, false, false]);
+        assert_eq!(classify(299), [true, false, false, false]);
+        assert_eq!(classify(300), [false, true, false, false]);
+        assert_eq!(classify(399), [false, true, false, false]);
+        assert_eq!(classify(400), [false, false, true, false]);
+        assert_eq!(classify(499), [false, false, true, false]);
+        assert_eq!(classify(500), [false, false, false, true]);
+        assert_eq!(classify(599), [false, false, false, true]);
+    }
+
+    #[test]
+    fn test_response_version() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"ok"));
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(server.url("/"))
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.map(|res| res.version)).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        assert_eq!(
+            receiver.try_recv().unwrap().unwrap(),
+            hyper::Version::HTTP_11
+        );
+    }
+
+    #[test]
+    fn test_execute_stats() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /missing ") {
+                response("404 Not Found", &[], b"")
+            } else {
+                response("200 OK", &[], b"ok")
+            }
+        });
+        let mut queue = Queue::new().unwrap();
+
+        let mut handles = vec![];
+        for path in &["/a", "/missing", "/b"] {
+            let handle = queue
+                .send_request(
+                    RequestBuilder::default()
+                        .http_type(RequestType::Get)
+                        .uri(server.url(path))
+                        .options(
+                            RequestOptionsBuilder::default()
+                                .error_for_status(true)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build()
+                        .unwrap(),
+                    |_| {},
+                )
+                .unwrap();
+            handles.push(handle);
+        }
+
+        let started = Instant::now();
+        while queue.response_receiver.len() < 3 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let first = queue.execute(2);
+        assert_eq!(first.processed, 2);
+        assert_eq!(first.remaining_pending, 1);
+        assert_eq!(first.successes + first.errors, 2);
+
+        let second = queue.execute(10);
+        assert_eq!(second.processed, 1);
+        assert_eq!(second.remaining_pending, 0);
+        assert_eq!(first.successes + second.successes, 2);
+        assert_eq!(first.errors + second.errors, 1);
+
+        assert_eq!(queue.execute(10), ExecuteStats::default());
+    }
+
+    #[test]
+    fn test_base_url() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|_| response("200 OK", &[], b"players"));
+        let base_url = server.url("/api/v1/").as_str().parse().unwrap();
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .base_url(Some(base_url))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _handle = queue
+            .send_request(
+                RequestBuilder::default()
+                    .http_type(RequestType::Get)
+                    .uri(queue.resolve_uri("players?top=10").unwrap())
+                    .build()
+                    .unwrap(),
+                move |req| sender.send(req.map(|res| res.body)).unwrap(),
+            )
+            .unwrap();
+
+        queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        assert_eq!(&receiver.try_recv().unwrap().unwrap()[..], b"players");
+        assert!(server.requests()[0]
+            .head
+            .starts_with("GET /api/v1/players?top=10 "));
+
+        assert_eq!(queue.resolve_uri("/status").unwrap(), server.url("/status"));
+        assert_eq!(
+            queue.resolve_uri("http://example.com/").unwrap().as_str(),
+            "http://example.com/"
+        );
+
+        let queue = Queue::new().unwrap();
+        match queue.resolve_uri("/api/v1/players").unwrap_err().kind() {
+            ErrorKind::RelativeUriWithoutBase(uri) => assert_eq!(uri, "/api/v1/players"),
+            kind => panic!("Unexpected error: {}", kind),
+        }
+
+        let options = QueueOptionsBuilder::default()
+            .base_url(Some("/api/v1/".parse().unwrap()))
+            .build()
+            .unwrap();
+        match Queue::with_options(options) {
+            Err(Error(ErrorKind::InvalidOptions(_), _)) => {}
+            _ => panic!("Relative base_url must be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_request_response_hooks() {
+        use super::test_server::*;
+        use super::*;
+        use std::sync::Mutex;
+
+        let server = TestServer::new(|request| {
+            if request.head.starts_with("GET /missing ") {
+                response("404 Not Found", &[], b"")
+            } else {
+                response("200 OK", &[], b"ok")
+            }
+        });
+
+        let requests = Arc::new(Mutex::new(vec![]));
+        let responses = Arc::new(Mutex::new(vec![]));
+        let mut queue = Queue::with_options(
+            QueueOptionsBuilder::default()
+                .on_request(Some(RequestHook::new({
+                    let requests = Arc::clone(&requests);
+                    move |request| requests.lock().unwrap().push(request.uri.path().to_owned())
+                })))
+                .on_response(Some(ResponseHook::new({
+                    let responses = Arc::clone(&responses);
+                    move |response| {
+                        responses.lock().unwrap().push((
+                            response.base_request.uri.path().to_owned(),
+                            response.status_code.as_u16(),
+                        ))
+                    }
+                })))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let mut handles = vec![];
+        for path in &["/ok", "/missing"] {
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url(path))
+                            .build()
+                            .unwrap(),
+                        |_| {},
+                    )
+                    .unwrap(),
+            );
+
+            while queue.number_of_pending_requests() > 0 {
+                queue.execute_query_with_timeout(
+                    Duration::from_millis(50),
+                    Duration::from_millis(10),
+                );
+            }
+            assert_eq!(requests.lock().unwrap().last().unwrap(), path);
+        }
+
+        assert_eq!(*requests.lock().unwrap(), vec!["/ok", "/missing"]);
+        assert_eq!(
+            *responses.lock().unwrap(),
+            vec![("/ok".to_owned(), 200), ("/missing".to_owned(), 404)]
+        );
+
+        // Requests of batches are observed too, futures resolve without the executing thread.
+        let request = |path| {
+            RequestBuilder::default()
+                .http_type(RequestType::Get)
+                .uri(server.url(path))
+                .build()
+                .unwrap()
+        };
+        let _batch = queue
+            .send_batch(vec![(request("/batch"), Box::new(|_| {}))], false)
+            .unwrap();
+        queue.request_future(request("/future")).wait().unwrap();
+        while queue.number_of_pending_requests() > 0 {
+            queue.execute_query_with_timeout(Duration::from_millis(50), Duration::from_millis(10));
+        }
+
+        assert_eq!(requests.lock().unwrap().split_off(2), vec!["/batch"]);
+    }
+
+    #[test]
+    fn test_response_retry_after() {
+        use super::test_server::*;
+        use super::*;
+
+        let server = TestServer::new(|request| {
+            let status = if request.head.starts_with("GET /throttled ") {
+                "429 Too Many Requests"
+            } else {
+                "200 OK"
+            };
+            response(status, &[("Retry-After", "3")], b"")
+        });
+        let mut queue = Queue::new().unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut handles = vec![];
+        for path in &["/throttled", "/ok"] {
+            let sender = sender.clone();
+            handles.push(
+                queue
+                    .send_request(
+                        RequestBuilder::default()
+                            .http_type(RequestType::Get)
+                            .uri(server.url(path))
+                            .build()
+                            .unwrap(),
+                        move |req| sender.send(req.unwrap().retry_after).unwrap(),
+                    )
+                    .unwrap(),
+            );
+            queue.execute_query_with_timeout(Duration::from_secs(1), Duration::from_millis(100));
+        }
+
+        assert_eq!(receiver.try_recv(), Ok(Some(Duration::from_secs(3))));
+        assert_eq!(receiver.try_recv(), Ok(None));
+    }
+}
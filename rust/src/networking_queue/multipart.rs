@@ -0,0 +1,336 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Incremental parser of the `multipart/*` bodies, e.g. `multipart/x-mixed-replace` streams.
+
+use std::fmt;
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+
+use crate::errors::*;
+
+/// Single part of the multipart body.
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// Receives parts of the multipart response as soon as each of them is complete.
+/// Called on the working thread of the queue.
+#[derive(Clone)]
+pub struct PartHandler(Arc<dyn Fn(Part) + Send + Sync>);
+
+impl PartHandler {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: 'static + Fn(Part) + Send + Sync,
+    {
+        PartHandler(Arc::new(handler))
+    }
+
+    pub fn handle(&self, part: Part) {
+        (self.0)(part)
+    }
+}
+
+impl fmt::Debug for PartHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PartHandler")
+    }
+}
+
+enum ParserState {
+    Preamble,
+    AfterDelimiter,
+    Headers,
+    Body(HeaderMap),
+    Done,
+}
+
+/// Splits multipart body into parts. Chunks may be split at any byte.
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    /// Position in `buffer`, before which delimiter was already searched for.
+    searched: usize,
+    state: ParserState,
+}
+
+fn malformed<T>(description: &str) -> Result<T> {
+    bail!(ErrorKind::MalformedResponse(format!(
+        "multipart body: {}",
+        description
+    )))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> Self {
+        MultipartParser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // First delimiter isn't required to be preceded by the line break.
+            buffer: b"\r\n".to_vec(),
+            searched: 0,
+            state: ParserState::Preamble,
+        }
+    }
+
+    /// Creates parser for the boundary given in the `Content-Type` header.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let boundary = content_type.split(';').skip(1).find_map(|parameter| {
+            let mut split = parameter.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("boundary") => {
+                    Some(value.trim().trim_matches('"'))
+                }
+                _ => None,
+            }
+        });
+
+        match boundary {
+            Some(boundary) if !boundary.is_empty() => Ok(MultipartParser::new(boundary)),
+            _ => malformed("Content-Type has no boundary"),
+        }
+    }
+
+    /// Consumes the next chunk of the body, returning parts completed by it.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Part>> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut parts = vec![];
+        loop {
+            match &self.state {
+                ParserState::Preamble => match self.find_delimiter() {
+                    Some(position) => {
+                        self.consume(position + self.delimiter.len());
+                        self.state = ParserState::AfterDelimiter;
+                    }
+                    None => break,
+                },
+                ParserState::AfterDelimiter => {
+                    if self.buffer.len() < 2 {
+                        break;
+                    }
+
+                    if self.buffer.starts_with(b"--") {
+                        self.state = ParserState::Done;
+                        continue;
+                    }
+
+                    // Line may end with the transport padding.
+                    match find(&self.buffer, b"\r\n") {
+                        Some(position) => {
+                            if !trim(&self.buffer[..position]).is_empty() {
+                                return malformed("garbage after boundary");
+                            }
+                            self.consume(position + 2);
+                            self.state = ParserState::Headers;
+                        }
+                        None => break,
+                    }
+                }
+                ParserState::Headers => {
+                    let end = if self.buffer.starts_with(b"\r\n") {
+                        0
+                    } else {
+                        match find(&self.buffer, b"\r\n\r\n") {
+                            Some(position) => position + 2,
+                            None => break,
+                        }
+                    };
+
+                    let headers = parse_headers(&self.buffer[..end])?;
+                    self.consume(end + 2);
+                    self.state = ParserState::Body(headers);
+                }
+                ParserState::Body(_) => match self.find_delimiter() {
+                    Some(position) => {
+                        let body = self.buffer[..position].to_vec();
+                        self.consume(position + self.delimiter.len());
+
+                        if let ParserState::Body(headers) =
+                            std::mem::replace(&mut self.state, ParserState::AfterDelimiter)
+                        {
+                            parts.push(Part { headers, body });
+                        }
+                    }
+                    None => break,
+                },
+                ParserState::Done => {
+                    // Epilogue is ignored.
+                    self.buffer.clear();
+                    break;
+                }
+            }
+        }
+
+        Ok(parts)
+    }
+
+    /// Checks that the body didn't end in the middle of the part.
+    pub fn finish(self) -> Result<()> {
+        match self.state {
+            ParserState::Done => Ok(()),
+            // Endless streams are usually cut right after the boundary.
+            ParserState::AfterDelimiter if trim(&self.buffer).is_empty() => Ok(()),
+            ParserState::Preamble => malformed("no boundary found"),
+            _ => malformed("body ended in the middle of the part"),
+        }
+    }
+
+    fn find_delimiter(&mut self) -> Option<usize> {
+        let position = find(&self.buffer[self.searched..], &self.delimiter)
+            .map(|position| position + self.searched);
+
+        if position.is_none() {
+            // Delimiter may be split between this and the next chunks.
+            self.searched = (self.buffer.len() + 1).saturating_sub(self.delimiter.len());
+        }
+
+        position
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buffer.drain(..len);
+        self.searched = 0;
+    }
+}
+
+fn parse_headers(raw: &[u8]) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    for line in raw.split(|b| *b == b'\n') {
+        let line = trim(line);
+        if line.is_empty() {
+            continue;
+        }
+
+        let colon = match line.iter().position(|b| *b == b':') {
+            Some(colon) => colon,
+            None => return malformed("header without colon"),
+        };
+
+        let name = HeaderName::from_bytes(&line[..colon]);
+        let value = HeaderValue::from_bytes(trim(&line[colon + 1..]));
+        match (name, value) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            }
+            _ => return malformed("invalid header"),
+        }
+    }
+
+    Ok(headers)
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or_else(|| bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |position| position + 1);
+
+    &bytes[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--frame\r\nContent-Type: text/plain\r\n\r\nfirst\r\n\
+        --frame  \r\nContent-Type: image/jpeg\r\nX-Index: 2\r\n\r\n--frame-\r\n\r\n\
+        --frame--\r\nepilogue";
+
+    fn summary(parts: &[Part]) -> Vec<(String, Vec<u8>)> {
+        parts
+            .iter()
+            .map(|part| {
+                (
+                    part.headers[CONTENT_TYPE].to_str().unwrap().to_owned(),
+                    part.body.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_split_at_every_byte() {
+        let mut parser = MultipartParser::new("frame");
+        let mut parts = vec![];
+        for byte in BODY.chunks(1) {
+            parts.extend(parser.push(byte).unwrap());
+        }
+        parser.finish().unwrap();
+
+        assert_eq!(
+            summary(&parts),
+            vec![
+                ("text/plain".to_owned(), b"first".to_vec()),
+                ("image/jpeg".to_owned(), b"--frame-\r\n".to_vec()),
+            ]
+        );
+        assert_eq!(parts[1].headers["X-Index"], "2");
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            "multipart/x-mixed-replace; boundary=\"frame\""
+                .parse()
+                .unwrap(),
+        );
+
+        let mut parser = MultipartParser::from_headers(&headers).unwrap();
+        assert_eq!(parser.push(BODY).unwrap().len(), 2);
+
+        headers.insert(CONTENT_TYPE, "multipart/mixed".parse().unwrap());
+        assert!(MultipartParser::from_headers(&headers).is_err());
+
+        let mut truncated = MultipartParser::new("frame");
+        truncated.push(&BODY[..40]).unwrap();
+        assert!(truncated.finish().is_err());
+    }
+}
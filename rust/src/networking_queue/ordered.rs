@@ -0,0 +1,112 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Delivery of the batch callbacks in the order of submission.
+
+use std::sync::{Arc, Mutex};
+
+use super::{guarded, Response, ResponseCallBack};
+use crate::errors::*;
+
+struct Slot {
+    callback: Arc<ResponseCallBack>,
+    /// Received, but not delivered yet.
+    results: Vec<Result<Response>>,
+    /// Last result of the request was received.
+    complete: bool,
+}
+
+/// Requests of the batch, which results wait for the earlier requests.
+struct Ordered {
+    /// First request, which results weren't all delivered.
+    next: usize,
+    slots: Vec<Slot>,
+}
+
+/// Wraps `callbacks`, so that they are called in their order. Results of the request are held,
+/// until all results of the earlier requests are delivered. Fragments of the body are delivered
+/// as they arrive, once the request is the first incomplete one.
+pub(crate) fn in_order(callbacks: Vec<Box<ResponseCallBack>>) -> Vec<Box<ResponseCallBack>> {
+    let ordered = Arc::new(Mutex::new(Ordered {
+        next: 0,
+        slots: callbacks
+            .into_iter()
+            .map(|callback| Slot {
+                callback: Arc::from(callback),
+                results: vec![],
+                complete: false,
+            })
+            .collect(),
+    }));
+
+    let count = ordered.lock().unwrap().slots.len();
+    (0..count)
+        .map(|index| {
+            let ordered = Arc::clone(&ordered);
+            Box::new(move |result: Result<Response>| {
+                for (callback, result) in ordered.lock().unwrap().receive(index, result) {
+                    guarded(|| (callback)(result));
+                }
+            }) as Box<ResponseCallBack>
+        })
+        .collect()
+}
+
+impl Ordered {
+    /// Stores `result` of the request at `index`. Returns results, which may be delivered now.
+    fn receive(
+        &mut self,
+        index: usize,
+        result: Result<Response>,
+    ) -> Vec<(Arc<ResponseCallBack>, Result<Response>)> {
+        let is_last = result.as_ref().map_or(true, |response| {
+            response.fragment.map_or(true, |f| f.is_last)
+        });
+        let slot = &mut self.slots[index];
+        slot.results.push(result);
+        slot.complete |= is_last;
+
+        let mut ready = vec![];
+        while let Some(slot) = self.slots.get_mut(self.next) {
+            let callback = &slot.callback;
+            ready.extend(
+                slot.results
+                    .drain(..)
+                    .map(|result| (Arc::clone(callback), result)),
+            );
+            if !slot.complete {
+                break;
+            }
+            self.next += 1;
+        }
+        ready
+    }
+}
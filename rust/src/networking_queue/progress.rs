@@ -0,0 +1,122 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Progress reporting of the body transfer.
+
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::prelude::*;
+use futures::stream;
+
+/// Receives number of bytes transferred so far and their total number.
+pub(crate) type ProgressCallBack = dyn Fn(u64, u64) + Send + Sync;
+
+/// Progress callback of the request. Called on the thread, which executes the queue.
+#[derive(Clone)]
+pub(crate) struct Progress(pub Arc<ProgressCallBack>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Progress of the response body download.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Number of body bytes received so far.
+    pub received: u64,
+    /// Body length given by `Content-Length`, if any.
+    pub total: Option<u64>,
+}
+
+impl DownloadProgress {
+    /// Received part of the body, from `0.0` to `1.0`. `None`, when total length is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total.map(|total| {
+            if total == 0 {
+                1.0
+            } else {
+                self.received as f64 / total as f64
+            }
+        })
+    }
+}
+
+/// Receives `DownloadProgress` every time the next chunk of the response body arrives.
+/// Called on the thread, which executes the queue.
+#[derive(Clone)]
+pub struct DownloadProgressHandler(Arc<dyn Fn(DownloadProgress) + Send + Sync>);
+
+impl DownloadProgressHandler {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: 'static + Fn(DownloadProgress) + Send + Sync,
+    {
+        DownloadProgressHandler(Arc::new(handler))
+    }
+
+    pub fn handle(&self, progress: DownloadProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for DownloadProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DownloadProgressHandler")
+    }
+}
+
+/// Size of the chunks, in which body is handed to the connection, when upload progress is reported.
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Streams `body` by chunks, calling `report` every time the next chunk is handed to the connection.
+/// Length of such body is unknown to `hyper`, so `Content-Length` must be set explicitly.
+pub(crate) fn upload_body<F>(body: Bytes, report: F) -> hyper::Body
+where
+    F: 'static + Fn(u64, u64) + Send,
+{
+    let total = body.len() as u64;
+    let chunks: Vec<_> = (0..body.len())
+        .step_by(UPLOAD_CHUNK_SIZE)
+        .map(|start| body.slice(start, (start + UPLOAD_CHUNK_SIZE).min(body.len())))
+        .collect();
+
+    let mut transferred = 0;
+    hyper::Body::wrap_stream(stream::iter_ok::<_, hyper::Error>(chunks).inspect(
+        move |chunk: &Bytes| {
+            transferred += chunk.len() as u64;
+            report(transferred, total);
+        },
+    ))
+}
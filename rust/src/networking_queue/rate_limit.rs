@@ -0,0 +1,139 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+
+/// Allows at most `max_requests` to start within any `per` window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max_requests: u32,
+    pub per: Duration,
+}
+
+/// Token bucket holding up to `max_requests` tokens, refilled evenly over `per`.
+/// Tokens are reserved in advance, so waiting requests start in the order they asked.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    inner: Arc<Mutex<BucketState>>,
+}
+
+struct BucketState {
+    /// May become negative, when tokens are reserved for the waiting requests.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// `max_requests` of the `limit` is positive, as `QueueOptions` are validated.
+    pub fn new(limit: RateLimit) -> Self {
+        debug_assert!(limit.max_requests > 0, "max_requests must be non-zero");
+
+        RateLimiter {
+            limit,
+            inner: Arc::new(Mutex::new(BucketState {
+                tokens: f64::from(limit.max_requests),
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a token, returning how long to wait until it becomes available.
+    fn reserve(&self, now: Instant) -> Duration {
+        let capacity = f64::from(self.limit.max_requests);
+        let per_token = duration_secs(self.limit.per) / capacity;
+
+        let mut state = self.inner.lock().unwrap();
+        if now > state.updated {
+            let refilled = duration_secs(now - state.updated) / per_token;
+            state.tokens = (state.tokens + refilled).min(capacity);
+            state.updated = now;
+        }
+
+        state.tokens -= 1.0;
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((-state.tokens * per_token * 1e9) as u64)
+        }
+    }
+
+    /// Resolves, when the request may be started.
+    pub fn acquire(&self) -> impl Future<Item = (), Error = ()> {
+        let now = Instant::now();
+        let wait = self.reserve(now);
+
+        if wait == Duration::from_secs(0) {
+            Either::A(future::ok(()))
+        } else {
+            Either::B(
+                tokio::timer::Delay::new(now + wait)
+                    .map_err(|e| error!("Rate limiter timer failed: {}", e)),
+            )
+        }
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(RateLimit {
+            max_requests: 2,
+            per: Duration::from_secs(1),
+        });
+        let now = limiter.inner.lock().unwrap().updated;
+
+        let waits: Vec<_> = (0..4).map(|_| limiter.reserve(now).as_millis()).collect();
+        assert_eq!(waits, vec![0, 0, 500, 1000]);
+
+        // Refilled tokens go to the requests, which reserved them earlier.
+        assert_eq!(
+            limiter.reserve(now + Duration::from_secs(1)).as_millis(),
+            500
+        );
+
+        // Bucket doesn't grow past `max_requests`.
+        let later = now + Duration::from_secs(10);
+        let waits: Vec<_> = (0..3).map(|_| limiter.reserve(later).as_millis()).collect();
+        assert_eq!(waits, vec![0, 0, 500]);
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{self, HeaderMap};
+use hyper::StatusCode;
+
+use crate::errors::*;
+
+/// Failures, which may be retried by the `RetryPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RetryableError {
+    /// Nobody listens on the remote address.
+    ConnectionRefused,
+    /// Host name couldn't be resolved.
+    DnsError,
+    /// Connection couldn't be established in time.
+    ConnectTimeout,
+    /// Request didn't complete within `RequestOptions::timeout`.
+    RequestTimeout,
+}
+
+impl RetryableError {
+    /// Connection-level failures, which are retried by default.
+    pub fn connection_errors() -> Vec<RetryableError> {
+        vec![
+            RetryableError::ConnectionRefused,
+            RetryableError::DnsError,
+            RetryableError::ConnectTimeout,
+        ]
+    }
+
+    /// Classifies the error of the request. Returns `None` for errors, which are never retried.
+    pub fn classify(error: &Error) -> Option<RetryableError> {
+        match error.kind() {
+            ErrorKind::RequestTimeout => Some(RetryableError::RequestTimeout),
+            ErrorKind::DnsResolution(_) => Some(RetryableError::DnsError),
+            ErrorKind::ConnectionRefused => Some(RetryableError::ConnectionRefused),
+            ErrorKind::HyperError(_) => {
+                let hyper_error = error.hyper_error().filter(|e| e.is_connect())?;
+                let io_error = hyper_error.cause2()?.downcast_ref::<io::Error>()?;
+
+                match io_error.kind() {
+                    io::ErrorKind::TimedOut => Some(RetryableError::ConnectTimeout),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Describes which failed requests are performed again.
+#[derive(Builder, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximal number of attempts after the first one. Zero disables retries.
+    #[builder(default)]
+    pub max_retries: u32,
+
+    /// Delay before every retry.
+    #[builder(default)]
+    pub delay: Duration,
+
+    /// Failures, which are retried.
+    #[builder(default = "RetryableError::connection_errors()")]
+    pub retry_on: Vec<RetryableError>,
+
+    /// Response statuses, which are retried. `Retry-After` of such response overrides `delay`.
+    #[builder(default)]
+    pub retry_on_status: Vec<StatusCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            delay: Duration::default(),
+            retry_on: RetryableError::connection_errors(),
+            retry_on_status: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether request, which already was retried `retries` times, should be retried after the `error`.
+    pub fn should_retry(&self, error: RetryableError, retries: u32) -> bool {
+        retries < self.max_retries && self.retry_on.contains(&error)
+    }
+
+    /// Whether request, which already was retried `retries` times, should be retried after the response with `status`.
+    pub fn should_retry_status(&self, status: StatusCode, retries: u32) -> bool {
+        retries < self.max_retries && self.retry_on_status.contains(&status)
+    }
+}
+
+/// Parses `Retry-After` header given in seconds or as the HTTP date.
+/// Dates in the past give the zero delay.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    retry_after_at(headers, SystemTime::now())
+}
+
+fn retry_after_at(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = time::strptime(value, "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let date = UNIX_EPOCH + Duration::from_secs(u64::try_from(date.to_timespec().sec).ok()?);
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_should_retry() {
+        use super::*;
+
+        let policy = RetryPolicyBuilder::default()
+            .max_retries(2)
+            .build()
+            .unwrap();
+
+        assert!(policy.should_retry(RetryableError::ConnectionRefused, 0));
+        assert!(policy.should_retry(RetryableError::DnsError, 1));
+        assert!(!policy.should_retry(RetryableError::ConnectTimeout, 2));
+        assert!(!policy.should_retry(RetryableError::RequestTimeout, 0));
+        assert!(!RetryPolicy::default().should_retry(RetryableError::ConnectionRefused, 0));
+    }
+
+    #[test]
+    fn test_should_retry_status() {
+        use super::*;
+
+        let policy = RetryPolicyBuilder::default()
+            .max_retries(1)
+            .retry_on_status(vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ])
+            .build()
+            .unwrap();
+
+        assert!(policy.should_retry_status(StatusCode::SERVICE_UNAVAILABLE, 0));
+        assert!(!policy.should_retry_status(StatusCode::SERVICE_UNAVAILABLE, 1));
+        assert!(!policy.should_retry_status(StatusCode::INTERNAL_SERVER_ERROR, 0));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(header::RETRY_AFTER, "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_retry_after_date() {
+        use super::*;
+
+        // Sun, 06 Nov 1994 08:49:00 GMT
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_740);
+        let retry_after = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RETRY_AFTER, value.parse().unwrap());
+            retry_after_at(&headers, now)
+        };
+
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::from_secs(37))
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:48:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        use super::*;
+
+        assert_eq!(
+            RetryableError::classify(&ErrorKind::RequestTimeout.into()),
+            Some(RetryableError::RequestTimeout)
+        );
+        assert_eq!(
+            RetryableError::classify(&ErrorKind::DnsResolution("localhost".to_owned()).into()),
+            Some(RetryableError::DnsError)
+        );
+        assert_eq!(
+            RetryableError::classify(&ErrorKind::ConnectionRefused.into()),
+            Some(RetryableError::ConnectionRefused)
+        );
+        assert_eq!(
+            RetryableError::classify(&ErrorKind::RequestCancelled.into()),
+            None
+        );
+    }
+}
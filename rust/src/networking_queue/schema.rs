@@ -0,0 +1,464 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Validation of the JSON responses against the subset of the JSON schema.
+
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use crate::errors::*;
+
+/// Keywords, which change the meaning of the schema but aren't supported.
+/// Schemas using them are rejected instead of being validated partially.
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "$ref",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+    "if",
+    "pattern",
+    "patternProperties",
+    "dependencies",
+    "uniqueItems",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JsonType {
+    Null,
+    Boolean,
+    Object,
+    Array,
+    Number,
+    Integer,
+    String,
+}
+
+impl JsonType {
+    fn parse(name: &str) -> Result<JsonType> {
+        Ok(match name {
+            "null" => JsonType::Null,
+            "boolean" => JsonType::Boolean,
+            "object" => JsonType::Object,
+            "array" => JsonType::Array,
+            "number" => JsonType::Number,
+            "integer" => JsonType::Integer,
+            "string" => JsonType::String,
+            _ => bail!(ErrorKind::InvalidSchema(format!("unknown type {}", name))),
+        })
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (JsonType::Null, Value::Null)
+            | (JsonType::Boolean, Value::Bool(_))
+            | (JsonType::Object, Value::Object(_))
+            | (JsonType::Array, Value::Array(_))
+            | (JsonType::Number, Value::Number(_))
+            | (JsonType::String, Value::String(_)) => true,
+            (JsonType::Integer, Value::Number(n)) => {
+                n.is_i64() || n.is_u64() || n.as_f64().map_or(false, |f| f.fract() == 0.0)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    reject_all: bool,
+    types: Option<Vec<JsonType>>,
+    enumeration: Option<Vec<Value>>,
+    properties: Vec<(String, Node)>,
+    required: Vec<String>,
+    additional_properties: Option<Box<Node>>,
+    items: Option<Box<Node>>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+}
+
+fn invalid<T>(keyword: &str) -> Result<T> {
+    bail!(ErrorKind::InvalidSchema(format!(
+        "malformed value of {}",
+        keyword
+    )))
+}
+
+fn usize_keyword(schema: &Map<String, Value>, keyword: &str) -> Result<Option<usize>> {
+    match schema.get(keyword) {
+        None => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(n) => Ok(Some(n as usize)),
+            None => invalid(keyword),
+        },
+    }
+}
+
+fn f64_keyword(schema: &Map<String, Value>, keyword: &str) -> Result<Option<f64>> {
+    match schema.get(keyword) {
+        None => Ok(None),
+        Some(value) => match value.as_f64() {
+            Some(n) => Ok(Some(n)),
+            None => invalid(keyword),
+        },
+    }
+}
+
+impl Node {
+    fn compile(schema: &Value) -> Result<Node> {
+        let schema = match schema {
+            Value::Bool(accept) => {
+                return Ok(Node {
+                    reject_all: !accept,
+                    ..Node::default()
+                })
+            }
+            Value::Object(schema) => schema,
+            _ => bail!(ErrorKind::InvalidSchema(
+                "schema must be an object or a boolean".to_owned()
+            )),
+        };
+
+        if let Some(keyword) = UNSUPPORTED_KEYWORDS
+            .iter()
+            .find(|keyword| schema.contains_key(**keyword))
+        {
+            bail!(ErrorKind::InvalidSchema(format!(
+                "unsupported keyword {}",
+                keyword
+            )));
+        }
+
+        let types = match schema.get("type") {
+            None => None,
+            Some(Value::String(name)) => Some(vec![JsonType::parse(name)?]),
+            Some(Value::Array(names)) => Some(
+                names
+                    .iter()
+                    .map(|name| match name.as_str() {
+                        Some(name) => JsonType::parse(name),
+                        None => invalid("type"),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Some(_) => return invalid("type"),
+        };
+
+        let enumeration = match (schema.get("enum"), schema.get("const")) {
+            (Some(Value::Array(values)), _) => Some(values.clone()),
+            (Some(_), _) => return invalid("enum"),
+            (None, Some(value)) => Some(vec![value.clone()]),
+            (None, None) => None,
+        };
+
+        let properties = match schema.get("properties") {
+            None => vec![],
+            Some(Value::Object(properties)) => properties
+                .iter()
+                .map(|(name, schema)| Ok((name.clone(), Node::compile(schema)?)))
+                .collect::<Result<_>>()?,
+            Some(_) => return invalid("properties"),
+        };
+
+        let required = match schema.get("required") {
+            None => vec![],
+            Some(Value::Array(names)) => names
+                .iter()
+                .map(|name| match name.as_str() {
+                    Some(name) => Ok(name.to_owned()),
+                    None => invalid("required"),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => return invalid("required"),
+        };
+
+        let compile_child = |keyword| -> Result<Option<Box<Node>>> {
+            match schema.get(keyword) {
+                None => Ok(None),
+                Some(Value::Array(_)) => bail!(ErrorKind::InvalidSchema(format!(
+                    "unsupported array form of {}",
+                    keyword
+                ))),
+                Some(schema) => Ok(Some(Box::new(Node::compile(schema)?))),
+            }
+        };
+
+        Ok(Node {
+            reject_all: false,
+            types,
+            enumeration,
+            properties,
+            required,
+            additional_properties: compile_child("additionalProperties")?,
+            items: compile_child("items")?,
+            min_items: usize_keyword(schema, "minItems")?,
+            max_items: usize_keyword(schema, "maxItems")?,
+            min_length: usize_keyword(schema, "minLength")?,
+            max_length: usize_keyword(schema, "maxLength")?,
+            minimum: f64_keyword(schema, "minimum")?,
+            maximum: f64_keyword(schema, "maximum")?,
+        })
+    }
+
+    fn validate(&self, value: &Value, path: &str, errors: &mut Vec<String>) {
+        if self.reject_all {
+            push(errors, path, "no value is allowed".to_owned());
+            return;
+        }
+
+        if let Some(types) = &self.types {
+            if !types.iter().any(|t| t.matches(value)) {
+                push(
+                    errors,
+                    path,
+                    format!(
+                        "expected {}, found {}",
+                        types
+                            .iter()
+                            .map(|t| format!("{:?}", t).to_lowercase())
+                            .collect::<Vec<_>>()
+                            .join(" or "),
+                        type_name(value)
+                    ),
+                );
+                return;
+            }
+        }
+
+        if let Some(enumeration) = &self.enumeration {
+            if !enumeration.contains(value) {
+                push(
+                    errors,
+                    path,
+                    format!("{} is not one of the allowed values", value),
+                );
+            }
+        }
+
+        match value {
+            Value::String(s) => {
+                let length = s.chars().count();
+                if self.min_length.map_or(false, |min| length < min) {
+                    push(
+                        errors,
+                        path,
+                        format!("string is shorter than {}", self.min_length.unwrap()),
+                    );
+                }
+                if self.max_length.map_or(false, |max| length > max) {
+                    push(
+                        errors,
+                        path,
+                        format!("string is longer than {}", self.max_length.unwrap()),
+                    );
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if self.minimum.map_or(false, |min| n < min) {
+                    push(
+                        errors,
+                        path,
+                        format!("{} is less than {}", n, self.minimum.unwrap()),
+                    );
+                }
+                if self.maximum.map_or(false, |max| n > max) {
+                    push(
+                        errors,
+                        path,
+                        format!("{} is greater than {}", n, self.maximum.unwrap()),
+                    );
+                }
+            }
+            Value::Array(items) => {
+                if self.min_items.map_or(false, |min| items.len() < min) {
+                    push(
+                        errors,
+                        path,
+                        format!("fewer than {} items", self.min_items.unwrap()),
+                    );
+                }
+                if self.max_items.map_or(false, |max| items.len() > max) {
+                    push(
+                        errors,
+                        path,
+                        format!("more than {} items", self.max_items.unwrap()),
+                    );
+                }
+                if let Some(node) = &self.items {
+                    for (index, item) in items.iter().enumerate() {
+                        node.validate(item, &format!("{}/{}", path, index), errors);
+                    }
+                }
+            }
+            Value::Object(object) => {
+                for name in &self.required {
+                    if !object.contains_key(name) {
+                        push(errors, path, format!("missing required property {}", name));
+                    }
+                }
+
+                for (name, value) in object {
+                    let child_path = format!("{}/{}", path, escape(name));
+                    match self.properties.iter().find(|(n, _)| n == name) {
+                        Some((_, node)) => node.validate(value, &child_path, errors),
+                        None => {
+                            if let Some(node) = &self.additional_properties {
+                                node.validate(value, &child_path, errors);
+                            }
+                        }
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => {}
+        }
+    }
+}
+
+/// Escapes the property name to be a part of the JSON pointer.
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn push(errors: &mut Vec<String>, path: &str, message: String) {
+    let path = if path.is_empty() { "/" } else { path };
+    errors.push(format!("{}: {}", path, message));
+}
+
+/// JSON schema compiled once and shared between requests.
+/// Supports `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum` and `maximum`.
+/// Other keywords are ignored, except the ones changing the meaning of the schema, which are rejected.
+#[derive(Clone, Debug)]
+pub struct JsonSchema(Arc<Node>);
+
+impl JsonSchema {
+    pub fn compile(schema: &Value) -> Result<JsonSchema> {
+        Ok(JsonSchema(Arc::new(Node::compile(schema)?)))
+    }
+
+    /// Returns all violations of the schema, each prefixed by the JSON pointer to the value.
+    pub fn validate(&self, value: &Value) -> Vec<String> {
+        let mut errors = vec![];
+        self.0.validate(value, "", &mut errors);
+        errors
+    }
+
+    /// Validates the JSON `body`, failing with `ErrorKind::SchemaValidation`.
+    pub(crate) fn validate_body(&self, body: &[u8]) -> Result<()> {
+        let errors = match serde_json::from_slice(body) {
+            Ok(value) => self.validate(&value),
+            Err(e) => vec![format!("invalid JSON: {}", e)],
+        };
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            bail!(ErrorKind::SchemaValidation(errors))
+        }
+    }
+}
+
+/// Whether `Content-Type` of the response denotes JSON.
+pub(crate) fn is_json(headers: &hyper::header::HeaderMap) -> bool {
+    headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            let essence = value.split(';').next().unwrap_or_default().trim();
+            essence.eq_ignore_ascii_case("application/json")
+                || essence.to_ascii_lowercase().ends_with("+json")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = JsonSchema::compile(&json!({
+            "type": "object",
+            "required": ["name", "players"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "players": {
+                    "type": "array",
+                    "items": { "type": "integer", "minimum": 0 }
+                },
+                "mode": { "enum": ["classic", "deathmatch"] }
+            }
+        }))
+        .unwrap();
+
+        assert!(schema
+            .validate(&json!({ "name": "de_dust2", "players": [1, 2], "mode": "classic" }))
+            .is_empty());
+
+        assert_eq!(
+            schema.validate(&json!({ "name": "x" })),
+            vec!["/: missing required property players".to_owned()]
+        );
+        assert_eq!(
+            schema.validate(&json!({ "name": "", "players": [1, -2.5], "map": 1 })),
+            vec![
+                "/name: string is shorter than 1".to_owned(),
+                "/players/1: expected integer, found number".to_owned(),
+                "/map: no value is allowed".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_rejects_unsupported() {
+        assert!(JsonSchema::compile(&json!({ "$ref": "#/definitions/a" })).is_err());
+        assert!(JsonSchema::compile(&json!({ "type": "float" })).is_err());
+        assert!(JsonSchema::compile(&json!(1)).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::sync::oneshot;
+
+/// Limits number of simultaneously executed requests.
+/// Waiting requests get slots by priority, and in the order they asked for them within the same priority.
+#[derive(Clone)]
+pub(crate) struct Slots {
+    inner: Arc<Mutex<SlotsInner>>,
+}
+
+struct SlotsInner {
+    available: usize,
+    waiters: BinaryHeap<Waiter>,
+    next_sequence: u64,
+}
+
+struct Waiter {
+    priority: u8,
+    sequence: u64,
+    sender: oneshot::Sender<SlotGuard>,
+}
+
+impl Waiter {
+    fn key(&self) -> (u8, Reverse<u64>) {
+        (self.priority, Reverse(self.sequence))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Occupied slot. Slot is released when guard is dropped.
+pub(crate) struct SlotGuard {
+    slots: Option<Slots>,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        if let Some(slots) = self.slots.take() {
+            slots.release();
+        }
+    }
+}
+
+impl Slots {
+    /// `None` means no limit.
+    pub fn new(limit: Option<usize>) -> Self {
+        Slots {
+            inner: Arc::new(Mutex::new(SlotsInner {
+                available: limit.unwrap_or(std::usize::MAX),
+                waiters: BinaryHeap::new(),
+                next_sequence: 0,
+            })),
+        }
+    }
+
+    /// Occupies free slot right away, if there is one.
+    pub fn try_acquire(&self) -> Option<SlotGuard> {
+        self.occupy(&mut self.inner.lock().unwrap())
+    }
+
+    fn occupy(&self, inner: &mut SlotsInner) -> Option<SlotGuard> {
+        if inner.available == 0 {
+            return None;
+        }
+
+        inner.available -= 1;
+        Some(SlotGuard {
+            slots: Some(self.clone()),
+        })
+    }
+
+    /// Resolves, when slot is available. Higher `priority` waits less.
+    /// Dropping the future gives up the place in the line.
+    pub fn acquire(&self, priority: u8) -> impl Future<Item = SlotGuard, Error = ()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(slot) = self.occupy(&mut inner) {
+            return Either::A(future::ok(slot));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let sequence = inner.next_sequence;
+        inner.next_sequence += 1;
+        inner.waiters.push(Waiter {
+            priority,
+            sequence,
+            sender,
+        });
+        Either::B(receiver.map_err(|_| ()))
+    }
+
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(waiter) = inner.waiters.pop() {
+            let guard = SlotGuard {
+                slots: Some(self.clone()),
+            };
+
+            match waiter.sender.send(guard) {
+                Ok(()) => return,
+                // Waiter gave up, so slot must not be released again.
+                Err(mut guard) => guard.slots = None,
+            }
+        }
+
+        inner.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_order() {
+        let slots = Slots::new(Some(1));
+
+        let first = slots.acquire(0).wait().unwrap();
+        let abandoned = slots.acquire(0);
+        let second = slots.acquire(0);
+        drop(abandoned);
+
+        drop(first);
+        let second = second.wait().unwrap();
+
+        assert!(slots.inner.lock().unwrap().waiters.is_empty());
+        assert_eq!(slots.inner.lock().unwrap().available, 0);
+
+        drop(second);
+        assert_eq!(slots.inner.lock().unwrap().available, 1);
+    }
+
+    #[test]
+    fn test_slots_priority() {
+        let slots = Slots::new(Some(1));
+
+        let first = slots.acquire(0).wait().unwrap();
+        let mut waiting: Vec<_> = [(0, "low"), (2, "high"), (0, "low2"), (1, "mid")]
+            .iter()
+            .map(|(priority, name)| (*name, slots.acquire(*priority)))
+            .collect();
+
+        drop(first);
+
+        // Every ready guard is dropped right away, passing the slot to the next waiter.
+        let order = future::lazy(move || {
+            let mut order = vec![];
+            while !waiting.is_empty() {
+                let ready = waiting
+                    .iter_mut()
+                    .position(|(_, future)| future.poll().unwrap().is_ready());
+                let (name, _) = waiting.remove(ready.unwrap());
+                order.push(name);
+            }
+            Ok::<_, ()>(order)
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(order, vec!["high", "mid", "low", "low2"]);
+    }
+}
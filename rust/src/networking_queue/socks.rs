@@ -0,0 +1,321 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Connections tunneled through the SOCKS5 proxy, see RFC 1928 and RFC 1929.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures::future;
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination, HttpConnector};
+use tokio::net::TcpStream;
+
+use super::dns::CachingResolver;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// SOCKS5 proxy, which TCP connections of the queue are tunneled through.
+/// Host names are resolved by the proxy.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Socks5Proxy {
+    pub host: String,
+    pub port: u16,
+    /// User name and password, sent when the proxy asks for them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Socks5Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Socks5Proxy")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(user, _)| (user, "<redacted>")),
+            )
+            .finish()
+    }
+}
+
+/// Proxy of the single request, overriding `QueueOptions::socks5_proxy`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProxyOverride {
+    /// Connects directly, even when the queue has the proxy.
+    Direct,
+    Socks5(Socks5Proxy),
+}
+
+/// Why the SOCKS5 proxy didn't establish the connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocksError {
+    /// Proxy accepts none of the offered authentication methods.
+    NoAcceptableMethod,
+    /// Proxy rejected the credentials.
+    AuthenticationFailed,
+    /// Proxy couldn't connect to the host. Holds the reply code, e.g. `5` for the refused connection.
+    ConnectFailed(u8),
+    /// Proxy violated the protocol, or the request can't be expressed in it.
+    Protocol(String),
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocksError::NoAcceptableMethod => f.write_str("no acceptable authentication method"),
+            SocksError::AuthenticationFailed => f.write_str("authentication failed"),
+            SocksError::ConnectFailed(code) => {
+                let reason = match code {
+                    1 => "general failure",
+                    2 => "connection not allowed by ruleset",
+                    3 => "network unreachable",
+                    4 => "host unreachable",
+                    5 => "connection refused",
+                    6 => "TTL expired",
+                    7 => "command not supported",
+                    8 => "address type not supported",
+                    _ => "unknown error",
+                };
+                write!(f, "{} (reply {})", reason, code)
+            }
+            SocksError::Protocol(t) => write!(f, "protocol error: {}", t),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {}
+
+impl SocksError {
+    /// Finds the error of the proxy, which caused the request to fail.
+    pub(crate) fn find(error: &hyper::Error) -> Option<&SocksError> {
+        error
+            .cause2()?
+            .downcast_ref::<io::Error>()?
+            .get_ref()?
+            .downcast_ref::<SocksError>()
+    }
+}
+
+impl From<SocksError> for io::Error {
+    fn from(error: SocksError) -> Self {
+        io::Error::new(io::ErrorKind::Other, error)
+    }
+}
+
+/// Connects directly, or through the proxy when it is set.
+/// Connection to the proxy itself is made by `http`, so its options apply.
+#[derive(Clone)]
+pub(crate) struct ProxyConnector {
+    pub http: HttpConnector<CachingResolver>,
+    pub proxy: Option<Arc<Socks5Proxy>>,
+}
+
+impl Connect for ProxyConnector {
+    type Transport = TcpStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let proxy = match &self.proxy {
+            Some(proxy) => Arc::clone(proxy),
+            None => return Box::new(self.http.connect(dst)),
+        };
+
+        let host = dst
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = dst
+            .port()
+            .unwrap_or(if dst.scheme() == "https" { 443 } else { 80 });
+
+        let mut proxy_dst = dst;
+        let valid = proxy_dst.set_scheme("http").is_ok() && proxy_dst.set_host(&proxy.host).is_ok();
+        if !valid {
+            let error = SocksError::Protocol(format!("invalid proxy host '{}'", proxy.host));
+            return Box::new(future::err(error.into()));
+        }
+        proxy_dst.set_port(proxy.port);
+
+        Box::new(
+            self.http
+                .connect(proxy_dst)
+                .and_then(move |(stream, connected)| {
+                    handshake(stream, &proxy, &host, port).map(|stream| (stream, connected))
+                }),
+        )
+    }
+}
+
+type Handshake = Box<dyn Future<Item = TcpStream, Error = io::Error> + Send>;
+
+/// Asks the proxy to connect to `host`, authenticating when the proxy requires it.
+fn handshake(stream: TcpStream, proxy: &Socks5Proxy, host: &str, port: u16) -> Handshake {
+    let request = match connect_request(host, port) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let credentials = proxy.credentials.clone();
+    let methods = match credentials {
+        Some(_) => vec![VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+        None => vec![VERSION, 1, NO_AUTHENTICATION],
+    };
+
+    Box::new(
+        tokio::io::write_all(stream, methods)
+            .and_then(|(stream, _)| tokio::io::read_exact(stream, [0; 2]))
+            .and_then(move |(stream, reply)| -> Handshake {
+                match (reply, credentials) {
+                    ([VERSION, NO_AUTHENTICATION], _) => Box::new(future::ok(stream)),
+                    ([VERSION, USERNAME_PASSWORD], Some((user, password))) => {
+                        authenticate(stream, &user, &password)
+                    }
+                    ([VERSION, NO_ACCEPTABLE_METHODS], _) => {
+                        Box::new(future::err(SocksError::NoAcceptableMethod.into()))
+                    }
+                    ([VERSION, method], _) => {
+                        let error = format!("method {} wasn't offered", method);
+                        Box::new(future::err(SocksError::Protocol(error).into()))
+                    }
+                    _ => Box::new(future::err(unexpected_version())),
+                }
+            })
+            .and_then(move |stream| tokio::io::write_all(stream, request))
+            .and_then(|(stream, _)| tokio::io::read_exact(stream, [0; 5]))
+            .and_then(|(stream, reply)| {
+                match reply {
+                    [VERSION, 0, _, _, _] => {}
+                    [VERSION, code, _, _, _] => return Err(SocksError::ConnectFailed(code).into()),
+                    _ => return Err(unexpected_version()),
+                }
+
+                // Bound address isn't needed, so the rest of it and the port are skipped.
+                let rest = match reply[3] {
+                    IPV4 => 4 - 1 + 2,
+                    IPV6 => 16 - 1 + 2,
+                    DOMAIN_NAME => usize::from(reply[4]) + 2,
+                    other => {
+                        let error = format!("unknown address type {}", other);
+                        return Err(SocksError::Protocol(error).into());
+                    }
+                };
+                Ok(tokio::io::read_exact(stream, vec![0; rest]))
+            })
+            .flatten()
+            .map(|(stream, _)| stream),
+    )
+}
+
+fn authenticate(stream: TcpStream, user: &str, password: &str) -> Handshake {
+    if user.len() > 255 || password.len() > 255 {
+        let error = SocksError::Protocol("credentials are longer than 255 bytes".to_owned());
+        return Box::new(future::err(error.into()));
+    }
+
+    let mut request = vec![1, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+
+    Box::new(
+        tokio::io::write_all(stream, request)
+            .and_then(|(stream, _)| tokio::io::read_exact(stream, [0; 2]))
+            .and_then(|(stream, reply)| match reply {
+                [1, 0] => Ok(stream),
+                [1, _] => Err(SocksError::AuthenticationFailed.into()),
+                _ => Err(unexpected_version()),
+            }),
+    )
+}
+
+/// `CONNECT` request. Host names are sent as is, to be resolved by the proxy.
+fn connect_request(host: &str, port: u16) -> Result<Vec<u8>, SocksError> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(address)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&address.octets());
+        }
+        Ok(IpAddr::V6(address)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&address.octets());
+        }
+        Err(_) if host.len() > 255 => {
+            return Err(SocksError::Protocol(
+                "host name is longer than 255 bytes".to_owned(),
+            ));
+        }
+        Err(_) => {
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    Ok(request)
+}
+
+fn unexpected_version() -> io::Error {
+    SocksError::Protocol("unexpected version in the reply".to_owned()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_request() {
+        assert_eq!(
+            connect_request("example.com", 443).unwrap(),
+            b"\x05\x01\x00\x03\x0bexample.com\x01\xbb".to_vec()
+        );
+        assert_eq!(
+            connect_request("127.0.0.1", 80).unwrap(),
+            vec![5, 1, 0, 1, 127, 0, 0, 1, 0, 80]
+        );
+        assert_eq!(connect_request("::1", 80).unwrap().len(), 4 + 16 + 2);
+        assert!(connect_request(&"a".repeat(256), 80).is_err());
+    }
+}
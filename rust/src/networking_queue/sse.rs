@@ -0,0 +1,212 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::mem;
+use std::time::Duration;
+
+/// Event of the `text/event-stream` response. See `Queue::send_sse_request`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerSentEvent {
+    /// "message", unless the server set the type.
+    pub event: String,
+    /// Lines of the multi-line data are joined with `\n`.
+    pub data: String,
+    /// Last event ID set by the server, sent back as `Last-Event-ID` on reconnects.
+    pub id: Option<String>,
+}
+
+/// Parses the `text/event-stream` incrementally, as the chunks arrive.
+#[derive(Debug, Default)]
+pub(crate) struct EventStreamParser {
+    buffer: Vec<u8>,
+    started: bool,
+    event: String,
+    data: String,
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl EventStreamParser {
+    /// Events completed by the `chunk`.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<ServerSentEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        if !self.started {
+            if self.buffer.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buffer) {
+                return vec![];
+            }
+            if self.buffer.starts_with(b"\xEF\xBB\xBF") {
+                self.buffer.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let mut events = vec![];
+        let mut start = 0;
+        while let Some(position) = self.buffer[start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+        {
+            let end = start + position;
+            let terminator_len = match &self.buffer[end..] {
+                [b'\r', b'\n', ..] => 2,
+                // May be followed by `\n` in the next chunk.
+                [b'\r'] => break,
+                _ => 1,
+            };
+
+            let line = String::from_utf8_lossy(&self.buffer[start..end]).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+            start = end + terminator_len;
+        }
+        self.buffer.drain(..start);
+
+        events
+    }
+
+    /// Reconnection time set by the server.
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Forgets the incomplete event, as the stream is reconnected.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.started = false;
+        self.event.clear();
+        self.data.clear();
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<ServerSentEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let mut split = line.splitn(2, ':');
+        let field = split.next().unwrap_or_default();
+        let value = split.next().unwrap_or_default();
+        let value = if value.starts_with(' ') {
+            &value[1..]
+        } else {
+            value
+        };
+
+        match field {
+            "event" => self.event = value.to_owned(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => {
+                self.last_event_id = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_owned())
+                };
+            }
+            "retry" => {
+                if let Ok(millis) = value.parse() {
+                    self.retry = Some(Duration::from_millis(millis));
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<ServerSentEvent> {
+        let event = mem::take(&mut self.event);
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut data = mem::take(&mut self.data);
+        data.pop();
+
+        Some(ServerSentEvent {
+            event: if event.is_empty() {
+                "message".to_owned()
+            } else {
+                event
+            },
+            data,
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parser() {
+        let mut parser = EventStreamParser::default();
+
+        assert_eq!(parser.feed(b"\xEF\xBB\xBF: comment\nretry: 250\n"), vec![]);
+        assert_eq!(parser.retry(), Some(Duration::from_millis(250)));
+
+        let events = parser.feed(b"data: first\r\ndata:second\r\n\r\nevent: score\nid: 7\nda");
+        assert_eq!(
+            events,
+            vec![ServerSentEvent {
+                event: "message".to_owned(),
+                data: "first\nsecond".to_owned(),
+                id: None,
+            }]
+        );
+
+        assert_eq!(
+            parser.feed(b"ta: 3:1\r"),
+            vec![],
+            "line isn't complete until the next chunk"
+        );
+        assert_eq!(
+            parser.feed(b"\n\r\nevent: empty\n\n"),
+            vec![ServerSentEvent {
+                event: "score".to_owned(),
+                data: "3:1".to_owned(),
+                id: Some("7".to_owned()),
+            }]
+        );
+        assert_eq!(parser.last_event_id(), Some("7"));
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Request bodies produced by the stream.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::prelude::*;
+
+use crate::errors::*;
+
+type ChunkStream = Box<dyn Stream<Item = Bytes, Error = io::Error> + Send>;
+
+/// Request body read from the stream, which must produce exactly `length` bytes.
+/// Body is sent with `Content-Length`, so servers rejecting chunked uploads accept it.
+/// Body of unknown length is sent with the chunked transfer encoding instead.
+/// Stream can be sent only once: retries and `307`/`308` redirects of such request fail.
+#[derive(Clone)]
+pub struct StreamBody {
+    stream: Arc<Mutex<Option<ChunkStream>>>,
+    length: Option<u64>,
+    produced: Arc<AtomicU64>,
+}
+
+impl StreamBody {
+    pub fn new<S>(stream: S, length: u64) -> Self
+    where
+        S: 'static + Stream<Item = Bytes, Error = io::Error> + Send,
+    {
+        Self::with_length(stream, Some(length))
+    }
+
+    /// Body of unknown length, which ends with the stream.
+    pub fn chunked<S>(stream: S) -> Self
+    where
+        S: 'static + Stream<Item = Bytes, Error = io::Error> + Send,
+    {
+        Self::with_length(stream, None)
+    }
+
+    fn with_length<S>(stream: S, length: Option<u64>) -> Self
+    where
+        S: 'static + Stream<Item = Bytes, Error = io::Error> + Send,
+    {
+        StreamBody {
+            stream: Arc::new(Mutex::new(Some(Box::new(stream)))),
+            length,
+            produced: Default::default(),
+        }
+    }
+
+    /// Declared length of the body. `None` for the chunked body.
+    pub fn len(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == Some(0)
+    }
+
+    /// Number of bytes the stream produced so far.
+    pub(crate) fn produced(&self) -> u64 {
+        self.produced.load(Ordering::Relaxed)
+    }
+
+    /// Takes the stream and wraps it into the body, which fails when the stream
+    /// produces more or less bytes than declared.
+    pub(crate) fn take(&self) -> Result<hyper::Body> {
+        let stream = self
+            .stream
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(ErrorKind::BodyStreamConsumed)?;
+
+        Ok(hyper::Body::wrap_stream(CheckedStream {
+            stream,
+            declared: self.length,
+            produced: 0,
+            counter: Arc::clone(&self.produced),
+        }))
+    }
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StreamBody")
+            .field("length", &self.length)
+            .finish()
+    }
+}
+
+/// Fails when the stream produces more or less bytes than declared.
+struct CheckedStream {
+    stream: ChunkStream,
+    declared: Option<u64>,
+    produced: u64,
+    counter: Arc<AtomicU64>,
+}
+
+impl Stream for CheckedStream {
+    type Item = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Self::Error> {
+        let chunk = match self.stream.poll()? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(chunk) => chunk,
+        };
+        if let Some(chunk) = &chunk {
+            self.produced += chunk.len() as u64;
+            self.counter.store(self.produced, Ordering::Relaxed);
+        }
+
+        let declared = match self.declared {
+            Some(declared) => declared,
+            None => return Ok(Async::Ready(chunk)),
+        };
+        match chunk {
+            Some(_) if self.produced <= declared => Ok(Async::Ready(chunk)),
+            None if self.produced == declared => Ok(Async::Ready(None)),
+            _ => Err(Box::new(LengthMismatch {
+                declared,
+                produced: self.produced,
+            })),
+        }
+    }
+}
+
+/// Stream produced number of bytes other than declared.
+#[derive(Debug)]
+pub(crate) struct LengthMismatch {
+    pub declared: u64,
+    pub produced: u64,
+}
+
+impl fmt::Display for LengthMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "stream produced {} bytes instead of {}",
+            self.produced, self.declared
+        )
+    }
+}
+
+impl std::error::Error for LengthMismatch {}
+
+impl LengthMismatch {
+    /// Finds the mismatch, which caused the request to fail.
+    pub fn find(error: &hyper::Error) -> Option<&LengthMismatch> {
+        // Body errors are wrapped into `hyper::Error` more than once.
+        let mut cause = error.cause2()?;
+        while let Some(error) = cause.downcast_ref::<hyper::Error>() {
+            cause = error.cause2()?;
+        }
+        cause.downcast_ref::<LengthMismatch>()
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Congestion statistics of the TCP connections, read with `TCP_INFO` on Linux.
+
+use std::time::Duration;
+
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper_rustls::MaybeHttpsStream;
+use tokio::net::TcpStream;
+
+/// Statistics of the TCP connection, which carried the response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpStats {
+    /// Smoothed round-trip time.
+    pub rtt: Duration,
+    /// Variance of the round-trip time.
+    pub rtt_variance: Duration,
+    /// Number of unrecovered retransmission timeouts.
+    pub retransmits: u32,
+    /// Total number of retransmitted segments.
+    pub total_retransmits: u32,
+    /// Congestion window, in segments.
+    pub congestion_window: u32,
+}
+
+/// Socket of the connection, which remains readable after the connection is closed.
+/// Passed to the responses through the `hyper` extensions.
+#[derive(Clone, Debug)]
+pub(crate) struct SocketHandle {
+    #[cfg(target_os = "linux")]
+    fd: std::sync::Arc<linux::DuplicatedFd>,
+}
+
+impl SocketHandle {
+    #[cfg(target_os = "linux")]
+    fn new(stream: &TcpStream) -> Option<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        linux::DuplicatedFd::new(stream.as_raw_fd()).map(|fd| SocketHandle {
+            fd: std::sync::Arc::new(fd),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(_stream: &TcpStream) -> Option<Self> {
+        None
+    }
+
+    /// Current statistics of the connection. `None`, when OS doesn't expose them.
+    #[cfg(target_os = "linux")]
+    pub fn stats(&self) -> Option<TcpStats> {
+        self.fd.stats()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn stats(&self) -> Option<TcpStats> {
+        None
+    }
+
+    #[cfg(all(test, target_os = "linux"))]
+    pub fn option(&self, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+        self.fd.option(level, name)
+    }
+}
+
+/// Attaches `SocketHandle` to the connections of the inner connector.
+#[derive(Clone)]
+pub(crate) struct StatsConnector<C>(pub C);
+
+impl<C> Connect for StatsConnector<C>
+where
+    C: Connect<Transport = MaybeHttpsStream<TcpStream>>,
+    C::Future: 'static,
+{
+    type Transport = C::Transport;
+    type Error = C::Error;
+    type Future = Box<dyn Future<Item = (Self::Transport, Connected), Error = Self::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        Box::new(self.0.connect(dst).map(|(io, connected)| {
+            let stream = match &io {
+                MaybeHttpsStream::Http(stream) => stream,
+                MaybeHttpsStream::Https(stream) => stream.get_ref().0,
+            };
+
+            match SocketHandle::new(stream) {
+                Some(handle) => (io, connected.extra(handle)),
+                None => (io, connected),
+            }
+        }))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    use super::TcpStats;
+
+    /// Beginning of the `struct tcp_info` from `linux/tcp.h`.
+    /// Kernel copies only as many bytes, as requested.
+    #[repr(C)]
+    #[derive(Default)]
+    struct TcpInfo {
+        state: u8,
+        ca_state: u8,
+        retransmits: u8,
+        probes: u8,
+        backoff: u8,
+        options: u8,
+        wscale: u8,
+        flags: u8,
+
+        rto: u32,
+        ato: u32,
+        snd_mss: u32,
+        rcv_mss: u32,
+
+        unacked: u32,
+        sacked: u32,
+        lost: u32,
+        retrans: u32,
+        fackets: u32,
+
+        last_data_sent: u32,
+        last_ack_sent: u32,
+        last_data_recv: u32,
+        last_ack_recv: u32,
+
+        pmtu: u32,
+        rcv_ssthresh: u32,
+        rtt: u32,
+        rttvar: u32,
+        snd_ssthresh: u32,
+        snd_cwnd: u32,
+        advmss: u32,
+        reordering: u32,
+
+        rcv_rtt: u32,
+        rcv_space: u32,
+
+        total_retrans: u32,
+    }
+
+    #[derive(Debug)]
+    pub struct DuplicatedFd(RawFd);
+
+    impl DuplicatedFd {
+        pub fn new(fd: RawFd) -> Option<Self> {
+            let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                None
+            } else {
+                Some(DuplicatedFd(fd))
+            }
+        }
+
+        pub fn stats(&self) -> Option<TcpStats> {
+            let mut info = TcpInfo::default();
+            let mut len = mem::size_of::<TcpInfo>() as libc::socklen_t;
+
+            let result = unsafe {
+                libc::getsockopt(
+                    self.0,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_INFO,
+                    &mut info as *mut TcpInfo as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if result != 0 || (len as usize) < mem::size_of::<TcpInfo>() {
+                return None;
+            }
+
+            Some(TcpStats {
+                rtt: Duration::from_micros(info.rtt.into()),
+                rtt_variance: Duration::from_micros(info.rttvar.into()),
+                retransmits: info.retransmits.into(),
+                total_retransmits: info.total_retrans,
+                congestion_window: info.snd_cwnd,
+            })
+        }
+
+        /// Integer socket option.
+        #[cfg(test)]
+        pub fn option(&self, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+            let mut value: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+            let result = unsafe {
+                libc::getsockopt(
+                    self.0,
+                    level,
+                    name,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if result == 0 {
+                Some(value)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl Drop for DuplicatedFd {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Decoding of the response body into the text.
+
+use std::borrow::Cow;
+use std::io::Read;
+
+use encoding_rs::{Encoding, UTF_8};
+use hyper::header::{self, HeaderMap};
+
+use super::Response;
+use crate::errors::*;
+
+impl Response {
+    /// Decodes the body using the charset from `Content-Type`, falling back to UTF-8.
+    /// Invalid sequences are replaced with `U+FFFD`.
+    pub fn text(&self) -> Result<String> {
+        let body = self.body_contents()?;
+        let (text, _) = charset(&self.headers).decode_without_bom_handling(&body);
+
+        Ok(text.into_owned())
+    }
+
+    /// Same as `text`, but fails with `ErrorKind::DecodeError` on invalid sequences.
+    pub fn text_strict(&self) -> Result<String> {
+        let body = self.body_contents()?;
+        let encoding = charset(&self.headers);
+
+        match encoding.decode_without_bom_handling_and_without_replacement(&body) {
+            Some(text) => Ok(text.into_owned()),
+            None => bail!(ErrorKind::DecodeError(encoding.name().to_owned())),
+        }
+    }
+
+    fn body_contents(&self) -> Result<Cow<[u8]>> {
+        if self.spilled_body.is_none() {
+            return Ok(Cow::Borrowed(&self.body));
+        }
+
+        let mut body = vec![];
+        self.body_reader()?.read_to_end(&mut body)?;
+        Ok(Cow::Owned(body))
+    }
+}
+
+/// Encoding named by the `charset` parameter of `Content-Type`.
+fn charset(headers: &HeaderMap) -> &'static Encoding {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value.split(';').skip(1).find_map(|parameter| {
+                let mut split = parameter.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(name), Some(label)) if name.trim().eq_ignore_ascii_case("charset") => {
+                        Encoding::for_label(label.trim().trim_matches('"').as_bytes())
+                    }
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(UTF_8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_charset() {
+        let headers = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+
+        assert_eq!(
+            charset(&headers("text/html; charset=ISO-8859-1")).name(),
+            "windows-1252"
+        );
+        assert_eq!(
+            charset(&headers("text/plain;charset=\"koi8-r\"")).name(),
+            "KOI8-R"
+        );
+        assert_eq!(charset(&headers("text/plain")), UTF_8);
+        assert_eq!(charset(&headers("text/plain; charset=unknown")), UTF_8);
+        assert_eq!(charset(&HeaderMap::new()), UTF_8);
+    }
+}
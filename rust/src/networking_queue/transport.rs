@@ -0,0 +1,496 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! `hyper` clients used to perform requests and the redirect handling on top of them.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use fnv::FnvHashMap;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use hyper_rustls::HttpsConnector;
+
+use super::dns::{AddressFamily, CachingResolver};
+use super::lifecycle::TrackingConnector;
+use super::socks::{ProxyConnector, ProxyOverride, Socks5Proxy};
+use super::stream_body::StreamBody;
+use super::tcp_info::StatsConnector;
+use super::QueueOptions;
+use crate::errors::*;
+
+pub(crate) type TcpConnector = TrackingConnector<StatsConnector<HttpsConnector<ProxyConnector>>>;
+
+type TcpClient = hyper::Client<TcpConnector>;
+
+fn tcp_client(
+    options: &QueueOptions,
+    resolver: CachingResolver,
+    proxy: Option<&Socks5Proxy>,
+) -> TcpClient {
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false);
+    http.set_local_address(options.local_address);
+    http.set_nodelay(options.tcp_nodelay);
+    http.set_keepalive(options.tcp_keepalive);
+
+    let mut tls = rustls::ClientConfig::new();
+    if options.http2_prior_knowledge {
+        tls.set_protocols(&["h2".into()]);
+    } else {
+        tls.set_protocols(&["h2".into(), "http/1.1".into()]);
+    }
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if options.danger_accept_invalid_certs {
+        tls.dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    // Connections receiving bytes after the end of the response, e.g. past its `Content-Length`,
+    // are closed by `hyper` instead of being returned to the pool.
+    client_builder(options).build(TrackingConnector {
+        inner: StatsConnector(HttpsConnector::from((
+            ProxyConnector {
+                http,
+                proxy: proxy.cloned().map(Arc::new),
+            },
+            tls,
+        ))),
+        hook: options.on_connection_close.clone(),
+        detect_trailing_data: options.reject_trailing_data,
+    })
+}
+
+fn client_builder(options: &QueueOptions) -> hyper::client::Builder {
+    let mut builder = hyper::Client::builder();
+    builder.http2_only(options.http2_prior_knowledge);
+    if let Some(max_idle) = options.pool_max_idle_per_host {
+        builder.max_idle_per_host(max_idle);
+    }
+    if let Some(timeout) = options.pool_idle_timeout {
+        builder.keep_alive_timeout(timeout);
+    }
+    builder
+}
+
+/// Accepts any certificate of the server.
+struct NoCertificateVerification;
+
+impl rustls::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// How the request is connected over TCP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TcpRoute {
+    pub family: AddressFamily,
+    pub dns_timeout: Option<Duration>,
+    /// Proxy of the queue is used, when it isn't set.
+    pub proxy: Option<ProxyOverride>,
+}
+
+type ClientKey = (AddressFamily, Option<Duration>, Option<Socks5Proxy>);
+
+/// Maximal number of the clients created on demand. The least recently used one is dropped over it,
+/// together with its idle connections.
+const MAX_CUSTOM_CLIENTS: usize = 16;
+
+#[derive(Default)]
+struct CustomClients {
+    map: FnvHashMap<ClientKey, (TcpClient, u64)>,
+    clock: u64,
+}
+
+impl CustomClients {
+    fn get_or_insert_with<F>(&mut self, key: ClientKey, create: F) -> TcpClient
+    where
+        F: FnOnce() -> TcpClient,
+    {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if !self.map.contains_key(&key) && self.map.len() >= MAX_CUSTOM_CLIENTS {
+            let least_recent = self
+                .map
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                self.map.remove(&least_recent);
+            }
+        }
+
+        let entry = self.map.entry(key).or_insert_with(|| (create(), clock));
+        entry.1 = clock;
+        entry.0.clone()
+    }
+}
+
+/// Clients, which connect only to the addresses of the specific `AddressFamily`.
+/// Connections of the different families are pooled separately.
+/// Clients bounding the DNS resolution or using the other proxy are created on demand,
+/// one per family, timeout and proxy, and at most `MAX_CUSTOM_CLIENTS` of them are kept.
+#[derive(Clone)]
+pub(crate) struct TcpClients {
+    any: hyper::Client<TcpConnector>,
+    v4_only: hyper::Client<TcpConnector>,
+    v6_only: hyper::Client<TcpConnector>,
+    options: Arc<QueueOptions>,
+    resolver: CachingResolver,
+    custom: Arc<Mutex<CustomClients>>,
+}
+
+impl TcpClients {
+    pub fn new(options: &QueueOptions, resolver: &CachingResolver) -> Self {
+        if options.danger_accept_invalid_certs {
+            warn!("TLS certificate verification is disabled, connections are open to interception");
+        }
+
+        let proxy = options.socks5_proxy.as_ref();
+        TcpClients {
+            any: tcp_client(options, resolver.with_family(AddressFamily::Any), proxy),
+            v4_only: tcp_client(options, resolver.with_family(AddressFamily::V4Only), proxy),
+            v6_only: tcp_client(options, resolver.with_family(AddressFamily::V6Only), proxy),
+            options: Arc::new(options.clone()),
+            resolver: resolver.clone(),
+            custom: Default::default(),
+        }
+    }
+
+    pub fn get(&self, route: &TcpRoute) -> hyper::Client<TcpConnector> {
+        let family = route.family;
+        let proxy = match &route.proxy {
+            None => self.options.socks5_proxy.as_ref(),
+            Some(ProxyOverride::Direct) => None,
+            Some(ProxyOverride::Socks5(proxy)) => Some(proxy),
+        };
+
+        if route.dns_timeout.is_some() || proxy != self.options.socks5_proxy.as_ref() {
+            return self.custom.lock().unwrap().get_or_insert_with(
+                (family, route.dns_timeout, proxy.cloned()),
+                || {
+                    let mut resolver = self.resolver.with_family(family);
+                    if let Some(dns_timeout) = route.dns_timeout {
+                        resolver = resolver.with_timeout(dns_timeout);
+                    }
+                    tcp_client(&self.options, resolver, proxy)
+                },
+            );
+        }
+
+        match family {
+            AddressFamily::Any => self.any.clone(),
+            AddressFamily::V4Only => self.v4_only.clone(),
+            AddressFamily::V6Only => self.v6_only.clone(),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn unix_client(options: &QueueOptions) -> hyper::Client<super::uds::UnixConnector> {
+    client_builder(options).build(super::uds::UnixConnector)
+}
+
+pub(crate) const MAX_REDIRECTS: usize = 10;
+
+/// Rejects responses, whose body length is ambiguous, as they may be used for smuggling.
+/// Repeated equal `Content-Length` headers are accepted only with `allow_identical_lengths`.
+/// `hyper` already rejects differing `Content-Length` values while parsing.
+pub(crate) fn check_framing(headers: &HeaderMap, allow_identical_lengths: bool) -> Result<()> {
+    let lengths: Vec<_> = headers.get_all(header::CONTENT_LENGTH).iter().collect();
+
+    if !lengths.is_empty() && headers.contains_key(header::TRANSFER_ENCODING) {
+        bail!(ErrorKind::MalformedResponse(
+            "both Content-Length and Transfer-Encoding are present".to_owned()
+        ));
+    }
+
+    if lengths.len() > 1 && !(allow_identical_lengths && lengths.windows(2).all(|w| w[0] == w[1])) {
+        bail!(ErrorKind::MalformedResponse(
+            "multiple Content-Length headers".to_owned()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Everything needed to send the request again after redirect.
+pub(crate) struct RequestTarget {
+    pub url: url::Url,
+    pub method: Method,
+    pub body: Bytes,
+    /// Sent instead of `body`, when set.
+    pub stream: Option<StreamBody>,
+    pub headers: HeaderMap,
+}
+
+impl RequestTarget {
+    /// Builds the `hyper` request carrying the `body`.
+    /// Header values are checked again, since they may have been constructed unchecked.
+    /// URI is parsed by `http`, which is stricter than `Url`.
+    pub fn build(&self, body: hyper::Body) -> Result<hyper::Request<hyper::Body>> {
+        let invalid = self
+            .headers
+            .iter()
+            .find(|(_, value)| HeaderValue::from_bytes(value.as_bytes()).is_err());
+        if let Some((name, _)) = invalid {
+            bail!(ErrorKind::RequestBuildError(format!(
+                "invalid value of the header '{}'",
+                name
+            )));
+        }
+
+        let mut request = hyper::Request::builder()
+            .method(self.method.clone())
+            .uri(self.url.as_str())
+            .body(body)
+            .map_err(|e| Error::from(ErrorKind::RequestBuildError(e.to_string())))?;
+        *request.headers_mut() = self.headers.clone();
+
+        Ok(request)
+    }
+
+    /// Returns target of the redirect, when `response` should be followed.
+    /// `Refresh` header with zero delay is followed as `303 See Other`, when `follow_refresh` is set.
+    pub fn redirect<B>(
+        &self,
+        response: &hyper::Response<B>,
+        follow_refresh: bool,
+    ) -> Option<RequestTarget> {
+        let mut method = self.method.clone();
+        let mut body = self.body.clone();
+        let mut stream = self.stream.clone();
+        let mut headers = self.headers.clone();
+
+        let location = response.headers().get(header::LOCATION);
+        let (location, switch_to_get) = match response.status() {
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => {
+                (location?.to_str().ok()?, true)
+            }
+            StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => {
+                (location?.to_str().ok()?, false)
+            }
+            _ if follow_refresh => (refresh_location(response.headers())?, true),
+            _ => return None,
+        };
+
+        if switch_to_get {
+            if method != Method::GET && method != Method::HEAD {
+                method = Method::GET;
+            }
+            body = Bytes::new();
+            stream = None;
+            for name in &[
+                header::TRANSFER_ENCODING,
+                header::CONTENT_ENCODING,
+                header::CONTENT_TYPE,
+                header::CONTENT_LENGTH,
+            ] {
+                headers.remove(name);
+            }
+        }
+
+        let url = self.url.join(location).ok()?;
+
+        // Credentials are not sent to the other hosts. Overridden `Host` doesn't apply to them either.
+        if url.host_str() != self.url.host_str()
+            || url.port_or_known_default() != self.url.port_or_known_default()
+        {
+            for name in &[
+                header::HOST,
+                header::AUTHORIZATION,
+                header::COOKIE,
+                header::PROXY_AUTHORIZATION,
+                header::WWW_AUTHENTICATE,
+            ] {
+                headers.remove(name);
+            }
+        }
+
+        Some(RequestTarget {
+            url,
+            method,
+            body,
+            stream,
+            headers,
+        })
+    }
+}
+
+/// URL of the `Refresh: 0; url=...` header. Refreshes with the delay are ignored.
+fn refresh_location(headers: &HeaderMap) -> Option<&str> {
+    let mut split = headers.get(header::REFRESH)?.to_str().ok()?.splitn(2, ';');
+    if split.next()?.trim().parse::<f64>().ok()? != 0.0 {
+        return None;
+    }
+
+    let parameter = split.next()?.trim();
+    let (name, url) = parameter.split_at(parameter.find('=')?);
+    if !name.trim().eq_ignore_ascii_case("url") {
+        return None;
+    }
+
+    let url = url[1..].trim().trim_matches(|c| c == '\'' || c == '"');
+    if url.is_empty() {
+        None
+    } else {
+        Some(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(status: u16, location: &str) -> hyper::Response<()> {
+        hyper::Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_custom_clients_bounded() {
+        use super::super::dns::tests::StubResolver;
+
+        let resolver = CachingResolver::new(Arc::new(StubResolver::default()), None);
+        let clients = TcpClients::new(&QueueOptions::default(), &resolver);
+        let route = |millis| TcpRoute {
+            family: AddressFamily::Any,
+            dns_timeout: Some(Duration::from_millis(millis)),
+            proxy: None,
+        };
+
+        for millis in 0..MAX_CUSTOM_CLIENTS as u64 * 2 {
+            clients.get(&route(millis));
+            clients.get(&route(0));
+        }
+
+        let custom = clients.custom.lock().unwrap();
+        assert_eq!(custom.map.len(), MAX_CUSTOM_CLIENTS);
+        // Client used all along isn't evicted.
+        assert!(custom.map.contains_key(&(
+            AddressFamily::Any,
+            Some(Duration::from_millis(0)),
+            None
+        )));
+    }
+
+    #[test]
+    fn test_proxy_clients_bounded() {
+        use super::super::dns::tests::StubResolver;
+
+        let resolver = CachingResolver::new(Arc::new(StubResolver::default()), None);
+        let clients = TcpClients::new(&QueueOptions::default(), &resolver);
+        let route = |proxy| TcpRoute {
+            family: AddressFamily::Any,
+            dns_timeout: None,
+            proxy: Some(proxy),
+        };
+
+        // Direct connections use the client of the queue without the proxy.
+        clients.get(&route(ProxyOverride::Direct));
+        assert!(clients.custom.lock().unwrap().map.is_empty());
+
+        for port in 0..MAX_CUSTOM_CLIENTS as u16 * 2 {
+            clients.get(&route(ProxyOverride::Socks5(Socks5Proxy {
+                host: "localhost".to_owned(),
+                port,
+                credentials: None,
+            })));
+        }
+        assert_eq!(clients.custom.lock().unwrap().map.len(), MAX_CUSTOM_CLIENTS);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        headers.insert(header::HOST, "virtual.test".parse().unwrap());
+
+        let target = RequestTarget {
+            url: "http://localhost/a/b".parse().unwrap(),
+            method: Method::POST,
+            body: Bytes::from_static(b"body"),
+            stream: None,
+            headers,
+        };
+
+        let see_other = target.redirect(&redirect(303, "c"), false).unwrap();
+        assert_eq!(see_other.url.as_str(), "http://localhost/a/c");
+        assert_eq!(see_other.method, Method::GET);
+        assert!(see_other.body.is_empty());
+        assert!(!see_other.headers.contains_key(header::CONTENT_TYPE));
+        assert!(see_other.headers.contains_key(header::AUTHORIZATION));
+        assert!(see_other.headers.contains_key(header::HOST));
+
+        let temporary = target
+            .redirect(&redirect(307, "http://example.com/"), false)
+            .unwrap();
+        assert_eq!(temporary.method, Method::POST);
+        assert_eq!(&temporary.body[..], b"body");
+        assert!(!temporary.headers.contains_key(header::AUTHORIZATION));
+        assert!(!temporary.headers.contains_key(header::HOST));
+
+        assert!(target.redirect(&redirect(200, "/"), false).is_none());
+    }
+
+    #[test]
+    fn test_refresh_location() {
+        let refresh = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::REFRESH, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(refresh_location(&refresh("0; url=/next")), Some("/next"));
+        assert_eq!(
+            refresh_location(&refresh("0;URL='http://example.com/'")),
+            Some("http://example.com/")
+        );
+        assert_eq!(refresh_location(&refresh("5; url=/next")), None);
+        assert_eq!(refresh_location(&refresh("0")), None);
+        assert_eq!(refresh_location(&HeaderMap::new()), None);
+    }
+}
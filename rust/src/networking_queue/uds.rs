@@ -0,0 +1,116 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+//! Requests over the Unix domain sockets.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures::future;
+use futures::prelude::*;
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::net::UnixStream;
+
+use super::lifecycle;
+use crate::errors::*;
+
+/// Connects to the socket, whose path is hex-encoded in the host of the URI.
+#[derive(Clone)]
+pub(crate) struct UnixConnector;
+
+impl Connect for UnixConnector {
+    type Transport = UnixStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (UnixStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        lifecycle::connecting();
+        match decode_socket_path(dst.host()) {
+            Some(path) => {
+                Box::new(UnixStream::connect(path).map(|stream| (stream, Connected::new())))
+            }
+            None => Box::new(future::err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid socket path",
+            ))),
+        }
+    }
+}
+
+/// Builds URI for the `UnixConnector`, taking path and query from the `url`.
+pub(crate) fn unix_uri(socket_path: &Path, url: &url::Url) -> Result<hyper::Uri> {
+    let host: String = socket_path
+        .to_string_lossy()
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let mut uri = format!("unix://{}:0{}", host, url.path());
+    if let Some(query) = url.query() {
+        uri.push('?');
+        uri.push_str(query);
+    }
+
+    Ok(uri.parse()?)
+}
+
+fn decode_socket_path(host: &str) -> Option<PathBuf> {
+    if host.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..host.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&host[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_uri() {
+        let uri = unix_uri(
+            Path::new("/tmp/grip.sock"),
+            &"http://localhost/stats?full=1".parse().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(uri.path_and_query().unwrap().as_str(), "/stats?full=1");
+        assert_eq!(
+            decode_socket_path(uri.host().unwrap()),
+            Some(PathBuf::from("/tmp/grip.sock"))
+        );
+    }
+}
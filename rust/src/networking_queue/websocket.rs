@@ -0,0 +1,510 @@
+/*
+ * gRIP
+ * Copyright (c) 2018 Alik Aslanyan <cplusplus256@gmail.com>
+ *
+ *
+ *    This program is free software; you can redistribute it and/or modify it
+ *    under the terms of the GNU General Public License as published by the
+ *    Free Software Foundation; either version 3 of the License, or (at
+ *    your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful, but
+ *    WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *    General Public License for more details.
+ *
+ *    You should have received a copy of the GNU General Public License
+ *    along with this program; if not, write to the Free Software Foundation,
+ *    Inc., 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+ *
+ *    In addition, as a special exception, the author gives permission to
+ *    link the code of this program with the Half-Life Game Engine ("HL
+ *    Engine") and Modified Game Libraries ("MODs") developed by Valve,
+ *    L.L.C ("Valve").  You must obey the GNU General Public License in all
+ *    respects for all of the code used other than the HL Engine and MODs
+ *    from Valve.  If you modify this file, you may extend this exception
+ *    to your version of the file, but you are not obligated to do so.  If
+ *    you do not wish to do so, delete this exception statement from your
+ *    version.
+ *
+ */
+
+use std::collections::VecDeque;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::RequestCancellation;
+use crate::errors::*;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Frames with the larger payload are rejected.
+const MAX_PAYLOAD: u64 = 64 * 1024 * 1024;
+
+pub(crate) const CONTINUATION: u8 = 0x0;
+pub(crate) const TEXT: u8 = 0x1;
+pub(crate) const BINARY: u8 = 0x2;
+pub(crate) const CLOSE: u8 = 0x8;
+pub(crate) const PING: u8 = 0x9;
+pub(crate) const PONG: u8 = 0xA;
+
+const NORMAL_CLOSURE: u16 = 1000;
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Bytes),
+}
+
+/// Delivered to the callback of `Queue::send_websocket`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebSocketEvent {
+    /// Handshake succeeded.
+    Opened,
+    Message(WebSocketMessage),
+    /// Last event of the connection closed by either side. `code` is `None`, when the peer didn't send it,
+    /// and 1006, when the connection was dropped without the closing handshake.
+    Closed {
+        code: Option<u16>,
+        reason: String,
+    },
+}
+
+/// Open WebSocket connection. Dropping it starts the closing handshake.
+pub struct WebSocket {
+    pub(crate) sender: mpsc::UnboundedSender<WebSocketMessage>,
+    pub(crate) _cancellation: RequestCancellation,
+}
+
+impl WebSocket {
+    /// Messages sent before the handshake completes are sent right after it.
+    /// Fails with `ErrorKind::WebSocketClosed`, once the connection is finished.
+    pub fn send(&self, message: WebSocketMessage) -> Result<()> {
+        self.sender
+            .unbounded_send(message)
+            .map_err(|_| ErrorKind::WebSocketClosed.into())
+    }
+
+    /// Starts the closing handshake. Callback receives `WebSocketEvent::Closed`, once it completes.
+    pub fn close(self) {}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, payload: Bytes) -> Self {
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+}
+
+/// Frames of RFC 6455. Client masks the frames it sends, server doesn't.
+pub(crate) struct FrameCodec {
+    masked: bool,
+}
+
+impl FrameCodec {
+    pub fn client() -> Self {
+        FrameCodec { masked: true }
+    }
+
+    #[cfg(test)]
+    pub fn server() -> Self {
+        FrameCodec { masked: false }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = src[0] & 0x80 != 0;
+        let opcode = src[0] & 0x0F;
+        let masked = src[1] & 0x80 != 0;
+
+        let (len, mut offset) = match src[1] & 0x7F {
+            126 if src.len() < 4 => return Ok(None),
+            126 => (u64::from(u16::from_be_bytes([src[2], src[3]])), 4),
+            127 if src.len() < 10 => return Ok(None),
+            127 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&src[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_PAYLOAD {
+            bail!(ErrorKind::WebSocketProtocol(format!(
+                "frame of {} bytes is too large",
+                len
+            )));
+        }
+
+        let mut mask = None;
+        if masked {
+            if src.len() < offset + 4 {
+                return Ok(None);
+            }
+            mask = Some([
+                src[offset],
+                src[offset + 1],
+                src[offset + 2],
+                src[offset + 3],
+            ]);
+            offset += 4;
+        }
+
+        let len = len as usize;
+        if src.len() < offset + len {
+            src.reserve(offset + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(offset);
+        let mut payload = src.split_to(len);
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload: payload.freeze(),
+        }))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<()> {
+        let len = frame.payload.len();
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+
+        dst.reserve(14 + len);
+        dst.put_u8(if frame.fin { 0x80 } else { 0 } | frame.opcode);
+        if len < 126 {
+            dst.put_u8(mask_bit | len as u8);
+        } else if len <= usize::from(std::u16::MAX) {
+            dst.put_u8(mask_bit | 126);
+            dst.put_u16_be(len as u16);
+        } else {
+            dst.put_u8(mask_bit | 127);
+            dst.put_u64_be(len as u64);
+        }
+
+        if self.masked {
+            let mut mask = [0; 4];
+            SystemRandom::new().fill(&mut mask).map_err(|_| {
+                ErrorKind::WebSocketProtocol("failed to generate the mask".to_owned())
+            })?;
+            dst.put_slice(&mask);
+
+            let mut payload = BytesMut::from(&frame.payload[..]);
+            apply_mask(&mut payload, mask);
+            dst.put_slice(&payload);
+        } else {
+            dst.put_slice(&frame.payload);
+        }
+
+        Ok(())
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Random value of the `Sec-WebSocket-Key` header.
+pub(crate) fn handshake_key() -> Result<String> {
+    let mut key = [0; 16];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| ErrorKind::WebSocketHandshake("failed to generate the key".to_owned()))?;
+    Ok(base64::encode(&key))
+}
+
+/// Value of the `Sec-WebSocket-Accept` header, which the server answers the `key` with.
+pub(crate) fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA1, format!("{}{}", key, GUID).as_bytes());
+    base64::encode(digest.as_ref())
+}
+
+/// Checks that the server switched to the WebSocket protocol in response to the `key`.
+pub(crate) fn check_handshake<B>(response: &hyper::Response<B>, key: &str) -> Result<()> {
+    if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
+        bail!(ErrorKind::WebSocketHandshake(format!(
+            "unexpected status {}",
+            response.status()
+        )));
+    }
+
+    let headers = response.headers();
+    let upgrade = headers
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok());
+    if !upgrade.map_or(false, |v| v.eq_ignore_ascii_case("websocket")) {
+        bail!(ErrorKind::WebSocketHandshake(
+            "missing Upgrade: websocket".to_owned()
+        ));
+    }
+
+    let accept = headers
+        .get(hyper::header::SEC_WEBSOCKET_ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    if accept != Some(accept_key(key).as_str()) {
+        bail!(ErrorKind::WebSocketHandshake(
+            "invalid Sec-WebSocket-Accept".to_owned()
+        ));
+    }
+
+    Ok(())
+}
+
+type DeliverFn = dyn Fn(Result<WebSocketEvent>) + Send;
+
+/// Exchanges messages over the upgraded connection until it is closed.
+/// Every event, including the last one, is passed to `deliver`.
+pub(crate) struct Connection<T> {
+    framed: Framed<T, FrameCodec>,
+    outgoing: Option<mpsc::UnboundedReceiver<WebSocketMessage>>,
+    cancellation_signal: Option<oneshot::Receiver<()>>,
+    deliver: Box<DeliverFn>,
+    queued: VecDeque<Frame>,
+    fragmented: Option<(u8, BytesMut)>,
+    close_sent: bool,
+    close_received: Option<(Option<u16>, String)>,
+}
+
+impl<T: AsyncRead + AsyncWrite> Connection<T> {
+    pub fn new(
+        io: T,
+        outgoing: mpsc::UnboundedReceiver<WebSocketMessage>,
+        cancellation_signal: oneshot::Receiver<()>,
+        deliver: Box<DeliverFn>,
+    ) -> Self {
+        Connection {
+            framed: Framed::new(io, FrameCodec::client()),
+            outgoing: Some(outgoing),
+            cancellation_signal: Some(cancellation_signal),
+            deliver,
+            queued: VecDeque::new(),
+            fragmented: None,
+            close_sent: false,
+            close_received: None,
+        }
+    }
+
+    /// Queues the outgoing messages, or the close frame once the connection is cancelled.
+    fn poll_local(&mut self) {
+        let cancelled = match &mut self.cancellation_signal {
+            // Dropped sender cancels too.
+            Some(signal) => !matches!(signal.poll(), Ok(Async::NotReady)),
+            None => false,
+        };
+
+        let mut finished = cancelled;
+        if let Some(outgoing) = &mut self.outgoing {
+            loop {
+                match outgoing.poll() {
+                    Ok(Async::Ready(Some(message))) => {
+                        let frame = match message {
+                            WebSocketMessage::Text(text) => Frame::new(TEXT, text.into()),
+                            WebSocketMessage::Binary(data) => Frame::new(BINARY, data),
+                        };
+                        self.queued.push_back(frame);
+                    }
+                    Ok(Async::NotReady) => break,
+                    _ => {
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if finished && !self.close_sent {
+            self.queued
+                .push_back(Frame::new(CLOSE, close_payload(NORMAL_CLOSURE)));
+            self.close_sent = true;
+            self.outgoing = None;
+            self.cancellation_signal = None;
+        }
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<()> {
+        match frame.opcode {
+            TEXT | BINARY if self.fragmented.is_some() => bail!(ErrorKind::WebSocketProtocol(
+                "expected continuation frame".to_owned()
+            )),
+            TEXT | BINARY if frame.fin => self.on_message(frame.opcode, frame.payload)?,
+            TEXT | BINARY => {
+                self.fragmented = Some((frame.opcode, BytesMut::from(&frame.payload[..])));
+            }
+            CONTINUATION => {
+                let (opcode, mut payload) = self.fragmented.take().ok_or_else(|| {
+                    ErrorKind::WebSocketProtocol("unexpected continuation frame".to_owned())
+                })?;
+                payload.extend_from_slice(&frame.payload);
+
+                if frame.fin {
+                    self.on_message(opcode, payload.freeze())?;
+                } else {
+                    self.fragmented = Some((opcode, payload));
+                }
+            }
+            PING => self.queued.push_back(Frame::new(PONG, frame.payload)),
+            PONG => {}
+            CLOSE => {
+                let payload = frame.payload;
+                let (code, reason) = if payload.len() >= 2 {
+                    let code = u16::from_be_bytes([payload[0], payload[1]]);
+                    (
+                        Some(code),
+                        String::from_utf8_lossy(&payload[2..]).into_owned(),
+                    )
+                } else {
+                    (None, String::new())
+                };
+
+                if !self.close_sent {
+                    let code = code.map_or_else(Bytes::new, close_payload);
+                    self.queued.push_back(Frame::new(CLOSE, code));
+                    self.close_sent = true;
+                    self.outgoing = None;
+                    self.cancellation_signal = None;
+                }
+                self.close_received = Some((code, reason));
+            }
+            opcode => bail!(ErrorKind::WebSocketProtocol(format!(
+                "unknown opcode {}",
+                opcode
+            ))),
+        }
+
+        Ok(())
+    }
+
+    fn on_message(&mut self, opcode: u8, payload: Bytes) -> Result<()> {
+        let message = if opcode == TEXT {
+            let text = String::from_utf8(payload.to_vec()).map_err(|_| {
+                ErrorKind::WebSocketProtocol("text message is not valid UTF-8".to_owned())
+            })?;
+            WebSocketMessage::Text(text)
+        } else {
+            WebSocketMessage::Binary(payload)
+        };
+
+        (self.deliver)(Ok(WebSocketEvent::Message(message)));
+        Ok(())
+    }
+
+    fn poll_connection(&mut self) -> Poll<WebSocketEvent, Error> {
+        self.poll_local();
+
+        while self.close_received.is_none() {
+            match self.framed.poll()? {
+                Async::Ready(Some(frame)) => self.on_frame(frame)?,
+                Async::Ready(None) => {
+                    return Ok(Async::Ready(WebSocketEvent::Closed {
+                        code: Some(ABNORMAL_CLOSURE),
+                        reason: String::new(),
+                    }))
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        while let Some(frame) = self.queued.pop_front() {
+            if let AsyncSink::NotReady(frame) = self.framed.start_send(frame)? {
+                self.queued.push_front(frame);
+                break;
+            }
+        }
+
+        let flushed = self.framed.poll_complete()?.is_ready();
+        if flushed && self.queued.is_empty() {
+            if let Some((code, reason)) = self.close_received.take() {
+                return Ok(Async::Ready(WebSocketEvent::Closed { code, reason }));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for Connection<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let event = match self.poll_connection() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(event)) => Ok(event),
+            Err(e) => Err(e),
+        };
+
+        (self.deliver)(event);
+        Ok(Async::Ready(()))
+    }
+}
+
+fn close_payload(code: u16) -> Bytes {
+    Bytes::from(&code.to_be_bytes()[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // Example of RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frame_codec() {
+        let frame = Frame::new(TEXT, Bytes::from(vec![b'x'; 300]));
+
+        let mut encoded = BytesMut::new();
+        FrameCodec::client()
+            .encode(frame.clone(), &mut encoded)
+            .unwrap();
+        assert_eq!(encoded[1], 0x80 | 126);
+
+        let mut partial = BytesMut::from(&encoded[..10]);
+        assert_eq!(FrameCodec::server().decode(&mut partial).unwrap(), None);
+
+        assert_eq!(
+            FrameCodec::server().decode(&mut encoded).unwrap(),
+            Some(frame)
+        );
+        assert!(encoded.is_empty());
+    }
+}